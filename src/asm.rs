@@ -0,0 +1,713 @@
+//! Assembler for CHIP-8 programs
+//!
+//! The assembler reads the mnemonics of Cowgod's Chip-8 Technical
+//! Reference, i.e. the `Display` syntax of `Instruction`, and produces
+//! the bytes of a ROM that can be loaded with `Vm::load_rom`:
+//!
+//! ```text
+//! SPEED   EQU 2               ; constant
+//!
+//! start:  LD V0, 0            ; label
+//!         LD I, sprite
+//! loop:   DRW V0, V1, sprite_end - sprite
+//!         ADD V0, SPEED * 4
+//!         JP loop
+//!
+//! sprite: db 0b11110000, 0x90, 0x90, 0x90, 0xF0
+//! sprite_end:
+//!         dw 0xBEEF           ; big-endian word
+//! ```
+//!
+//! Mnemonics, registers and the special operands (`I`, `[I]`, `DT`, `ST`,
+//! `K`, `F`, `HF`, `B`, `R`, `LONG`) are case-insensitive, labels and
+//! constants are case-sensitive. Numbers are decimal, hexadecimal (`0x`)
+//! or binary (`0b`), and can be combined into expressions with the
+//! operators `+ - * / % & | ^ ~ << >>` and parentheses. Labels and
+//! constants can be used before they are defined.
+
+use std::collections::HashMap;
+
+use error::Chip8Error;
+use instructions::{Instruction, Register, Addr, Nibble, LongAddr};
+use vm::PROGRAM_START;
+
+/// Assembles `source` into a ROM loaded at `PROGRAM_START`
+pub fn assemble(source: &str) -> Result<Vec<u8>, Chip8Error> {
+    assemble_at(source, PROGRAM_START)
+}
+
+/// Assembles `source` into bytes that are loaded at address `origin`
+pub fn assemble_at(source: &str, origin: usize) -> Result<Vec<u8>, Chip8Error> {
+    let mut symbols = HashMap::new();
+    let mut statements = Vec::new();
+
+    // First pass: parse all lines and assign addresses to the labels
+    let mut addr = origin;
+    for (idx, text) in source.lines().enumerate() {
+        let line = idx + 1;
+        let tokens = tokenize(text, line)?;
+        let mut parser = Parser { tokens: &tokens, pos: 0, line, end: text.len() + 1 };
+
+        if parser.is_label() {
+            let (name, column) = parser.ident().unwrap();
+            parser.pos += 2;
+            define(&mut symbols, name, Symbol::Label(addr), line, column)?;
+        } else if parser.is_constant() {
+            let (name, column) = parser.ident().unwrap();
+            parser.pos += 2;
+            let expr = parser.expr()?;
+            parser.finish()?;
+            define(&mut symbols, name, Symbol::Constant(expr, line), line, column)?;
+            continue;
+        }
+        if parser.at_end() {
+            continue;
+        }
+
+        let statement = parser.statement()?;
+        addr += statement.size(line)?;
+        statements.push((line, statement));
+    }
+
+    // Second pass: evaluate all expressions and emit the bytes
+    let mut bytes = Vec::new();
+    for &(line, ref statement) in statements.iter() {
+        let mut eval = |expr: &Expr| evaluate(expr, &symbols, line, &mut Vec::new());
+        match *statement {
+            Statement::Instruction(ref mnemonic, ref args) => {
+                let ins = instruction(mnemonic, args, line, &mut eval)?;
+                bytes.extend_from_slice(&ins.to_bytes());
+            },
+            Statement::Bytes(ref exprs) => {
+                for &(ref expr, column) in exprs.iter() {
+                    let value = eval(expr)?;
+                    bytes.push(check(value, Width::Byte, line, column)? as u8);
+                }
+            },
+            Statement::Words(ref exprs) => {
+                for &(ref expr, column) in exprs.iter() {
+                    let value = eval(expr)?;
+                    let word = check(value, Width::Word, line, column)? as u16;
+                    bytes.push((word >> 8) as u8);
+                    bytes.push(word as u8);
+                }
+            },
+        }
+    }
+    Ok(bytes)
+}
+
+fn error<S: Into<String>>(line: usize, column: usize, message: S) -> Chip8Error {
+    Chip8Error::Asm { line, column, message: message.into() }
+}
+
+/// Names that can not be used for labels and constants
+const RESERVED: &[&str] = &["I", "DT", "ST", "K", "F", "HF", "B", "R", "LONG", "EQU", "DB", "DW"];
+
+fn define(symbols: &mut HashMap<String, Symbol>, name: &str, symbol: Symbol, line: usize, column: usize) -> Result<(), Chip8Error> {
+    let upper = name.to_uppercase();
+    if register(name).is_some() || RESERVED.contains(&&upper[..]) {
+        return Err(error(line, column, format!("`{}` is a reserved name", name)));
+    }
+    if symbols.contains_key(name) {
+        return Err(error(line, column, format!("`{}` is already defined", name)));
+    }
+    symbols.insert(name.to_string(), symbol);
+    Ok(())
+}
+
+/// Parses a register name like `V0` or `va`
+fn register(name: &str) -> Option<Register> {
+    let mut chars = name.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('V'), Some(digit), None) | (Some('v'), Some(digit), None) => {
+            digit.to_digit(16).and_then(|bits| Register::new(bits as u8).ok())
+        },
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(i64),
+    Comma,
+    Colon,
+    LBracket,
+    RBracket,
+    LParen,
+    RParen,
+    Op(char),
+    Shl,
+    Shr,
+}
+
+/// Splits a line into tokens and their columns, dropping the comment
+fn tokenize(text: &str, line: usize) -> Result<Vec<(Token, usize)>, Chip8Error> {
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let mut tokens = Vec::new();
+    let mut pos = 0;
+    while pos < chars.len() {
+        let (offset, c) = chars[pos];
+        let column = offset + 1;
+        let token = match c {
+            ';' => break,
+            _ if c.is_whitespace() => {
+                pos += 1;
+                continue;
+            },
+            _ if c.is_ascii_alphabetic() || c == '_' || c == '.' => {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].1.is_ascii_alphanumeric() || chars[pos].1 == '_' || chars[pos].1 == '.') {
+                    pos += 1;
+                }
+                let ident: String = chars[start..pos].iter().map(|&(_, c)| c).collect();
+                tokens.push((Token::Ident(ident), column));
+                continue;
+            },
+            _ if c.is_ascii_digit() => {
+                let start = pos;
+                while pos < chars.len() && (chars[pos].1.is_ascii_alphanumeric() || chars[pos].1 == '_') {
+                    pos += 1;
+                }
+                let literal: String = chars[start..pos].iter().map(|&(_, c)| c).filter(|&c| c != '_').collect();
+                tokens.push((Token::Number(number(&literal, line, column)?), column));
+                continue;
+            },
+            ',' => Token::Comma,
+            ':' => Token::Colon,
+            '[' => Token::LBracket,
+            ']' => Token::RBracket,
+            '(' => Token::LParen,
+            ')' => Token::RParen,
+            '+' | '-' | '*' | '/' | '%' | '&' | '|' | '^' | '~' => Token::Op(c),
+            '<' | '>' if pos + 1 < chars.len() && chars[pos + 1].1 == c => {
+                pos += 1;
+                if c == '<' { Token::Shl } else { Token::Shr }
+            },
+            _ => return Err(error(line, column, format!("Unexpected character `{}`", c))),
+        };
+        tokens.push((token, column));
+        pos += 1;
+    }
+    Ok(tokens)
+}
+
+fn number(literal: &str, line: usize, column: usize) -> Result<i64, Chip8Error> {
+    let lower = literal.to_lowercase();
+    let parsed = if let Some(hex) = lower.strip_prefix("0x") {
+        i64::from_str_radix(hex, 16)
+    } else if let Some(binary) = lower.strip_prefix("0b") {
+        i64::from_str_radix(binary, 2)
+    } else {
+        lower.parse()
+    };
+    parsed.map_err(|_| error(line, column, format!("Invalid number `{}`", literal)))
+}
+
+/// Expression that is evaluated in the second pass
+#[derive(Clone, Debug)]
+enum Expr {
+    Number(i64),
+    Symbol(String, usize),
+    Unary(char, Box<Expr>),
+    Binary(Token, Box<Expr>, Box<Expr>, usize),
+}
+
+#[derive(Clone, Debug)]
+enum Symbol {
+    /// Address of a label
+    Label(usize),
+    /// Constant defined by `EQU` on the given line
+    Constant(Expr, usize),
+}
+
+fn evaluate(expr: &Expr, symbols: &HashMap<String, Symbol>, line: usize, visiting: &mut Vec<String>) -> Result<i64, Chip8Error> {
+    match *expr {
+        Expr::Number(value) => Ok(value),
+        Expr::Symbol(ref name, column) => {
+            match symbols.get(name) {
+                Some(&Symbol::Label(addr)) => Ok(addr as i64),
+                Some(&Symbol::Constant(ref expr, defined)) => {
+                    if visiting.contains(name) {
+                        return Err(error(line, column, format!("`{}` is defined in terms of itself", name)));
+                    }
+                    visiting.push(name.clone());
+                    let value = evaluate(expr, symbols, defined, visiting);
+                    visiting.pop();
+                    value
+                },
+                None => Err(error(line, column, format!("Undefined symbol `{}`", name))),
+            }
+        },
+        Expr::Unary(op, ref operand) => {
+            let value = evaluate(operand, symbols, line, visiting)?;
+            Ok(match op {
+                '-' => value.wrapping_neg(),
+                '~' => !value,
+                _ => value,
+            })
+        },
+        Expr::Binary(ref op, ref lhs, ref rhs, column) => {
+            let lhs = evaluate(lhs, symbols, line, visiting)?;
+            let rhs = evaluate(rhs, symbols, line, visiting)?;
+            match *op {
+                Token::Op('+') => Ok(lhs.wrapping_add(rhs)),
+                Token::Op('-') => Ok(lhs.wrapping_sub(rhs)),
+                Token::Op('*') => Ok(lhs.wrapping_mul(rhs)),
+                Token::Op('/') | Token::Op('%') if rhs == 0 => Err(error(line, column, "Division by zero")),
+                Token::Op('/') => Ok(lhs.wrapping_div(rhs)),
+                Token::Op('%') => Ok(lhs.wrapping_rem(rhs)),
+                Token::Op('&') => Ok(lhs & rhs),
+                Token::Op('|') => Ok(lhs | rhs),
+                Token::Op('^') => Ok(lhs ^ rhs),
+                Token::Shl => Ok(lhs.checked_shl(rhs as u32).unwrap_or(0)),
+                Token::Shr => Ok(lhs.checked_shr(rhs as u32).unwrap_or(0)),
+                _ => unreachable!(),
+            }
+        },
+    }
+}
+
+/// Kinds of values in instructions and data
+#[derive(Clone, Copy, Debug)]
+enum Width {
+    Nibble,
+    /// Bytes also accept negative values down to `-128`
+    Byte,
+    /// Words also accept negative values down to `-32768`
+    Word,
+    Addr,
+    LongAddr,
+}
+
+/// Checks that `value` fits in `width`
+fn check(value: i64, width: Width, line: usize, column: usize) -> Result<i64, Chip8Error> {
+    let (min, max, what) = match width {
+        Width::Nibble => (0, 0xF, "nibble"),
+        Width::Byte => (-0x80, 0xFF, "byte"),
+        Width::Word => (-0x8000, 0xFFFF, "word"),
+        Width::Addr => (0, 0xFFF, "12 bit address"),
+        Width::LongAddr => (0, 0xFFFF, "16 bit address"),
+    };
+    if value < min || value > max {
+        return Err(error(line, column, format!("Value {} does not fit in a {}", value, what)));
+    }
+    Ok(value)
+}
+
+/// Operand of an instruction
+#[derive(Clone, Debug)]
+enum Arg {
+    Reg(Register),
+    /// `Vx - Vy`
+    Range(Register, Register),
+    /// `[I]`
+    IndirectI,
+    /// Special operand like `I` or `DT`, always uppercase
+    Name(&'static str),
+    /// `LONG addr`
+    Long(Expr),
+    Expr(Expr),
+}
+
+const NAMES: &[&str] = &["I", "DT", "ST", "K", "F", "HF", "B", "R"];
+
+/// Parsed line, without its labels
+#[derive(Clone, Debug)]
+enum Statement {
+    /// Uppercase mnemonic and its operands, with their columns
+    Instruction((String, usize), Vec<(Arg, usize)>),
+    /// `db` data
+    Bytes(Vec<(Expr, usize)>),
+    /// `dw` data
+    Words(Vec<(Expr, usize)>),
+}
+
+impl Statement {
+    /// Number of bytes this statement assembles to
+    ///
+    /// Checks the instruction with all expressions evaluated to `0`.
+    fn size(&self, line: usize) -> Result<usize, Chip8Error> {
+        Ok(match *self {
+            Statement::Instruction(ref mnemonic, ref args) => {
+                instruction(mnemonic, args, line, &mut |_| Ok(0))?.size()
+            },
+            Statement::Bytes(ref exprs) => exprs.len(),
+            Statement::Words(ref exprs) => exprs.len() * 2,
+        })
+    }
+}
+
+struct Parser<'a> {
+    tokens: &'a [(Token, usize)],
+    pos: usize,
+    line: usize,
+    /// Column after the end of the line
+    end: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn peek_at(&self, offset: usize) -> Option<&'a Token> {
+        self.tokens.get(self.pos + offset).map(|(token, _)| token)
+    }
+
+    fn column(&self) -> usize {
+        self.tokens.get(self.pos).map(|&(_, column)| column).unwrap_or(self.end)
+    }
+
+    fn at_end(&self) -> bool {
+        self.pos >= self.tokens.len()
+    }
+
+    fn ident(&self) -> Option<(&'a str, usize)> {
+        match self.tokens.get(self.pos) {
+            Some(&(Token::Ident(ref name), column)) => Some((name, column)),
+            _ => None,
+        }
+    }
+
+    fn is_label(&self) -> bool {
+        self.ident().is_some() && self.peek_at(1) == Some(&Token::Colon)
+    }
+
+    fn is_constant(&self) -> bool {
+        match self.peek_at(1) {
+            Some(Token::Ident(name)) => self.ident().is_some() && name.eq_ignore_ascii_case("EQU"),
+            _ => false,
+        }
+    }
+
+    fn unexpected(&self) -> Chip8Error {
+        match self.peek() {
+            Some(token) => error(self.line, self.column(), format!("Unexpected {}", describe(token))),
+            None => error(self.line, self.column(), "Unexpected end of line"),
+        }
+    }
+
+    fn expect(&mut self, expected: Token) -> Result<(), Chip8Error> {
+        if self.peek() != Some(&expected) {
+            return Err(self.unexpected());
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn finish(&self) -> Result<(), Chip8Error> {
+        if self.at_end() { Ok(()) } else { Err(self.unexpected()) }
+    }
+
+    fn statement(&mut self) -> Result<Statement, Chip8Error> {
+        let mnemonic = match self.ident() {
+            Some((name, column)) => (name.to_uppercase(), column),
+            None => return Err(self.unexpected()),
+        };
+        self.pos += 1;
+
+        let statement = match &mnemonic.0[..] {
+            "DB" => Statement::Bytes(self.expr_list()?),
+            "DW" => Statement::Words(self.expr_list()?),
+            _ => {
+                let mut args = Vec::new();
+                if !self.at_end() {
+                    args.push(self.arg()?);
+                    while self.peek() == Some(&Token::Comma) {
+                        self.pos += 1;
+                        args.push(self.arg()?);
+                    }
+                }
+                Statement::Instruction(mnemonic, args)
+            },
+        };
+        self.finish()?;
+        Ok(statement)
+    }
+
+    fn expr_list(&mut self) -> Result<Vec<(Expr, usize)>, Chip8Error> {
+        let mut exprs = Vec::new();
+        loop {
+            let column = self.column();
+            exprs.push((self.expr()?, column));
+            if self.peek() != Some(&Token::Comma) {
+                return Ok(exprs);
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn arg(&mut self) -> Result<(Arg, usize), Chip8Error> {
+        let column = self.column();
+        if self.peek() == Some(&Token::LBracket) {
+            self.pos += 1;
+            match self.ident() {
+                Some((name, _)) if name.eq_ignore_ascii_case("I") => self.pos += 1,
+                _ => return Err(self.unexpected()),
+            }
+            self.expect(Token::RBracket)?;
+            return Ok((Arg::IndirectI, column));
+        }
+
+        if let Some((name, _)) = self.ident() {
+            if let Some(vx) = register(name) {
+                self.pos += 1;
+                if self.peek() == Some(&Token::Op('-')) {
+                    self.pos += 1;
+                    let vy = match self.ident().and_then(|(name, _)| register(name)) {
+                        Some(vy) => vy,
+                        None => return Err(self.unexpected()),
+                    };
+                    self.pos += 1;
+                    return Ok((Arg::Range(vx, vy), column));
+                }
+                return Ok((Arg::Reg(vx), column));
+            }
+            let upper = name.to_uppercase();
+            if upper == "LONG" {
+                self.pos += 1;
+                return Ok((Arg::Long(self.expr()?), column));
+            }
+            if let Some(special) = NAMES.iter().find(|special| **special == upper) {
+                self.pos += 1;
+                return Ok((Arg::Name(special), column));
+            }
+        }
+        Ok((Arg::Expr(self.expr()?), column))
+    }
+
+    fn expr(&mut self) -> Result<Expr, Chip8Error> {
+        self.binary(0)
+    }
+
+    /// Parses binary operators of precedence `level` and higher
+    fn binary(&mut self, level: usize) -> Result<Expr, Chip8Error> {
+        const LEVELS: &[&[Token]] = &[
+            &[Token::Op('|')],
+            &[Token::Op('^')],
+            &[Token::Op('&')],
+            &[Token::Shl, Token::Shr],
+            &[Token::Op('+'), Token::Op('-')],
+            &[Token::Op('*'), Token::Op('/'), Token::Op('%')],
+        ];
+
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(op) = self.peek().filter(|token| LEVELS[level].contains(token)) {
+            let column = self.column();
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = Expr::Binary(op.clone(), Box::new(lhs), Box::new(rhs), column);
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Chip8Error> {
+        match self.peek() {
+            Some(&Token::Op(op)) if op == '-' || op == '~' || op == '+' => {
+                self.pos += 1;
+                Ok(Expr::Unary(op, Box::new(self.unary()?)))
+            },
+            Some(&Token::Number(value)) => {
+                self.pos += 1;
+                Ok(Expr::Number(value))
+            },
+            Some(Token::Ident(name)) => {
+                let column = self.column();
+                self.pos += 1;
+                Ok(Expr::Symbol(name.clone(), column))
+            },
+            Some(&Token::LParen) => {
+                self.pos += 1;
+                let expr = self.expr()?;
+                self.expect(Token::RParen)?;
+                Ok(expr)
+            },
+            _ => Err(self.unexpected()),
+        }
+    }
+}
+
+fn describe(token: &Token) -> String {
+    match *token {
+        Token::Ident(ref name) => format!("`{}`", name),
+        Token::Number(value) => format!("number {}", value),
+        Token::Comma => "`,`".to_string(),
+        Token::Colon => "`:`".to_string(),
+        Token::LBracket => "`[`".to_string(),
+        Token::RBracket => "`]`".to_string(),
+        Token::LParen => "`(`".to_string(),
+        Token::RParen => "`)`".to_string(),
+        Token::Op(op) => format!("`{}`", op),
+        Token::Shl => "`<<`".to_string(),
+        Token::Shr => "`>>`".to_string(),
+    }
+}
+
+/// Builds the instruction for `mnemonic` and its operands on `line`
+fn instruction(&(ref mnemonic, column): &(String, usize), args: &[(Arg, usize)], line: usize, eval: &mut dyn FnMut(&Expr) -> Result<i64, Chip8Error>) -> Result<Instruction, Chip8Error> {
+    use instructions::Instruction::*;
+    use self::Arg::*;
+
+    let mut value = |idx: usize, width: Width| -> Result<i64, Chip8Error> {
+        let (ref arg, column) = args[idx];
+        let value = match *arg {
+            Arg::Expr(ref expr) | Long(ref expr) => eval(expr)?,
+            _ => unreachable!(),
+        };
+        check(value, width, line, column)
+    };
+
+    let kinds: Vec<&Arg> = args.iter().map(|(arg, _)| arg).collect();
+    let ins = match (&mnemonic[..], &kinds[..]) {
+        ("SYS", [Arg::Expr(_)]) => Sys(Addr::new(value(0, Width::Addr)? as u16)),
+        ("CLS", []) => Clear,
+        ("RET", []) => Return,
+        ("JP", [Arg::Expr(_)]) => Jump(Addr::new(value(0, Width::Addr)? as u16)),
+        ("JP", [Reg(Register::V0), Arg::Expr(_)]) => LongJump(Addr::new(value(1, Width::Addr)? as u16)),
+        ("CALL", [Arg::Expr(_)]) => Call(Addr::new(value(0, Width::Addr)? as u16)),
+        ("SE", [Reg(vx), Reg(vy)]) => SkipEqual(*vx, *vy),
+        ("SE", [Reg(vx), Arg::Expr(_)]) => SkipEqualK(*vx, value(1, Width::Byte)? as u8),
+        ("SNE", [Reg(vx), Reg(vy)]) => SkipNotEqual(*vx, *vy),
+        ("SNE", [Reg(vx), Arg::Expr(_)]) => SkipNotEqualK(*vx, value(1, Width::Byte)? as u8),
+        ("LD", [Reg(vx), Reg(vy)]) => Set(*vx, *vy),
+        ("LD", [Reg(vx), Arg::Expr(_)]) => SetK(*vx, value(1, Width::Byte)? as u8),
+        ("LD", [Name("I"), Arg::Expr(_)]) => LoadI(Addr::new(value(1, Width::Addr)? as u16)),
+        ("LD", [Name("I"), Long(_)]) => LoadLongI(LongAddr::new(value(1, Width::LongAddr)? as u16)),
+        ("LD", [Reg(vx), Name("DT")]) => GetTimer(*vx),
+        ("LD", [Reg(vx), Name("K")]) => WaitKey(*vx),
+        ("LD", [Name("DT"), Reg(vx)]) => SetTimer(*vx),
+        ("LD", [Name("ST"), Reg(vx)]) => SetSoundTimer(*vx),
+        ("LD", [Name("F"), Reg(vx)]) => LoadHexGlyph(*vx),
+        ("LD", [Name("HF"), Reg(vx)]) => LoadLargeHexGlyph(*vx),
+        ("LD", [Name("B"), Reg(vx)]) => StoreBCD(*vx),
+        ("LD", [IndirectI, Reg(vx)]) => StoreRegisters(*vx),
+        ("LD", [Reg(vx), IndirectI]) => LoadRegisters(*vx),
+        ("LD", [IndirectI, Range(vx, vy)]) => SaveRange(*vx, *vy),
+        ("LD", [Range(vx, vy), IndirectI]) => LoadRange(*vx, *vy),
+        ("LD", [Name("R"), Reg(vx)]) => StoreFlags(*vx),
+        ("LD", [Reg(vx), Name("R")]) => LoadFlags(*vx),
+        ("ADD", [Reg(vx), Reg(vy)]) => Add(*vx, *vy),
+        ("ADD", [Reg(vx), Arg::Expr(_)]) => AddK(*vx, value(1, Width::Byte)? as u8),
+        ("ADD", [Name("I"), Reg(vx)]) => AddToI(*vx),
+        ("OR", [Reg(vx), Reg(vy)]) => Or(*vx, *vy),
+        ("AND", [Reg(vx), Reg(vy)]) => And(*vx, *vy),
+        ("XOR", [Reg(vx), Reg(vy)]) => XOr(*vx, *vy),
+        ("SUB", [Reg(vx), Reg(vy)]) => Sub(*vx, *vy),
+        ("SUBN", [Reg(vx), Reg(vy)]) => SubInv(*vx, *vy),
+        ("SHR", [Reg(vx)]) => ShiftRight(*vx, *vx),
+        ("SHR", [Reg(vx), Reg(vy)]) => ShiftRight(*vx, *vy),
+        ("SHL", [Reg(vx)]) => ShiftLeft(*vx, *vx),
+        ("SHL", [Reg(vx), Reg(vy)]) => ShiftLeft(*vx, *vy),
+        ("RND", [Reg(vx), Arg::Expr(_)]) => Rand(*vx, value(1, Width::Byte)? as u8),
+        ("DRW", [Reg(vx), Reg(vy), Arg::Expr(_)]) => Draw(*vx, *vy, Nibble::new(value(2, Width::Nibble)? as u8)),
+        ("SKP", [Reg(vx)]) => SkipPressed(*vx),
+        ("SKNP", [Reg(vx)]) => SkipNotPressed(*vx),
+        ("SCD", [Arg::Expr(_)]) => ScrollDown(Nibble::new(value(0, Width::Nibble)? as u8)),
+        ("SCU", [Arg::Expr(_)]) => ScrollUp(Nibble::new(value(0, Width::Nibble)? as u8)),
+        ("SCR", []) => ScrollRight,
+        ("SCL", []) => ScrollLeft,
+        ("EXIT", []) => Exit,
+        ("LOW", []) => LowRes,
+        ("HIGH", []) => HighRes,
+        ("PLANE", [Arg::Expr(_)]) => SelectPlanes(Nibble::new(value(0, Width::Nibble)? as u8)),
+        ("AUDIO", []) => LoadAudio,
+        ("PITCH", [Reg(vx)]) => SetPitch(*vx),
+        _ => {
+            return Err(error(line, column, format!("Invalid operands for `{}`", mnemonic)));
+        },
+    };
+    Ok(ins)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instructions::RawInstruction;
+    use vm::Vm;
+
+    fn asm_error(source: &str) -> (usize, usize, String) {
+        match assemble(source) {
+            Err(Chip8Error::Asm { line, column, message }) => (line, column, message),
+            other => panic!("expected an assembly error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn display_round_trip() {
+        for bits in 0..=0xFFFFu16 {
+            let ins = Instruction::from_raw(&RawInstruction::new(bits));
+            if ins == Instruction::Unknown {
+                continue;
+            }
+            let source = ins.to_string();
+            assert_eq!(assemble(&source).unwrap(), ins.to_bytes(), "{}", source);
+        }
+        let long = Instruction::LoadLongI(LongAddr::new(0xABCD));
+        assert_eq!(assemble(&long.to_string()).unwrap(), long.to_bytes());
+    }
+
+    #[test]
+    fn labels_and_constants() {
+        let source = "
+            SPEED   EQU 2 * STEP    ; constants may use later symbols
+            STEP    equ 3
+            start:  ld v0, SPEED
+                    LD I, sprite
+            loop:   DRW V0, V1, end - sprite
+                    JP loop
+            sprite: db 0b11110000, 0x90, -1
+                    dw 0xBEEF, start
+            end:
+        ";
+        assert_eq!(assemble(source).unwrap(), vec![
+            0x60, 0x06,
+            0xA2, 0x08,
+            0xD0, 0x17,
+            0x12, 0x04,
+            0xF0, 0x90, 0xFF,
+            0xBE, 0xEF, 0x02, 0x00,
+        ]);
+    }
+
+    #[test]
+    fn expressions() {
+        let bytes = assemble("db 1 + 2 * 3, (1 + 2) * 3, 1 << 4 | 1, ~0 & 0x0F, 7 % 4, -2 / 2 + 0x10").unwrap();
+        assert_eq!(bytes, vec![7, 9, 0x11, 0x0F, 3, 0x0F]);
+        assert_eq!(assemble_at("here: JP here", 0x300).unwrap(), vec![0x13, 0x00]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(asm_error("CLS\n  FOO V0"), (2, 3, "Invalid operands for `FOO`".to_string()));
+        assert_eq!(asm_error("LD V0, 0x100"), (1, 8, "Value 256 does not fit in a byte".to_string()));
+        assert_eq!(asm_error("JP nowhere"), (1, 4, "Undefined symbol `nowhere`".to_string()));
+        assert_eq!(asm_error("a: CLS\na: RET"), (2, 1, "`a` is already defined".to_string()));
+        assert_eq!(asm_error("X EQU Y\nY EQU X\nJP X"), (2, 7, "`X` is defined in terms of itself".to_string()));
+        assert_eq!(asm_error("V1: CLS"), (1, 1, "`V1` is a reserved name".to_string()));
+        assert_eq!(asm_error("LD V0, 1 +"), (1, 11, "Unexpected end of line".to_string()));
+        assert_eq!(asm_error("db 1 / 0"), (1, 6, "Division by zero".to_string()));
+        assert_eq!(asm_error("LD V0, $"), (1, 8, "Unexpected character `$`".to_string()));
+    }
+
+    #[test]
+    fn runs_in_vm() {
+        let rom = assemble("
+                    LD V0, 5
+            loop:   ADD V1, 3
+                    SUB V0, V2      ; V2 = 1
+                    LD V2, 1
+                    SE V0, 0
+                    JP loop
+                    EXIT
+        ").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        vm.step(1.0).unwrap();
+        assert!(vm.exited());
+    }
+}
@@ -0,0 +1,234 @@
+//! Audio samples of the sound timer
+//!
+//! A `Vm` with audio enabled by `Vm::set_audio` generates mono PCM samples
+//! while it runs: a tone while the sound timer is active and silence
+//! otherwise. The tone starts and stops at the exact sample the sound timer
+//! is set and runs out at. XO-CHIP programs that loaded an audio pattern
+//! play the pattern at its pitch instead of the tone.
+//!
+//! `write_wav` writes samples as WAV file, e.g. to render a run offline.
+
+use std::f32::consts::PI;
+use std::io::Write;
+
+use error::Chip8Error;
+
+/// Shape of the tone
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Waveform {
+    Square,
+    Triangle,
+    Sine,
+}
+
+/// Format and sound of the generated samples
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AudioConfig {
+    /// Samples per second
+    pub sample_rate: u32,
+    /// Frequency of the tone in Hz
+    pub frequency: f32,
+    /// Amplitude of the samples, from `0.0` to `1.0`
+    pub volume: f32,
+    pub waveform: Waveform,
+}
+
+impl Default for AudioConfig {
+    /// Square wave of 440 Hz at a quarter of the full volume, 44.1 kHz
+    fn default() -> AudioConfig {
+        AudioConfig {
+            sample_rate: 44100,
+            frequency: 440.0,
+            volume: 0.25,
+            waveform: Waveform::Square,
+        }
+    }
+}
+
+/// Generator of the samples of a `Vm`
+#[derive(Clone, Debug)]
+pub struct Audio {
+    config: AudioConfig,
+    /// Position within the period of the tone or the pattern, from `0.0` to `1.0`
+    phase: f64,
+    /// Fraction of a sample left over from the previous call of `generate`,
+    /// in `1 / clock_hz` samples
+    remainder: u64,
+    playing: bool,
+    samples: Vec<f32>,
+}
+
+impl Audio {
+    /// Creates a generator without any samples
+    pub fn new(config: AudioConfig) -> Audio {
+        Audio { config, phase: 0.0, remainder: 0, playing: false, samples: Vec::new() }
+    }
+
+    /// Returns the configuration of the samples
+    pub fn config(&self) -> AudioConfig {
+        self.config
+    }
+
+    /// Removes and returns the samples generated so far
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        ::std::mem::take(&mut self.samples)
+    }
+
+    /// Generates the samples for `cycles` cycles of a clock of `clock_hz`,
+    /// playing if `playing`
+    ///
+    /// `pattern` is the XO-CHIP audio pattern and its rate in bits per
+    /// second, if the program loaded one.
+    pub(crate) fn generate(&mut self, cycles: u32, clock_hz: u32, playing: bool, pattern: Option<(&[u8], f32)>) {
+        let exact = cycles as u64 * self.config.sample_rate as u64 + self.remainder;
+        let count = exact / clock_hz as u64;
+        self.remainder = exact % clock_hz as u64;
+
+        if playing && !self.playing {
+            self.phase = 0.0;
+        }
+        self.playing = playing;
+        if !playing {
+            let len = self.samples.len() + count as usize;
+            self.samples.resize(len, 0.0);
+            return;
+        }
+
+        let volume = self.config.volume;
+        let step = match pattern {
+            Some((bits, rate)) => rate as f64 / (bits.len() * 8) as f64,
+            None => self.config.frequency as f64,
+        } / self.config.sample_rate as f64;
+        for _ in 0..count as usize {
+            let phase = self.phase as f32;
+            let sample = match pattern {
+                Some((bits, _)) => {
+                    // In `f64` as a phase just below `1.0` rounds up to it as `f32`
+                    let bit = ((self.phase * (bits.len() * 8) as f64) as usize).min(bits.len() * 8 - 1);
+                    if bits[bit / 8] & (0x80 >> (bit % 8)) != 0 { volume } else { -volume }
+                },
+                None => match self.config.waveform {
+                    Waveform::Square => if phase < 0.5 { volume } else { -volume },
+                    Waveform::Triangle => volume * (1.0 - 4.0 * (phase - 0.5).abs()),
+                    Waveform::Sine => volume * (2.0 * PI * phase).sin(),
+                },
+            };
+            self.samples.push(sample);
+            self.phase = (self.phase + step).fract();
+        }
+    }
+}
+
+/// Writes `samples` as mono WAV file with 16 bit PCM at `sample_rate`
+///
+/// Samples beyond `-1.0` .. `1.0` are clipped.
+pub fn write_wav(samples: &[f32], sample_rate: u32, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    let data_len = samples.len() as u32 * 2;
+    writer.write_all(b"RIFF")?;
+    writer.write_all(&(36 + data_len).to_le_bytes())?;
+    writer.write_all(b"WAVEfmt ")?;
+    writer.write_all(&16u32.to_le_bytes())?;
+    // PCM, 1 channel
+    writer.write_all(&[1, 0, 1, 0])?;
+    writer.write_all(&sample_rate.to_le_bytes())?;
+    writer.write_all(&(sample_rate * 2).to_le_bytes())?;
+    // 2 bytes per frame, 16 bits per sample
+    writer.write_all(&[2, 0, 16, 0])?;
+    writer.write_all(b"data")?;
+    writer.write_all(&data_len.to_le_bytes())?;
+
+    let mut data = Vec::with_capacity(data_len as usize);
+    for sample in samples {
+        let value = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+        data.extend_from_slice(&value.to_le_bytes());
+    }
+    writer.write_all(&data)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use vm::Vm;
+
+    fn vm_with(source: &str, config: AudioConfig) -> Vm {
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &assemble(source).unwrap()[..]).unwrap();
+        vm.set_audio(Some(config));
+        vm
+    }
+
+    #[test]
+    fn sound_timer() {
+        let config = AudioConfig { sample_rate: 6000, frequency: 500.0, ..AudioConfig::default() };
+        let mut vm = vm_with("LD V0, 6\nLD ST, V0\nloop: JP loop", config);
+        vm.step(0.5).unwrap();
+        let samples = vm.take_samples();
+        assert_eq!(samples.len(), 3000);
+
+        // Sound from the cycle of the second instruction up to the end of the
+        // sixth frame, 10 samples per cycle
+        let playing: Vec<usize> = (0..samples.len()).filter(|&idx| samples[idx] != 0.0).collect();
+        assert_eq!(playing.len(), 590);
+        assert_eq!(playing[0], 10);
+        assert_eq!(*playing.last().unwrap(), 599);
+
+        // 12 samples per period at 500 Hz
+        assert_eq!(&samples[10..23], &[0.25, 0.25, 0.25, 0.25, 0.25, 0.25,
+                                       -0.25, -0.25, -0.25, -0.25, -0.25, -0.25, 0.25]);
+        assert!(vm.take_samples().is_empty());
+    }
+
+    #[test]
+    fn pattern() {
+        let config = AudioConfig { sample_rate: 4000, ..AudioConfig::default() };
+        let mut vm = vm_with("
+                    LD I, pattern
+                    AUDIO
+                    LD V0, 60
+                    LD ST, V0
+            loop:   JP loop
+            pattern: DB 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0
+                     DB 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0, 0xF0
+        ", config);
+        vm.step(0.1).unwrap();
+        let samples = vm.take_samples();
+        // The default pitch plays 4000 bits per second, one per sample
+        let start = samples.iter().position(|&sample| sample != 0.0).unwrap();
+        assert_eq!(&samples[start..start + 10], &[0.25, 0.25, 0.25, 0.25, -0.25, -0.25, -0.25, -0.25, 0.25, 0.25]);
+    }
+
+    #[test]
+    fn pattern_phase_near_end() {
+        // A phase just below `1.0` rounds up to it as `f32`, but still plays the last bit
+        let mut audio = Audio::new(AudioConfig { sample_rate: 4, volume: 1.0, ..AudioConfig::default() });
+        audio.playing = true;
+        audio.phase = 1.0 - 1e-9;
+        audio.generate(1, 1, true, Some((&[0xFE; 16], 3999.7)));
+        assert_eq!(audio.take_samples()[0], -1.0);
+    }
+
+    #[test]
+    fn waveforms() {
+        let mut audio = Audio::new(AudioConfig { sample_rate: 8, frequency: 1.0, volume: 1.0, waveform: Waveform::Triangle });
+        audio.generate(1, 1, true, None);
+        assert_eq!(audio.take_samples(), [-1.0, -0.5, 0.0, 0.5, 1.0, 0.5, 0.0, -0.5]);
+
+        let mut audio = Audio::new(AudioConfig { sample_rate: 4, frequency: 1.0, volume: 1.0, waveform: Waveform::Sine });
+        audio.generate(1, 1, true, None);
+        let samples: Vec<i32> = audio.take_samples().iter().map(|sample| sample.round() as i32).collect();
+        assert_eq!(samples, [0, 1, 0, -1]);
+    }
+
+    #[test]
+    fn wav() {
+        let mut wav = Vec::new();
+        write_wav(&[0.0, 1.0, -2.0], 8000, &mut wav).unwrap();
+        assert_eq!(wav.len(), 44 + 6);
+        assert_eq!(&wav[..12], b"RIFF\x2A\x00\x00\x00WAVE");
+        assert_eq!(&wav[24..28], &8000u32.to_le_bytes());
+        assert_eq!(&wav[36..44], b"data\x06\x00\x00\x00");
+        assert_eq!(&wav[44..], &[0x00, 0x00, 0xFF, 0x7F, 0x01, 0x80]);
+    }
+}
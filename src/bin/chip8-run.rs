@@ -0,0 +1,262 @@
+//! Runs a CHIP-8 program without a user interface
+//!
+//! The program runs for a number of frames, or until it exits, waits for a
+//! key or reaches an idle loop. Then the screen and the registers are
+//! written out, e.g. to compare them with golden files in CI.
+
+extern crate chip8_vm;
+
+use std::cell::RefCell;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::rc::Rc;
+
+use chip8_vm::audio::{self, AudioConfig};
+use chip8_vm::error::Chip8Error;
+use chip8_vm::export::{self, Frame, ImageOptions, Recorder};
+use chip8_vm::instructions::{Instruction, Register};
+use chip8_vm::observer::VmObserver;
+use chip8_vm::quirks::Quirks;
+use chip8_vm::rng::XorShiftRng;
+use chip8_vm::timing::{CycleCosts, COSMAC_VIP_CLOCK_HZ};
+use chip8_vm::vm::{Vm, RAM_SIZE, XO_CHIP_RAM_SIZE};
+
+const USAGE: &str = "Usage: chip8-run [OPTIONS] ROM
+
+Options:
+    -n, --frames N       Run for at most N frames at 60 Hz (default 600)
+    -f, --format FORMAT  Write the screen as text, pbm or png (default text)
+    -o, --output FILE    Write the screen to FILE instead of stdout
+    -x, --scale N        Enlarge the pixels of PBM and PNG images N times (default 1)
+    -r, --record FILE    Record the screen of every frame as animated GIF to FILE
+    -w, --wav FILE       Record the sound as WAV file to FILE
+    -q, --quirks NAME    Interpret the program for vip, chip48, schip or xo (default xo)
+    -c, --clock HZ       Run at HZ instructions per second, or at the speed of the
+                         COSMAC VIP with vip (default 600)
+    -s, --seed N         Seed of the random numbers (default 0)
+    -h, --help           Print this help
+
+The registers are written to stdout after the screen, or to stderr if a
+PBM or PNG image is written to stdout.";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Text,
+    Pbm,
+    Png,
+}
+
+struct Options {
+    rom: String,
+    frames: usize,
+    format: Format,
+    output: Option<String>,
+    scale: usize,
+    record: Option<String>,
+    wav: Option<String>,
+    quirks: Quirks,
+    ram_size: usize,
+    clock_hz: u32,
+    cycle_costs: CycleCosts,
+    seed: u64,
+}
+
+/// Notices when the program can not make progress on its own anymore,
+/// i.e. it waits for a key or jumps to the jump itself
+#[derive(Default)]
+struct Idle(bool);
+
+impl VmObserver for Idle {
+    fn on_instruction(&mut self, pc: usize, ins: &Instruction) {
+        if let Instruction::Jump(addr) = *ins {
+            self.0 |= addr.bits as usize == pc;
+        }
+    }
+
+    fn on_wait_key(&mut self, _: Register) {
+        self.0 = true;
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut options = Options {
+        rom: String::new(),
+        frames: 600,
+        format: Format::Text,
+        output: None,
+        scale: 1,
+        record: None,
+        wav: None,
+        quirks: Quirks::xo_chip(),
+        ram_size: XO_CHIP_RAM_SIZE,
+        clock_hz: 600,
+        cycle_costs: CycleCosts::Uniform,
+        seed: 0,
+    };
+    let mut rom = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("Missing value for {}", arg));
+        match arg.as_str() {
+            "-n" | "--frames" => {
+                let frames = value()?;
+                options.frames = frames.parse().map_err(|_| format!("Invalid number of frames {}", frames))?;
+            },
+            "-f" | "--format" => {
+                options.format = match value()?.as_str() {
+                    "text" => Format::Text,
+                    "pbm" => Format::Pbm,
+                    "png" => Format::Png,
+                    other => return Err(format!("Unknown format {}", other)),
+                };
+            },
+            "-o" | "--output" => options.output = Some(value()?.clone()),
+            "-x" | "--scale" => {
+                let scale = value()?;
+                options.scale = match scale.parse() {
+                    Ok(scale) if scale > 0 => scale,
+                    _ => return Err(format!("Invalid scale {}", scale)),
+                };
+            },
+            "-r" | "--record" => options.record = Some(value()?.clone()),
+            "-w" | "--wav" => options.wav = Some(value()?.clone()),
+            "-q" | "--quirks" => {
+                let (quirks, ram_size) = match value()?.as_str() {
+                    "vip" => (Quirks::cosmac_vip(), RAM_SIZE),
+                    "chip48" => (Quirks::chip48(), RAM_SIZE),
+                    "schip" => (Quirks::super_chip(), RAM_SIZE),
+                    "xo" => (Quirks::xo_chip(), XO_CHIP_RAM_SIZE),
+                    other => return Err(format!("Unknown quirks {}", other)),
+                };
+                options.quirks = quirks;
+                options.ram_size = ram_size;
+            },
+            "-c" | "--clock" => {
+                let (clock_hz, cycle_costs) = match value()?.as_str() {
+                    "vip" => (COSMAC_VIP_CLOCK_HZ, CycleCosts::CosmacVip),
+                    clock => match clock.parse() {
+                        Ok(hz) if hz > 0 => (hz, CycleCosts::Uniform),
+                        _ => return Err(format!("Invalid clock speed {}", clock)),
+                    },
+                };
+                options.clock_hz = clock_hz;
+                options.cycle_costs = cycle_costs;
+            },
+            "-s" | "--seed" => {
+                let seed = value()?;
+                options.seed = seed.parse().map_err(|_| format!("Invalid seed {}", seed))?;
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            },
+            other if other.starts_with('-') => return Err(format!("Unknown option {}", other)),
+            other if rom.is_none() => rom = Some(other.to_string()),
+            other => return Err(format!("Unexpected argument {}", other)),
+        }
+    }
+    options.rom = rom.ok_or_else(|| "Missing ROM".to_string())?;
+    Ok(options)
+}
+
+/// Runs the program and returns the fault it stopped at, if any
+fn run(vm: &mut Vm, frames: usize, mut recorder: Option<&mut Recorder>) -> Option<Chip8Error> {
+    let idle = Rc::new(RefCell::new(Idle::default()));
+    vm.add_observer(Box::new(idle.clone()));
+    for _ in 0..frames {
+        if let Err(err) = vm.run_frame() {
+            return Some(err);
+        }
+        if let Some(ref mut recorder) = recorder {
+            recorder.capture(vm);
+        }
+        if vm.exited() || idle.borrow().0 {
+            break;
+        }
+    }
+    None
+}
+
+fn write_screen(vm: &Vm, options: &Options, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    let image = ImageOptions { scale: options.scale, ..ImageOptions::default() };
+    match options.format {
+        Format::Text => export::write_text(vm, writer),
+        Format::Pbm => Frame::capture(vm).write_pbm(&image, writer),
+        Format::Png => Frame::capture(vm).write_png(&image, writer),
+    }
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = parse_args(&args).unwrap_or_else(|err| {
+        eprintln!("{}\n\n{}", err, USAGE);
+        process::exit(2);
+    });
+
+    let mut vm = Vm::with_ram_size(options.quirks, options.ram_size);
+    vm.set_rng(Box::new(XorShiftRng::new(options.seed)));
+    vm.set_clock_hz(options.clock_hz);
+    vm.set_cycle_costs(options.cycle_costs);
+    let loaded = File::open(&options.rom)
+        .map_err(Chip8Error::from)
+        .and_then(|mut rom| vm.load_rom(&mut rom));
+    if let Err(err) = loaded {
+        eprintln!("Can not load {}: {}", options.rom, err);
+        process::exit(1);
+    }
+
+    if options.wav.is_some() {
+        vm.set_audio(Some(AudioConfig::default()));
+    }
+    let mut recorder = Recorder::new(ImageOptions { scale: options.scale, ..ImageOptions::default() });
+    let fault = run(&mut vm, options.frames, options.record.as_ref().map(|_| &mut recorder));
+    if let Some(ref path) = options.record {
+        let recorded = File::create(path).map_err(Chip8Error::from)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                recorder.write_gif(&mut writer)?;
+                writer.flush().map_err(Chip8Error::from)
+            });
+        if let Err(err) = recorded {
+            eprintln!("Can not write the recording: {}", err);
+            process::exit(1);
+        }
+    }
+    if let Some(ref path) = options.wav {
+        let samples = vm.take_samples();
+        let written = File::create(path).map_err(Chip8Error::from)
+            .and_then(|file| {
+                let mut writer = BufWriter::new(file);
+                audio::write_wav(&samples, AudioConfig::default().sample_rate, &mut writer)?;
+                writer.flush().map_err(Chip8Error::from)
+            });
+        if let Err(err) = written {
+            eprintln!("Can not write the sound: {}", err);
+            process::exit(1);
+        }
+    }
+
+    let stdout = io::stdout();
+    let written = match options.output {
+        Some(ref path) => File::create(path).map_err(Chip8Error::from)
+            .and_then(|mut file| write_screen(&vm, &options, &mut file))
+            .and_then(|_| export::write_registers(&vm, &mut stdout.lock())),
+        None if options.format == Format::Text => {
+            write_screen(&vm, &options, &mut stdout.lock())
+                .and_then(|_| export::write_registers(&vm, &mut stdout.lock()))
+        },
+        None => write_screen(&vm, &options, &mut stdout.lock())
+            .and_then(|_| export::write_registers(&vm, &mut io::stderr())),
+    };
+    if let Err(err) = written {
+        eprintln!("Can not write the screen: {}", err);
+        process::exit(1);
+    }
+
+    if let Some(err) = fault {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,240 @@
+//! Interactive terminal player
+//!
+//! Renders the screen at 60 Hz with Unicode half blocks, two rows of pixels
+//! per line of text, and maps the keys of a QWERTY keyboard onto the keypad:
+//!
+//! ```text
+//! 1 2 3 4        1 2 3 C
+//! Q W E R        4 5 6 D
+//! A S D F   ->   7 8 9 E
+//! Z X C V        A 0 B F
+//! ```
+//!
+//! Escape quits. Needs the `terminal` feature.
+
+extern crate chip8_vm;
+#[macro_use]
+extern crate crossterm;
+
+use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+use std::time::{Duration, Instant};
+
+use crossterm::cursor::{Hide, MoveTo, Show};
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::event::{KeyboardEnhancementFlags, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags};
+use crossterm::style::Print;
+use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
+
+use chip8_vm::error::Chip8Error;
+use chip8_vm::quirks::Quirks;
+use chip8_vm::timing::{CycleCosts, COSMAC_VIP_CLOCK_HZ};
+use chip8_vm::vm::{Vm, RAM_SIZE, XO_CHIP_RAM_SIZE};
+
+const USAGE: &str = "Usage: chip8-term [OPTIONS] ROM
+
+Options:
+    -q, --quirks NAME    Interpret the program for vip, chip48, schip or xo (default xo)
+    -c, --clock HZ       Run at HZ instructions per second, or at the speed of the
+                         COSMAC VIP with vip (default 600)
+    --flash              Flash the screen instead of ringing the bell for sounds
+    -h, --help           Print this help";
+
+/// Duration of one frame at 60 Hz
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// How long a key stays pressed after its last key event, for terminals
+/// that do not report releasing keys. Long enough to bridge the delay
+/// before a held key repeats.
+const KEY_HOLD: Duration = Duration::from_millis(300);
+
+/// QWERTY keys of the keypad keys `0` .. `F`
+const KEYS: &str = "x123qweasdzc4rfv";
+
+/// Raw mode on the alternate screen, restored on drop
+struct Terminal {
+    /// The terminal reports releasing keys
+    releases: bool,
+}
+
+impl Terminal {
+    fn enter() -> io::Result<Terminal> {
+        terminal::enable_raw_mode()?;
+        let mut stdout = io::stdout();
+        execute!(stdout, EnterAlternateScreen, Hide)?;
+        let releases = terminal::supports_keyboard_enhancement().unwrap_or(false);
+        if releases {
+            execute!(stdout, PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::REPORT_EVENT_TYPES))?;
+        }
+        Ok(Terminal { releases })
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        let mut stdout = io::stdout();
+        if self.releases {
+            let _ = execute!(stdout, PopKeyboardEnhancementFlags);
+        }
+        let _ = execute!(stdout, Show, LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Renders the screen with half blocks and a status line, inverted while `flash`
+fn render(vm: &Vm, flash: bool) -> String {
+    let rows: Vec<&[u8]> = vm.screen_rows().collect();
+    let mut text = String::new();
+    for pair in rows.chunks(2) {
+        for x in 0..vm.screen_width() {
+            let top = (pair[0][x] != 0) != flash;
+            let bottom = (pair.get(1).map(|row| row[x] != 0).unwrap_or(false)) != flash;
+            text.push(match (top, bottom) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        text.push_str("\r\n");
+    }
+    text.push_str(if vm.exited() { "Program exited, Esc quits" } else { "Esc quits" });
+    text
+}
+
+/// Plays the program until Escape is pressed, or returns its fault
+fn play(vm: &mut Vm, terminal: &Terminal, flash: bool) -> Result<(), Chip8Error> {
+    let mut stdout = io::stdout();
+    let mut release_at: [Option<Instant>; 16] = [None; 16];
+    let mut shown = String::new();
+    let mut hires = !vm.hires();
+    let mut beeping = false;
+    let mut next_frame = Instant::now();
+
+    loop {
+        next_frame += FRAME;
+        while event::poll(next_frame.saturating_duration_since(Instant::now()))? {
+            let key = match event::read()? {
+                Event::Key(key) => key,
+                _ => continue,
+            };
+            let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if key.code == KeyCode::Esc || ctrl_c {
+                return Ok(());
+            }
+            let idx = match key.code {
+                KeyCode::Char(c) => KEYS.find(c.to_ascii_lowercase()),
+                _ => None,
+            };
+            if let Some(idx) = idx {
+                if key.kind == KeyEventKind::Release {
+                    vm.unset_key(idx as u8)?;
+                } else {
+                    vm.set_key(idx as u8)?;
+                    if !terminal.releases {
+                        release_at[idx] = Some(Instant::now() + KEY_HOLD);
+                    }
+                }
+            }
+        }
+        for (idx, release) in release_at.iter_mut().enumerate() {
+            if release.map(|at| at <= Instant::now()).unwrap_or(false) {
+                *release = None;
+                vm.unset_key(idx as u8)?;
+            }
+        }
+
+        vm.run_frame()?;
+
+        if vm.beeping() && !beeping && !flash {
+            queue!(stdout, Print('\x07'))?;
+        }
+        beeping = vm.beeping();
+        if vm.hires() != hires {
+            hires = vm.hires();
+            queue!(stdout, Clear(ClearType::All))?;
+            shown.clear();
+        }
+        let frame = render(vm, flash && beeping);
+        if frame != shown {
+            queue!(stdout, MoveTo(0, 0), Print(&frame))?;
+            shown = frame;
+        }
+        stdout.flush()?;
+    }
+}
+
+fn main() {
+    let mut quirks = (Quirks::xo_chip(), XO_CHIP_RAM_SIZE);
+    let mut clock = None;
+    let mut flash = false;
+    let mut rom = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-q" | "--quirks" => {
+                quirks = match args.next().as_deref() {
+                    Some("vip") => (Quirks::cosmac_vip(), RAM_SIZE),
+                    Some("chip48") => (Quirks::chip48(), RAM_SIZE),
+                    Some("schip") => (Quirks::super_chip(), RAM_SIZE),
+                    Some("xo") => (Quirks::xo_chip(), XO_CHIP_RAM_SIZE),
+                    _ => {
+                        eprintln!("Unknown quirks\n\n{}", USAGE);
+                        process::exit(2);
+                    },
+                };
+            },
+            "-c" | "--clock" => {
+                clock = match args.next().as_deref() {
+                    Some("vip") => Some((COSMAC_VIP_CLOCK_HZ, CycleCosts::CosmacVip)),
+                    Some(hz) => match hz.parse() {
+                        Ok(hz) if hz > 0 => Some((hz, CycleCosts::Uniform)),
+                        _ => None,
+                    },
+                    None => None,
+                };
+                if clock.is_none() {
+                    eprintln!("Invalid clock speed\n\n{}", USAGE);
+                    process::exit(2);
+                }
+            },
+            "--flash" => flash = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            },
+            _ if rom.is_none() && !arg.starts_with('-') => rom = Some(arg),
+            _ => {
+                eprintln!("Unexpected argument {}\n\n{}", arg, USAGE);
+                process::exit(2);
+            },
+        }
+    }
+    let rom = rom.unwrap_or_else(|| {
+        eprintln!("Missing ROM\n\n{}", USAGE);
+        process::exit(2);
+    });
+
+    let mut vm = Vm::with_ram_size(quirks.0, quirks.1);
+    if let Some((hz, costs)) = clock {
+        vm.set_clock_hz(hz);
+        vm.set_cycle_costs(costs);
+    }
+    let loaded = File::open(&rom)
+        .map_err(Chip8Error::from)
+        .and_then(|mut file| vm.load_rom(&mut file));
+    if let Err(err) = loaded {
+        eprintln!("Can not load {}: {}", rom, err);
+        process::exit(1);
+    }
+
+    let played = Terminal::enter()
+        .map_err(Chip8Error::from)
+        .and_then(|terminal| play(&mut vm, &terminal, flash));
+    if let Err(err) = played {
+        eprintln!("{}", err);
+        process::exit(1);
+    }
+}
@@ -0,0 +1,260 @@
+//! Breakpoints and watchpoints for debuggers
+//!
+//! Every `Vm` owns a `Breakpoints` table. `Vm::step` checks it before each
+//! instruction and returns early with a `StopReason` when a breakpoint
+//! fires:
+//!
+//! * `Breakpoint::Address` stops before the instruction at an address,
+//!   optionally only if a register `Condition` holds.
+//! * `Breakpoint::Instruction` stops before any instruction of a kind,
+//!   e.g. every `Draw` regardless of its operands.
+//! * `Breakpoint::Watch` stops after an instruction read or wrote data in
+//!   a range of the RAM. Fetching instructions does not count as reading.
+
+use std::mem::{self, Discriminant};
+use std::ops::Range;
+
+use instructions::{Instruction, Register};
+use vm::Vm;
+
+/// Identifies a breakpoint in its `Breakpoints` table
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BreakpointId(usize);
+
+/// Comparison of a `Condition`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+/// Condition on a register, e.g. `V3 == 0x10`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Condition {
+    pub register: Register,
+    pub comparison: Comparison,
+    pub value: u8,
+}
+
+impl Condition {
+    /// Creates a condition comparing `register` with `value`
+    pub fn new(register: Register, comparison: Comparison, value: u8) -> Condition {
+        Condition { register, comparison, value }
+    }
+
+    /// Returns `true` if the condition holds for the registers of `vm`
+    pub fn holds(&self, vm: &Vm) -> bool {
+        let x = vm.register(self.register);
+        match self.comparison {
+            Comparison::Equal => x == self.value,
+            Comparison::NotEqual => x != self.value,
+            Comparison::Less => x < self.value,
+            Comparison::LessOrEqual => x <= self.value,
+            Comparison::Greater => x > self.value,
+            Comparison::GreaterOrEqual => x >= self.value,
+        }
+    }
+}
+
+/// Kind of memory access
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    /// Either reading or writing, only used for watchpoints
+    ReadWrite,
+}
+
+impl Access {
+    /// Returns `true` if a watchpoint on `self` fires for an `access`
+    fn covers(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+/// Breakpoint or watchpoint
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Breakpoint {
+    /// Stops before the instruction at `addr` is executed, if the
+    /// `condition` holds or there is none
+    Address { addr: usize, condition: Option<Condition> },
+    /// Stops before any instruction of the given kind is executed
+    Instruction(Discriminant<Instruction>),
+    /// Stops after an instruction accessed the RAM in `range`
+    Watch { range: Range<usize>, access: Access },
+}
+
+impl Breakpoint {
+    /// Breakpoint on any instruction of the same kind as `ins`,
+    /// ignoring its operands
+    pub fn instruction(ins: &Instruction) -> Breakpoint {
+        Breakpoint::Instruction(mem::discriminant(ins))
+    }
+}
+
+/// Why `Vm::step` stopped early
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StopReason {
+    /// The breakpoint `id` fired before the instruction at `pc`,
+    /// which has not been executed yet
+    Breakpoint { id: BreakpointId, pc: usize },
+    /// The watchpoint `id` fired after the instruction at `pc` accessed
+    /// the RAM at `addr`
+    Watchpoint { id: BreakpointId, pc: usize, addr: usize, access: Access },
+}
+
+/// Table of breakpoints and watchpoints
+#[derive(Clone, Debug, Default)]
+pub struct Breakpoints {
+    entries: Vec<(BreakpointId, Breakpoint)>,
+    next_id: usize,
+}
+
+impl Breakpoints {
+    /// Creates an empty table
+    pub fn new() -> Breakpoints {
+        Breakpoints::default()
+    }
+
+    /// Adds `breakpoint` and returns its id
+    pub fn add(&mut self, breakpoint: Breakpoint) -> BreakpointId {
+        let id = BreakpointId(self.next_id);
+        self.next_id += 1;
+        self.entries.push((id, breakpoint));
+        id
+    }
+
+    /// Removes the breakpoint `id` and returns it, if it exists
+    pub fn remove(&mut self, id: BreakpointId) -> Option<Breakpoint> {
+        let idx = self.entries.iter().position(|&(other, _)| other == id)?;
+        Some(self.entries.remove(idx).1)
+    }
+
+    /// Returns the breakpoint `id`, if it exists
+    pub fn get(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.entries.iter().find(|&&(other, _)| other == id).map(|(_, breakpoint)| breakpoint)
+    }
+
+    /// Iterates over all breakpoints and their ids, in the order they were added
+    pub fn iter(&self) -> impl Iterator<Item=(BreakpointId, &Breakpoint)> {
+        self.entries.iter().map(|(id, breakpoint)| (*id, breakpoint))
+    }
+
+    /// Removes all breakpoints
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Returns `true` if there are no breakpoints
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns the first breakpoint that fires before `ins` at `pc` is executed by `vm`
+    pub(crate) fn check(&self, vm: &Vm, pc: usize, ins: &Instruction) -> Option<BreakpointId> {
+        self.entries.iter().find(|(_, breakpoint)| match *breakpoint {
+            Breakpoint::Address { addr, condition } => {
+                addr == pc && condition.map(|condition| condition.holds(vm)).unwrap_or(true)
+            },
+            Breakpoint::Instruction(kind) => kind == mem::discriminant(ins),
+            Breakpoint::Watch { .. } => false,
+        }).map(|&(id, _)| id)
+    }
+
+    /// Returns the first watchpoint and address that fires for an `access`
+    /// of the RAM in `range`
+    pub(crate) fn check_access(&self, range: &Range<usize>, access: Access) -> Option<(BreakpointId, usize)> {
+        self.entries.iter().filter_map(|&(id, ref breakpoint)| match *breakpoint {
+            Breakpoint::Watch { range: ref watched, access: watched_access } if watched_access.covers(access) => {
+                let start = range.start.max(watched.start);
+                if start < range.end.min(watched.end) { Some((id, start)) } else { None }
+            },
+            _ => None,
+        }).next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn vm_with(source: &str) -> Vm {
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &assemble(source).unwrap()[..]).unwrap();
+        vm
+    }
+
+    const COUNTER: &str = "
+        loop:   ADD V3, 1
+                LD I, 0x300
+                LD [I], V3
+                LD I, 0x300
+                LD V0, [I]
+                LD I, 0x300
+                DRW V0, V0, 1
+                JP loop
+    ";
+
+    #[test]
+    fn address_breakpoint() {
+        let mut vm = vm_with(COUNTER);
+        let id = vm.breakpoints_mut().add(Breakpoint::Address { addr: 0x204, condition: None });
+
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Breakpoint { id, pc: 0x204 }));
+        assert_eq!(vm.pc(), 0x204);
+        assert_eq!(vm.register(Register::V3), 1);
+
+        // Resuming executes the instruction the breakpoint stopped at
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Breakpoint { id, pc: 0x204 }));
+        assert_eq!(vm.register(Register::V3), 2);
+
+        assert!(vm.breakpoints_mut().remove(id).is_some());
+        assert_eq!(vm.step(0.1).unwrap(), None);
+    }
+
+    #[test]
+    fn conditional_breakpoint() {
+        let mut vm = vm_with(COUNTER);
+        let condition = Condition::new(Register::V3, Comparison::Equal, 0x10);
+        let id = vm.breakpoints_mut().add(Breakpoint::Address { addr: 0x200, condition: Some(condition) });
+
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Breakpoint { id, pc: 0x200 }));
+        assert_eq!(vm.register(Register::V3), 0x10);
+    }
+
+    #[test]
+    fn instruction_breakpoint() {
+        let mut vm = vm_with(COUNTER);
+        let draw = Instruction::Draw(Register::V1, Register::V2, ::instructions::Nibble::new(5));
+        let id = vm.breakpoints_mut().add(Breakpoint::instruction(&draw));
+
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Breakpoint { id, pc: 0x20C }));
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Breakpoint { id, pc: 0x20C }));
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut vm = vm_with(COUNTER);
+        let write = vm.breakpoints_mut().add(Breakpoint::Watch { range: 0x300..0x301, access: Access::Write });
+        let read = vm.breakpoints_mut().add(Breakpoint::Watch { range: 0x2FF..0x301, access: Access::Read });
+
+        // Watchpoints stop after the accessing instruction
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Watchpoint { id: write, pc: 0x204, addr: 0x300, access: Access::Write }));
+        assert_eq!(vm.pc(), 0x206);
+        assert_eq!(vm.step(1.0).unwrap(), Some(StopReason::Watchpoint { id: read, pc: 0x208, addr: 0x300, access: Access::Read }));
+
+        // The sprite of `DRW` is read from `I` as well
+        vm.breakpoints_mut().remove(write);
+        assert_eq!(vm.step_instruction().unwrap().stop, None);
+        let outcome = vm.step_instruction().unwrap();
+        assert_eq!(outcome.stop, Some(StopReason::Watchpoint { id: read, pc: 0x20C, addr: 0x300, access: Access::Read }));
+
+        vm.breakpoints_mut().clear();
+        assert_eq!(vm.step(0.1).unwrap(), None);
+    }
+}
@@ -0,0 +1,281 @@
+//! Disassembler for CHIP-8 programs
+//!
+//! The disassembler traces the program by recursive descent: starting at
+//! the first instruction it follows every jump, call and skip, so only bytes
+//! that can actually be executed are shown as instructions. Everything else,
+//! like sprite data, is shown as `db` data bytes.
+//!
+//! Instructions can be written in the syntax of Cowgod's Chip-8 Technical
+//! Reference (the `Display` implementation of `Instruction`) or in the
+//! syntax of the Octo assembler (`Octo`).
+
+use std::fmt;
+use std::io::Write;
+
+use error::Chip8Error;
+use instructions::{Instruction, RawInstruction, Register};
+use vm::{Vm, PROGRAM_START};
+
+/// Assembly syntax of a listing
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Syntax {
+    /// Syntax of Cowgod's Chip-8 Technical Reference, e.g. `LD V1, 0x2A`
+    Cowgod,
+    /// Syntax of the Octo assembler, e.g. `v1 := 0x2A`
+    Octo,
+}
+
+/// What a `Line` of a listing contains
+#[derive(Clone, Copy, Debug)]
+pub enum LineKind {
+    /// Reachable instruction
+    Instruction(Instruction),
+    /// Data byte that is never executed
+    Data(u8),
+}
+
+/// One line of a listing
+#[derive(Clone, Debug)]
+pub struct Line {
+    /// Memory address of the line
+    pub addr: usize,
+    /// Raw bytes of the line
+    pub bytes: Vec<u8>,
+    /// Instruction or data of the line
+    pub kind: LineKind,
+}
+
+/// Disassembles `bytes` which are loaded at address `origin`,
+/// tracing the program from its start at `origin`
+pub fn disassemble(bytes: &[u8], origin: usize) -> Vec<Line> {
+    let code = trace(bytes, origin);
+
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let addr = origin + offset;
+        let kind = match code[offset] {
+            Some(ins) => LineKind::Instruction(ins),
+            None => LineKind::Data(bytes[offset]),
+        };
+        let size = match kind {
+            LineKind::Instruction(ins) => ins.size(),
+            LineKind::Data(_) => 1,
+        };
+        lines.push(Line { addr, bytes: bytes[offset..offset + size].to_vec(), kind });
+        offset += size;
+    }
+    lines
+}
+
+/// Disassembles the program in the RAM of `vm`
+///
+/// The listing starts at `PROGRAM_START` and ends at the last non-zero byte.
+pub fn disassemble_vm(vm: &Vm) -> Vec<Line> {
+    let ram = &vm.ram()[PROGRAM_START..];
+    let len = ram.iter().rposition(|b| *b != 0).map(|pos| pos + 1).unwrap_or(0);
+    disassemble(&ram[..len], PROGRAM_START)
+}
+
+/// Writes `lines` as a listing with one line per instruction or data byte,
+/// showing address, raw bits and mnemonic
+pub fn write_listing(writer: &mut dyn Write, lines: &[Line], syntax: Syntax) -> Result<(), Chip8Error> {
+    for line in lines {
+        let raw: String = line.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        let text = match (line.kind, syntax) {
+            (LineKind::Instruction(ins), Syntax::Cowgod) => ins.to_string(),
+            (LineKind::Instruction(ins), Syntax::Octo) => Octo(&ins).to_string(),
+            (LineKind::Data(b), Syntax::Cowgod) => format!("db 0x{:02X}", b),
+            (LineKind::Data(b), Syntax::Octo) => format!("0x{:02X}", b),
+        };
+        writeln!(writer, "0x{:03X}: {:<8} {}", line.addr, raw, text)?;
+    }
+    Ok(())
+}
+
+/// Returns for every byte of `bytes` the instruction starting at it,
+/// if it is reachable from `origin`
+fn trace(bytes: &[u8], origin: usize) -> Vec<Option<Instruction>> {
+    use instructions::Instruction::*;
+
+    let mut code = vec![None; bytes.len()];
+    let mut visited = vec![false; bytes.len()];
+    let mut pending = vec![origin];
+
+    let decode = |addr: usize| -> Option<Instruction> {
+        let offset = addr.checked_sub(origin)?;
+        let word = |offset: usize| bytes.get(offset..offset + 2)
+            .map(|w| RawInstruction::new(((w[0] as u16) << 8) | w[1] as u16));
+        let raw = word(offset)?;
+        let ins = if raw.is_long() {
+            Instruction::from_raw_long(&raw, &word(offset + 2)?)
+        } else {
+            Instruction::from_raw(&raw)
+        };
+        match ins {
+            Unknown => None,
+            ins => Some(ins),
+        }
+    };
+
+    while let Some(addr) = pending.pop() {
+        let offset = match addr.checked_sub(origin) {
+            Some(offset) if offset < bytes.len() => offset,
+            _ => continue,
+        };
+        if visited[offset] {
+            continue;
+        }
+        visited[offset] = true;
+
+        let ins = match decode(addr) {
+            Some(ins) => ins,
+            None => continue,
+        };
+        code[offset] = Some(ins);
+        let next = addr + ins.size();
+
+        match ins {
+            Return | Exit | LongJump(_) => {},
+            Jump(target) => pending.push(target.bits as usize),
+            Call(target) => {
+                pending.push(target.bits as usize);
+                pending.push(next);
+            },
+            SkipEqualK(..) | SkipNotEqualK(..) | SkipEqual(..) | SkipNotEqual(..) |
+            SkipPressed(_) | SkipNotPressed(_) => {
+                pending.push(next);
+                let skipped = decode(next).map(|ins| ins.size()).unwrap_or(2);
+                pending.push(next + skipped);
+            },
+            _ => pending.push(next),
+        }
+    }
+    code
+}
+
+/// Formats an `Instruction` in the syntax of the Octo assembler,
+/// e.g. `v1 := 0x2A` or `sprite v0 v1 5`
+pub struct Octo<'a>(pub &'a Instruction);
+
+impl<'a> fmt::Display for Octo<'a> {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use instructions::Instruction::*;
+
+        let v = |r: Register| format!("v{:x}", r as u8);
+
+        match *self.0 {
+            Sys(addr)               => write!(fmt, "0x{:02X} 0x{:02X} # sys", addr.bits >> 8, addr.bits & 0xFF),
+            Clear                   => write!(fmt, "clear"),
+            Return                  => write!(fmt, "return"),
+            Jump(addr)              => write!(fmt, "jump 0x{:03X}", addr.bits),
+            Call(addr)              => write!(fmt, ":call 0x{:03X}", addr.bits),
+            // Octo's conditionals say when the next instruction is executed, not when it is skipped
+            SkipEqualK(vx, k)       => write!(fmt, "if {} != 0x{:02X} then", v(vx), k),
+            SkipNotEqualK(vx, k)    => write!(fmt, "if {} == 0x{:02X} then", v(vx), k),
+            SkipEqual(vx, vy)       => write!(fmt, "if {} != {} then", v(vx), v(vy)),
+            SetK(vx, k)             => write!(fmt, "{} := 0x{:02X}", v(vx), k),
+            AddK(vx, k)             => write!(fmt, "{} += 0x{:02X}", v(vx), k),
+            Set(vx, vy)             => write!(fmt, "{} := {}", v(vx), v(vy)),
+            Or(vx, vy)              => write!(fmt, "{} |= {}", v(vx), v(vy)),
+            And(vx, vy)             => write!(fmt, "{} &= {}", v(vx), v(vy)),
+            XOr(vx, vy)             => write!(fmt, "{} ^= {}", v(vx), v(vy)),
+            Add(vx, vy)             => write!(fmt, "{} += {}", v(vx), v(vy)),
+            Sub(vx, vy)             => write!(fmt, "{} -= {}", v(vx), v(vy)),
+            ShiftRight(vx, vy)      => write!(fmt, "{} >>= {}", v(vx), v(vy)),
+            SubInv(vx, vy)          => write!(fmt, "{} =- {}", v(vx), v(vy)),
+            ShiftLeft(vx, vy)       => write!(fmt, "{} <<= {}", v(vx), v(vy)),
+            SkipNotEqual(vx, vy)    => write!(fmt, "if {} == {} then", v(vx), v(vy)),
+            LoadI(addr)             => write!(fmt, "i := 0x{:03X}", addr.bits),
+            LongJump(addr)          => write!(fmt, "jump0 0x{:03X}", addr.bits),
+            Rand(vx, k)             => write!(fmt, "{} := random 0x{:02X}", v(vx), k),
+            Draw(vx, vy, n)         => write!(fmt, "sprite {} {} {}", v(vx), v(vy), n.bits),
+            SkipPressed(vx)         => write!(fmt, "if {} -key then", v(vx)),
+            SkipNotPressed(vx)      => write!(fmt, "if {} key then", v(vx)),
+            GetTimer(vx)            => write!(fmt, "{} := delay", v(vx)),
+            WaitKey(vx)             => write!(fmt, "{} := key", v(vx)),
+            SetTimer(vx)            => write!(fmt, "delay := {}", v(vx)),
+            SetSoundTimer(vx)       => write!(fmt, "buzzer := {}", v(vx)),
+            AddToI(vx)              => write!(fmt, "i += {}", v(vx)),
+            LoadHexGlyph(vx)        => write!(fmt, "i := hex {}", v(vx)),
+            StoreBCD(vx)            => write!(fmt, "bcd {}", v(vx)),
+            StoreRegisters(vx)      => write!(fmt, "save {}", v(vx)),
+            LoadRegisters(vx)       => write!(fmt, "load {}", v(vx)),
+            ScrollDown(n)           => write!(fmt, "scroll-down {}", n.bits),
+            ScrollRight             => write!(fmt, "scroll-right"),
+            ScrollLeft              => write!(fmt, "scroll-left"),
+            Exit                    => write!(fmt, "exit"),
+            LowRes                  => write!(fmt, "lores"),
+            HighRes                 => write!(fmt, "hires"),
+            LoadLargeHexGlyph(vx)   => write!(fmt, "i := bighex {}", v(vx)),
+            StoreFlags(vx)          => write!(fmt, "saveflags {}", v(vx)),
+            LoadFlags(vx)           => write!(fmt, "loadflags {}", v(vx)),
+            SaveRange(vx, vy)       => write!(fmt, "save {} - {}", v(vx), v(vy)),
+            LoadRange(vx, vy)       => write!(fmt, "load {} - {}", v(vx), v(vy)),
+            LoadLongI(addr)         => write!(fmt, "i := long 0x{:04X}", addr.bits),
+            SelectPlanes(n)         => write!(fmt, "plane {}", n.bits),
+            LoadAudio               => write!(fmt, "audio"),
+            SetPitch(vx)            => write!(fmt, "pitch := {}", v(vx)),
+            ScrollUp(n)             => write!(fmt, "scroll-up {}", n.bits),
+            Unknown                 => write!(fmt, "# unknown"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // CLS ; LD I, 0x20E ; CALL 0x20C ; SE V0, 0 ; LD I, LONG 0x0000 ;
+    // RET ; sprite data
+    const ROM: [u8; 16] = [
+        0x00, 0xE0, 0xA2, 0x0E, 0x22, 0x0C, 0x30, 0x00,
+        0xF0, 0x00, 0x00, 0x00, 0x00, 0xEE, 0x3C, 0x42,
+    ];
+
+    #[test]
+    fn traces_reachable_code() {
+        let lines = disassemble(&ROM, PROGRAM_START);
+        let listing: Vec<(usize, String)> = lines.iter().map(|line| {
+            let text = match line.kind {
+                LineKind::Instruction(ins) => ins.to_string(),
+                LineKind::Data(b) => format!("db 0x{:02X}", b),
+            };
+            (line.addr, text)
+        }).collect();
+
+        assert_eq!(listing, vec![
+            (0x200, "CLS".to_string()),
+            (0x202, "LD I, 0x20E".to_string()),
+            (0x204, "CALL 0x20C".to_string()),
+            (0x206, "SE V0, 0x00".to_string()),
+            (0x208, "LD I, LONG 0x0000".to_string()),
+            (0x20C, "RET".to_string()),
+            (0x20E, "db 0x3C".to_string()),
+            (0x20F, "db 0x42".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn listing() {
+        let lines = disassemble(&ROM[..4], PROGRAM_START);
+        let mut cowgod = Vec::new();
+        write_listing(&mut cowgod, &lines, Syntax::Cowgod).unwrap();
+        assert_eq!(String::from_utf8(cowgod).unwrap(),
+                   "0x200: 00E0     CLS\n0x202: A20E     LD I, 0x20E\n");
+
+        let mut octo = Vec::new();
+        write_listing(&mut octo, &lines, Syntax::Octo).unwrap();
+        assert_eq!(String::from_utf8(octo).unwrap(),
+                   "0x200: 00E0     clear\n0x202: A20E     i := 0x20E\n");
+    }
+
+    #[test]
+    fn vm_ram() {
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &ROM[..]).unwrap();
+        let lines = disassemble_vm(&vm);
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0].addr, PROGRAM_START);
+    }
+}
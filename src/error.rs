@@ -10,11 +10,51 @@ use std::convert::From;
 pub enum Chip8Error {
     /// I/O error
     Io(&'static str, Option<io::Error>),
+    /// `Call` at `pc` with a full stack
+    StackOverflow { pc: usize },
+    /// `Return` at `pc` with an empty stack
+    StackUnderflow { pc: usize },
+    /// Memory access at `addr` outside of the RAM by the instruction at `pc`
+    MemoryOutOfBounds { addr: usize, pc: usize },
+    /// Key index that is not on the keypad
+    InvalidKey(u8),
+    /// Raw bits at `pc` that are not a valid instruction
+    UnknownOpcode { raw: u16, pc: usize },
+    /// Saved state that can not be restored
+    InvalidState(&'static str),
+    /// Save state of format version `found`, which is not the supported version `expected`
+    StateVersion { found: u16, expected: u16 },
+    /// Assembly source that can not be assembled, at `line` and `column` (both starting at 1)
+    Asm { line: usize, column: usize, message: String },
+    /// Input movie that can not be played back
+    InvalidMovie(&'static str),
+    /// Played back state that differs from the recorded checksum after `frame`
+    Desync { frame: usize, expected: u32, found: u32 },
 }
 
 impl fmt::Display for Chip8Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        return write!(fmt, "{}", self.description())
+        match *self {
+            Chip8Error::Io(desc, _) => write!(fmt, "{}", desc),
+            Chip8Error::StackOverflow { pc } =>
+                write!(fmt, "Stack overflow at 0x{:04X}", pc),
+            Chip8Error::StackUnderflow { pc } =>
+                write!(fmt, "Stack underflow at 0x{:04X}", pc),
+            Chip8Error::MemoryOutOfBounds { addr, pc } =>
+                write!(fmt, "Memory access out of bounds at 0x{:04X} (address 0x{:X})", pc, addr),
+            Chip8Error::InvalidKey(idx) =>
+                write!(fmt, "Invalid key {}", idx),
+            Chip8Error::UnknownOpcode { raw, pc } =>
+                write!(fmt, "Unknown opcode 0x{:04X} at 0x{:04X}", raw, pc),
+            Chip8Error::InvalidState(desc) => write!(fmt, "{}", desc),
+            Chip8Error::StateVersion { found, expected } =>
+                write!(fmt, "Save state version {} is not supported, expected {}", found, expected),
+            Chip8Error::Asm { line, column, ref message } =>
+                write!(fmt, "Assembly error at line {}, column {}: {}", line, column, message),
+            Chip8Error::InvalidMovie(desc) => write!(fmt, "{}", desc),
+            Chip8Error::Desync { frame, expected, found } =>
+                write!(fmt, "Desync after frame {}: checksum 0x{:08X}, expected 0x{:08X}", frame, found, expected),
+        }
     }
 }
 
@@ -22,10 +62,20 @@ impl Error for Chip8Error {
     fn description(&self) -> &str {
         match *self {
             Chip8Error::Io(desc, _) => desc,
+            Chip8Error::StackOverflow { .. } => "Stack overflow",
+            Chip8Error::StackUnderflow { .. } => "Stack underflow",
+            Chip8Error::MemoryOutOfBounds { .. } => "Memory access out of bounds",
+            Chip8Error::InvalidKey(_) => "Invalid key",
+            Chip8Error::UnknownOpcode { .. } => "Unknown opcode",
+            Chip8Error::InvalidState(desc) => desc,
+            Chip8Error::StateVersion { .. } => "Unsupported save state version",
+            Chip8Error::Asm { .. } => "Assembly error",
+            Chip8Error::InvalidMovie(desc) => desc,
+            Chip8Error::Desync { .. } => "Desync during movie playback",
         }
     }
 
-    fn cause(&self) -> Option<&Error> {
+    fn cause(&self) -> Option<&dyn Error> {
         match *self {
             Chip8Error::Io(_, Some(ref cause)) => Some(cause),
             _ => None,
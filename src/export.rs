@@ -0,0 +1,545 @@
+//! Exports of the screen and registers of a `Vm`
+//!
+//! The screen can be written as text, as a binary PBM image or as a PNG
+//! image, the registers as text. A `Recorder` captures the screen after
+//! every frame and writes the changes as animated GIF or as a sequence of
+//! PNG images.
+//!
+//! The encoders are kept free of dependencies: PNG images are stored
+//! without compression, GIF images use their mandatory LZW compression.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use error::Chip8Error;
+use instructions::Register;
+use vm::Vm;
+
+/// Number of colors, one for each combination of the two XO-CHIP bitplanes
+const NUM_COLORS: usize = 4;
+
+/// Scale and colors of exported images
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageOptions {
+    /// Width and height of each pixel in the image
+    pub scale: usize,
+    /// RGB colors of the pixel values, i.e. unlit, lit in the first
+    /// bitplane, lit in the second bitplane and lit in both
+    pub palette: [[u8; 3]; NUM_COLORS],
+}
+
+impl Default for ImageOptions {
+    /// White on black without scaling, with grays for the second bitplane
+    fn default() -> ImageOptions {
+        ImageOptions {
+            scale: 1,
+            palette: [[0x00, 0x00, 0x00], [0xFF, 0xFF, 0xFF], [0xAA, 0xAA, 0xAA], [0x55, 0x55, 0x55]],
+        }
+    }
+}
+
+/// Copy of the pixels of the screen
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub width: usize,
+    pub height: usize,
+    /// Pixel values row by row, see `Vm::screen_rows`
+    pub pixels: Vec<u8>,
+}
+
+impl Frame {
+    /// Copies the screen of `vm` in its current resolution
+    pub fn capture(vm: &Vm) -> Frame {
+        Frame {
+            width: vm.screen_width(),
+            height: vm.screen_height(),
+            pixels: vm.screen_rows().flat_map(|row| row.iter().cloned()).collect(),
+        }
+    }
+
+    /// Returns the rows of pixel values enlarged by `scale`
+    fn scaled_rows(&self, scale: usize) -> Vec<Vec<u8>> {
+        let mut rows = Vec::with_capacity(self.height * scale);
+        for row in self.pixels.chunks(self.width) {
+            let mut scaled = Vec::with_capacity(row.len() * scale);
+            for &pixel in row {
+                scaled.resize(scaled.len() + scale, pixel);
+            }
+            for _ in 0..scale {
+                rows.push(scaled.clone());
+            }
+        }
+        rows
+    }
+
+    /// Writes the frame as binary PBM image, where lit pixels are black
+    /// and the palette is ignored
+    pub fn write_pbm(&self, options: &ImageOptions, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+        let rows = self.scaled_rows(options.scale);
+        write!(writer, "P4\n{} {}\n", self.width * options.scale, rows.len())?;
+        for row in rows {
+            let lit: Vec<u8> = row.iter().map(|&pixel| (pixel != 0) as u8).collect();
+            writer.write_all(&pack_bits(&lit, 1))?;
+        }
+        Ok(())
+    }
+
+    /// Writes the frame as PNG image with the colors of the palette
+    pub fn write_png(&self, options: &ImageOptions, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+        let rows = self.scaled_rows(options.scale);
+        let mut header = Vec::new();
+        header.extend_from_slice(&((self.width * options.scale) as u32).to_be_bytes());
+        header.extend_from_slice(&(rows.len() as u32).to_be_bytes());
+        // Bit depth 2, indexed colors, deflate, adaptive filtering, no interlacing
+        header.extend_from_slice(&[2, 3, 0, 0, 0]);
+
+        // Each row starts with its filter type, 0 for none
+        let mut pixels = Vec::new();
+        for row in rows {
+            pixels.push(0);
+            pixels.extend_from_slice(&pack_bits(&row, 2));
+        }
+
+        writer.write_all(b"\x89PNG\r\n\x1a\n")?;
+        write_chunk(writer, b"IHDR", &header)?;
+        write_chunk(writer, b"PLTE", &options.palette.concat())?;
+        write_chunk(writer, b"IDAT", &zlib_stored(&pixels))?;
+        write_chunk(writer, b"IEND", &[])
+    }
+}
+
+/// Records the changes of the screen for animations
+///
+/// `capture` has to be called once after every frame, i.e. every 1/60 s
+/// of emulated time. Only frames that differ from the previous one are
+/// kept, together with the number of the frame they first appeared in.
+#[derive(Clone, Debug)]
+pub struct Recorder {
+    options: ImageOptions,
+    frames: Vec<(usize, Frame)>,
+    frame_count: usize,
+}
+
+impl Recorder {
+    /// Creates an empty recording
+    pub fn new(options: ImageOptions) -> Recorder {
+        Recorder { options, frames: Vec::new(), frame_count: 0 }
+    }
+
+    /// Captures the screen of `vm` at the end of a frame
+    pub fn capture(&mut self, vm: &Vm) {
+        let frame = Frame::capture(vm);
+        if self.frames.last().map(|(_, last)| *last != frame).unwrap_or(true) {
+            self.frames.push((self.frame_count, frame));
+        }
+        self.frame_count += 1;
+    }
+
+    /// Returns the recorded frames and the numbers of the frames they first
+    /// appeared in
+    pub fn frames(&self) -> &[(usize, Frame)] {
+        &self.frames
+    }
+
+    /// Writes the recording as looping animated GIF
+    ///
+    /// Delays are whole hundredths of a second, so frames of 1/60 s
+    /// alternate between 2 and 1 hundredths to keep the animation in sync.
+    /// Frames in low resolution are enlarged to fill the image if the
+    /// recording switches to high resolution.
+    pub fn write_gif(&self, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+        let scale = self.options.scale;
+        let width = self.frames.iter().map(|(_, frame)| frame.width).max().unwrap_or(0);
+        let height = self.frames.iter().map(|(_, frame)| frame.height).max().unwrap_or(0);
+        let mut size = Vec::new();
+        size.extend_from_slice(&((width * scale) as u16).to_le_bytes());
+        size.extend_from_slice(&((height * scale) as u16).to_le_bytes());
+
+        writer.write_all(b"GIF89a")?;
+        writer.write_all(&size)?;
+        // Global color table of 4 colors with 2 bits each, no background or aspect ratio
+        writer.write_all(&[0x91, 0, 0])?;
+        writer.write_all(&self.options.palette.concat())?;
+        // Loop forever
+        writer.write_all(b"\x21\xFF\x0BNETSCAPE2.0\x03\x01\x00\x00\x00")?;
+
+        for (idx, (start, frame)) in self.frames.iter().enumerate() {
+            let end = self.frames.get(idx + 1).map(|(next, _)| *next).unwrap_or(self.frame_count);
+            let delay = centiseconds(end) - centiseconds(*start);
+            writer.write_all(&[0x21, 0xF9, 4, 0])?;
+            writer.write_all(&(delay as u16).to_le_bytes())?;
+            writer.write_all(&[0, 0])?;
+
+            writer.write_all(&[0x2C, 0, 0, 0, 0])?;
+            writer.write_all(&size)?;
+            writer.write_all(&[0, 2])?;
+            let rows = frame.scaled_rows(scale * width / frame.width);
+            for block in lzw(2, &rows.concat()).chunks(255) {
+                writer.write_all(&[block.len() as u8])?;
+                writer.write_all(block)?;
+            }
+            writer.write_all(&[0])?;
+        }
+        writer.write_all(&[0x3B])?;
+        Ok(())
+    }
+
+    /// Writes the recorded frames as PNG images into `dir`
+    ///
+    /// The files are named like `frame_00042.png` after the number of the
+    /// frame they first appeared in, which gives their timing at 60 Hz.
+    /// Returns the number of files written.
+    pub fn write_png_sequence(&self, dir: &Path) -> Result<usize, Chip8Error> {
+        for (start, frame) in self.frames.iter() {
+            let file = File::create(dir.join(format!("frame_{:05}.png", start)))?;
+            let mut writer = BufWriter::new(file);
+            frame.write_png(&self.options, &mut writer)?;
+            writer.flush()?;
+        }
+        Ok(self.frames.len())
+    }
+}
+
+/// Writes the screen as text, one line per row with `▓` for lit and `░`
+/// for unlit pixels
+pub fn write_text(vm: &Vm, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    for row in vm.screen_rows() {
+        let line: String = row.iter().map(|&pixel| if pixel != 0 { '▓' } else { '░' }).collect();
+        writeln!(writer, "{}", line)?;
+    }
+    Ok(())
+}
+
+/// Writes the screen as binary PBM image, where lit pixels are black
+pub fn write_pbm(vm: &Vm, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    Frame::capture(vm).write_pbm(&ImageOptions::default(), writer)
+}
+
+/// Writes the screen as PNG image with the default `ImageOptions`
+pub fn write_png(vm: &Vm, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    Frame::capture(vm).write_png(&ImageOptions::default(), writer)
+}
+
+/// Writes the registers as text, e.g.
+///
+/// ```text
+/// PC=0200 I=0000 SP=00 DT=00 ST=00
+/// V0=00 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00
+/// V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=00
+/// ```
+pub fn write_registers(vm: &Vm, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+    writeln!(writer, "PC={:04X} I={:04X} SP={:02X} DT={:02X} ST={:02X}",
+             vm.pc(), vm.i(), vm.sp(), vm.delay_timer(), vm.sound_timer())?;
+    for half in 0..2 {
+        let line: Vec<String> = (half * 8..half * 8 + 8).map(|idx| {
+            let vx = Register::new(idx as u8).unwrap();
+            format!("V{:X}={:02X}", idx, vm.register(vx))
+        }).collect();
+        writeln!(writer, "{}", line.join(" "))?;
+    }
+    Ok(())
+}
+
+/// Time at the start of `frame` in whole hundredths of a second
+fn centiseconds(frame: usize) -> usize {
+    (frame * 100 + 30) / 60
+}
+
+/// Packs `values` of `bits` bits each into bytes, with the leftmost in the
+/// highest bits
+fn pack_bits(values: &[u8], bits: usize) -> Vec<u8> {
+    let mask = (1 << bits) - 1;
+    values.chunks(8 / bits).map(|values| {
+        values.iter().enumerate().fold(0, |byte, (idx, &value)| {
+            byte | ((value & mask) << (8 - bits * (idx + 1)))
+        })
+    }).collect()
+}
+
+/// Writes a PNG chunk of `kind` with its length and checksum
+fn write_chunk(writer: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> Result<(), Chip8Error> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let crc = crc32(&[&kind[..], data].concat());
+    writer.write_all(&crc.to_be_bytes())?;
+    Ok(())
+}
+
+/// Wraps `data` in a zlib stream of uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut stream = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none();
+        let len = block.len() as u16;
+        stream.push(last as u8);
+        stream.extend_from_slice(&len.to_le_bytes());
+        stream.extend_from_slice(&(!len).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(!0, |crc, &byte| {
+        (0..8).fold(crc ^ byte as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 }
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    (b << 16) | a
+}
+
+/// Number of codes of GIF's LZW compression
+const LZW_CODES: u16 = 4096;
+/// Largest size of LZW codes in bits
+const LZW_MAX_SIZE: u8 = 12;
+
+/// Compresses `values` of `min_size` bits with the LZW variant of GIF
+fn lzw(min_size: u8, values: &[u8]) -> Vec<u8> {
+    let clear = 1u16 << min_size;
+    let end = clear + 1;
+    let mut codes = HashMap::new();
+    let mut next = end + 1;
+    let mut size = min_size + 1;
+
+    let mut out = Vec::new();
+    let mut bits = 0u32;
+    let mut num_bits = 0;
+    let mut write = |code: u16, size: u8| {
+        bits |= (code as u32) << num_bits;
+        num_bits += size;
+        while num_bits >= 8 {
+            out.push(bits as u8);
+            bits >>= 8;
+            num_bits -= 8;
+        }
+    };
+
+    write(clear, size);
+    let mut prefix: Option<u16> = None;
+    for &value in values {
+        let current = match prefix {
+            Some(current) => current,
+            None => {
+                prefix = Some(value as u16);
+                continue;
+            },
+        };
+        if let Some(&code) = codes.get(&(current, value)) {
+            prefix = Some(code);
+            continue;
+        }
+        write(current, size);
+        // Decoders add each code one step later, so the size only grows
+        // after the first code past the current size was written
+        if next == 1 << size && size < LZW_MAX_SIZE {
+            size += 1;
+        }
+        if next < LZW_CODES {
+            codes.insert((current, value), next);
+            next += 1;
+        } else {
+            write(clear, size);
+            codes.clear();
+            next = end + 1;
+            size = min_size + 1;
+        }
+        prefix = Some(value as u16);
+    }
+    if let Some(current) = prefix {
+        write(current, size);
+        if next == 1 << size && size < LZW_MAX_SIZE {
+            size += 1;
+        }
+    }
+    write(end, size);
+    // Flush the last partial byte
+    write(0, 7);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+
+    fn vm_with_digit() -> Vm {
+        let rom = assemble("LD V0, 1\nLD F, V0\nDRW V0, V0, 5\nEXIT").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        vm.step(0.1).unwrap();
+        vm
+    }
+
+    /// Decompresses GIF's LZW data the way decoders do
+    fn unlzw(min_size: u8, data: &[u8]) -> Vec<u8> {
+        let clear = 1usize << min_size;
+        let mut table: Vec<Vec<u8>> = Vec::new();
+        let mut size = min_size + 1;
+        let mut prev: Option<Vec<u8>> = None;
+        let mut values = Vec::new();
+        let mut pos = 0;
+        loop {
+            let code = (0..size as usize).fold(0, |code, bit| {
+                let bit_pos = pos + bit;
+                code | (((data[bit_pos / 8] >> (bit_pos % 8)) & 1) as usize) << bit
+            });
+            pos += size as usize;
+            if code == clear {
+                table = (0..clear).map(|value| vec![value as u8]).collect();
+                table.extend(vec![vec![], vec![]]);
+                size = min_size + 1;
+                prev = None;
+                continue;
+            }
+            if code == clear + 1 {
+                return values;
+            }
+            let entry = match table.get(code) {
+                Some(entry) => entry.clone(),
+                None => {
+                    let mut entry = prev.clone().unwrap();
+                    entry.push(entry[0]);
+                    entry
+                },
+            };
+            if let Some(mut prev) = prev {
+                if table.len() < LZW_CODES as usize {
+                    prev.push(entry[0]);
+                    table.push(prev);
+                }
+                if table.len() == 1 << size && size < LZW_MAX_SIZE {
+                    size += 1;
+                }
+            }
+            values.extend_from_slice(&entry);
+            prev = Some(entry);
+        }
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+    }
+
+    #[test]
+    fn text_and_registers() {
+        let vm = vm_with_digit();
+        let mut text = Vec::new();
+        write_text(&vm, &mut text).unwrap();
+        let text = String::from_utf8(text).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 32);
+        assert!(lines[2].starts_with("░░▓▓░░░░"));
+
+        let mut registers = Vec::new();
+        write_registers(&vm, &mut registers).unwrap();
+        assert_eq!(String::from_utf8(registers).unwrap(),
+                   "PC=0208 I=0005 SP=00 DT=00 ST=00\n\
+                    V0=01 V1=00 V2=00 V3=00 V4=00 V5=00 V6=00 V7=00\n\
+                    V8=00 V9=00 VA=00 VB=00 VC=00 VD=00 VE=00 VF=00\n");
+    }
+
+    #[test]
+    fn pbm() {
+        let mut image = Vec::new();
+        write_pbm(&vm_with_digit(), &mut image).unwrap();
+        assert_eq!(&image[..9], b"P4\n64 32\n");
+        assert_eq!(image.len(), 9 + 32 * 8);
+        // Row 1 of the glyph "1" is 0x60, drawn at x = 1 and y = 1
+        assert_eq!(image[9 + 2 * 8], 0x30);
+
+        let mut image = Vec::new();
+        let options = ImageOptions { scale: 2, ..ImageOptions::default() };
+        Frame::capture(&vm_with_digit()).write_pbm(&options, &mut image).unwrap();
+        assert_eq!(&image[..10], b"P4\n128 64\n");
+        assert_eq!(image.len(), 10 + 64 * 16);
+        assert_eq!(image[10 + 4 * 16], 0x0F);
+        assert_eq!(image[10 + 5 * 16], 0x0F);
+    }
+
+    #[test]
+    fn png() {
+        let mut image = Vec::new();
+        let options = ImageOptions { scale: 3, ..ImageOptions::default() };
+        Frame::capture(&vm_with_digit()).write_png(&options, &mut image).unwrap();
+        assert_eq!(&image[..8], b"\x89PNG\r\n\x1a\n");
+        assert_eq!(&image[12..16], b"IHDR");
+        assert_eq!(&image[16..29], &[0, 0, 0, 192, 0, 0, 0, 96, 2, 3, 0, 0, 0]);
+        assert_eq!(&image[33..41], &[0, 0, 0, 12, b'P', b'L', b'T', b'E']);
+        assert_eq!(&image[image.len() - 12..], &[0, 0, 0, 0, b'I', b'E', b'N', b'D', 0xAE, 0x42, 0x60, 0x82]);
+
+        let idat = &image[57..];
+        assert_eq!(&idat[4..8], b"IDAT");
+        let len = 96 * (1 + 48);
+        assert_eq!(&idat[..4], &(2 + 5 + len as u32 + 4).to_be_bytes());
+        assert_eq!(&idat[8..15], &[0x78, 0x01, 1, 0x60, 0x12, 0x9F, 0xED]);
+        // Row 1 of the glyph "1" is 0x60, drawn at x = 1 and y = 1
+        let row = &idat[15 + 6 * 49..15 + 7 * 49];
+        assert_eq!(&row[..4], &[0, 0, 0b0000_0101, 0b0101_0101]);
+    }
+
+    #[test]
+    fn lzw_round_trip() {
+        let noise: Vec<u8> = (0..20000u32).map(|idx| (idx.wrapping_mul(2654435761) >> 13) as u8 & 3).collect();
+        let runs: Vec<u8> = (0..20000).map(|idx| (idx / 300 % 4) as u8).collect();
+        for values in &[vec![], vec![1], noise, runs] {
+            assert_eq!(&unlzw(2, &lzw(2, values)), values);
+        }
+    }
+
+    #[test]
+    fn gif() {
+        let rom = assemble("
+            loop:   LD F, V0
+                    DRW V1, V1, 5
+                    LD V2, K
+                    DRW V1, V1, 5
+                    ADD V0, 1
+                    JP loop
+        ").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        let mut recorder = Recorder::new(ImageOptions::default());
+        // The next digit is drawn every 3 frames
+        for frame in 0..12 {
+            if frame % 3 == 0 {
+                vm.set_key(0).unwrap();
+                vm.unset_key(0).unwrap();
+            }
+            vm.run_frame().unwrap();
+            recorder.capture(&vm);
+        }
+        let starts: Vec<usize> = recorder.frames().iter().map(|(start, _)| *start).collect();
+        assert_eq!(starts, [0, 3, 6, 9]);
+
+        let mut image = Vec::new();
+        recorder.write_gif(&mut image).unwrap();
+        assert_eq!(&image[..13], b"GIF89a\x40\x00\x20\x00\x91\x00\x00");
+        assert_eq!(*image.last().unwrap(), 0x3B);
+
+        // 3 frames of 1/60 s each take 5/100 s
+        let delays: Vec<u16> = (0..image.len() - 6)
+            .filter(|&pos| image[pos..pos + 3] == [0x21, 0xF9, 4])
+            .map(|pos| u16::from_le_bytes([image[pos + 4], image[pos + 5]]))
+            .collect();
+        assert_eq!(delays, [5, 5, 5, 5]);
+        let single: Vec<usize> = (0..4).map(|frame| centiseconds(frame + 1) - centiseconds(frame)).collect();
+        assert_eq!(single, [2, 1, 2, 2]);
+    }
+}
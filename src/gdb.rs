@@ -0,0 +1,599 @@
+//! GDB remote serial protocol server
+//!
+//! `GdbServer` lets a debugger that speaks the GDB remote serial protocol
+//! attach to a `Vm` over TCP, e.g. with `target remote localhost:1234`.
+//! It supports reading and writing the registers (`g`, `G`, `p`, `P`) and
+//! the RAM (`m`, `M`), breakpoints and watchpoints (`Z0`, `Z2` .. `Z4`),
+//! single-stepping (`s`), continuing (`c`, interrupted with Ctrl-C) and
+//! the target description (`qXfer:features:read`).
+//!
+//! The registers are `V0` .. `VF` with 8 bits each, followed by `I` and
+//! `pc` with 16 bits each and the stack depth `sp` with 8 bits. Values are
+//! sent in the big-endian byte order of CHIP-8. The stack pointer can not
+//! be written.
+//!
+//! While continuing, the `Vm` runs at its normal speed in steps of one
+//! frame.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use breakpoints::{Access, Breakpoint, BreakpointId, StopReason};
+use error::Chip8Error;
+use instructions::Register;
+use vm::Vm;
+
+/// Duration of one step of the `Vm` while continuing
+const FRAME: Duration = Duration::from_micros(16_667);
+
+/// Number of registers in the `g` packet
+const NUM_REGISTERS: usize = 19;
+
+/// Target description with the CHIP-8 register set
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.chip8.core">
+    <reg name="v0" bitsize="8" type="uint8" regnum="0"/>
+    <reg name="v1" bitsize="8" type="uint8"/>
+    <reg name="v2" bitsize="8" type="uint8"/>
+    <reg name="v3" bitsize="8" type="uint8"/>
+    <reg name="v4" bitsize="8" type="uint8"/>
+    <reg name="v5" bitsize="8" type="uint8"/>
+    <reg name="v6" bitsize="8" type="uint8"/>
+    <reg name="v7" bitsize="8" type="uint8"/>
+    <reg name="v8" bitsize="8" type="uint8"/>
+    <reg name="v9" bitsize="8" type="uint8"/>
+    <reg name="va" bitsize="8" type="uint8"/>
+    <reg name="vb" bitsize="8" type="uint8"/>
+    <reg name="vc" bitsize="8" type="uint8"/>
+    <reg name="vd" bitsize="8" type="uint8"/>
+    <reg name="ve" bitsize="8" type="uint8"/>
+    <reg name="vf" bitsize="8" type="uint8"/>
+    <reg name="i" bitsize="16" type="data_ptr"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+  </feature>
+</target>
+"#;
+
+/// Server that debuggers connect to
+pub struct GdbServer {
+    listener: TcpListener,
+}
+
+impl GdbServer {
+    /// Creates a server listening on `addr`
+    pub fn bind<A: ToSocketAddrs>(addr: A) -> Result<GdbServer, Chip8Error> {
+        Ok(GdbServer { listener: TcpListener::bind(addr)? })
+    }
+
+    /// Returns the address the server listens on
+    pub fn local_addr(&self) -> Result<SocketAddr, Chip8Error> {
+        Ok(self.listener.local_addr()?)
+    }
+
+    /// Waits for a debugger to connect and lets it control `vm` until it
+    /// detaches, kills the program or disconnects
+    ///
+    /// Breakpoints set by the debugger are removed afterwards.
+    pub fn serve(&self, vm: &mut Vm) -> Result<(), Chip8Error> {
+        let (stream, peer) = self.listener.accept()?;
+        info!("Debugger connected from {}", peer);
+        let mut session = Session { stream, vm, ack: true, breakpoints: HashMap::new() };
+        let result = session.run();
+        for (_, id) in session.breakpoints.drain() {
+            session.vm.breakpoints_mut().remove(id);
+        }
+        info!("Debugger disconnected");
+        result
+    }
+}
+
+/// Connection to one debugger
+struct Session<'a> {
+    stream: TcpStream,
+    vm: &'a mut Vm,
+    /// `false` after the debugger asked for `QStartNoAckMode`
+    ack: bool,
+    /// Breakpoints set by the debugger, by type, address and length
+    breakpoints: HashMap<(u8, usize, usize), BreakpointId>,
+}
+
+impl<'a> Session<'a> {
+    fn run(&mut self) -> Result<(), Chip8Error> {
+        while let Some(packet) = self.read_packet()? {
+            debug!("GDB packet {}", packet);
+            let reply = match self.handle(&packet) {
+                Ok(Some(reply)) => reply,
+                Ok(None) => {
+                    // Detached or killed
+                    if packet.starts_with('D') {
+                        self.write_packet("OK")?;
+                    }
+                    return Ok(());
+                },
+                Err(err @ Chip8Error::Io(..)) => return Err(err),
+                Err(err) => {
+                    debug!("GDB request failed: {}", err);
+                    "E01".to_string()
+                },
+            };
+            self.write_packet(&reply)?;
+            if packet == "QStartNoAckMode" {
+                // Only the reply to it is still acknowledged
+                self.ack = false;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_byte(&mut self) -> Result<Option<u8>, Chip8Error> {
+        let mut byte = [0];
+        match self.stream.read(&mut byte)? {
+            0 => Ok(None),
+            _ => Ok(Some(byte[0])),
+        }
+    }
+
+    /// Reads the next packet, or `None` if the debugger disconnected
+    fn read_packet(&mut self) -> Result<Option<String>, Chip8Error> {
+        loop {
+            // Skip acknowledgements and interrupts outside of `c`
+            loop {
+                match self.read_byte()? {
+                    Some(b'$') => break,
+                    Some(_) => continue,
+                    None => return Ok(None),
+                }
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    Some(b'#') => break,
+                    Some(byte) => data.push(byte),
+                    None => return Ok(None),
+                }
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum)?;
+
+            let expected = ::std::str::from_utf8(&checksum).ok()
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+            if self.ack {
+                if expected != Some(checksum_of(&data)) {
+                    self.stream.write_all(b"-")?;
+                    continue;
+                }
+                self.stream.write_all(b"+")?;
+            }
+            return Ok(Some(unescape(&data)));
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> Result<(), Chip8Error> {
+        let data = escape(data.as_bytes());
+        let mut packet = Vec::with_capacity(data.len() + 4);
+        packet.push(b'$');
+        packet.extend_from_slice(&data);
+        packet.extend_from_slice(format!("#{:02x}", checksum_of(&data)).as_bytes());
+        loop {
+            self.stream.write_all(&packet)?;
+            if !self.ack {
+                return Ok(());
+            }
+            match self.read_byte()? {
+                Some(b'-') => continue,
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Handles one packet and returns the reply, or `None` to end the session
+    fn handle(&mut self, packet: &str) -> Result<Option<String>, Chip8Error> {
+        let (command, args) = packet.split_at(packet.len().min(1));
+        let reply = match command {
+            "?" => "S05".to_string(),
+            "g" => {
+                let mut hex = String::new();
+                for idx in 0..NUM_REGISTERS {
+                    hex.push_str(&self.read_register(idx));
+                }
+                hex
+            },
+            "G" => {
+                let bytes = decode_hex(args)?;
+                let mut offset = 0;
+                for idx in 0..NUM_REGISTERS {
+                    let size = register_size(idx);
+                    if offset + size > bytes.len() {
+                        break;
+                    }
+                    self.write_register(idx, &bytes[offset..offset + size])?;
+                    offset += size;
+                }
+                "OK".to_string()
+            },
+            "p" => {
+                let idx = parse_hex(args)?;
+                if idx >= NUM_REGISTERS {
+                    return Err(Chip8Error::InvalidState("Unknown register"));
+                }
+                self.read_register(idx)
+            },
+            "P" => {
+                let (idx, value) = split(args, '=')?;
+                let idx = parse_hex(idx)?;
+                if idx >= NUM_REGISTERS {
+                    return Err(Chip8Error::InvalidState("Unknown register"));
+                }
+                self.write_register(idx, &decode_hex(value)?)?;
+                "OK".to_string()
+            },
+            "m" => {
+                let (addr, len) = split(args, ',')?;
+                let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+                let ram = self.vm.ram();
+                if addr.checked_add(len).map(|end| end > ram.len()).unwrap_or(true) {
+                    return Err(Chip8Error::MemoryOutOfBounds { addr, pc: self.vm.pc() });
+                }
+                encode_hex(&ram[addr..addr + len])
+            },
+            "M" => {
+                let (target, data) = split(args, ':')?;
+                let (addr, len) = split(target, ',')?;
+                let (addr, len) = (parse_hex(addr)?, parse_hex(len)?);
+                let data = decode_hex(data)?;
+                if data.len() != len {
+                    return Err(Chip8Error::InvalidState("Memory write length mismatch"));
+                }
+                self.vm.write_ram(addr, &data)?;
+                "OK".to_string()
+            },
+            "Z" | "z" => self.breakpoint(command == "Z", args)?,
+            "s" => {
+                if !args.is_empty() {
+                    let pc = parse_hex(args)?;
+                    self.vm.set_pc(pc);
+                }
+                self.single_step()
+            },
+            "c" => {
+                if !args.is_empty() {
+                    let pc = parse_hex(args)?;
+                    self.vm.set_pc(pc);
+                }
+                self.resume()?
+            },
+            "H" | "T" => "OK".to_string(),
+            "D" | "k" => return Ok(None),
+            "q" | "Q" => self.query(packet)?,
+            // Unsupported, including `vCont` which makes GDB fall back to `s` and `c`
+            _ => String::new(),
+        };
+        Ok(Some(reply))
+    }
+
+    fn query(&mut self, packet: &str) -> Result<String, Chip8Error> {
+        if packet.starts_with("qSupported") {
+            return Ok("PacketSize=4000;qXfer:features:read+;QStartNoAckMode+".to_string());
+        }
+        if let Some(args) = packet.strip_prefix("qXfer:features:read:") {
+            let (annex, range) = split(args, ':')?;
+            if annex != "target.xml" {
+                return Ok("E00".to_string());
+            }
+            let (offset, len) = split(range, ',')?;
+            let (offset, len) = (parse_hex(offset)?, parse_hex(len)?);
+            let xml = TARGET_XML.as_bytes();
+            let start = offset.min(xml.len());
+            let end = start.saturating_add(len).min(xml.len());
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return Ok(format!("{}{}", marker, String::from_utf8_lossy(&xml[start..end])));
+        }
+        Ok(match packet {
+            "QStartNoAckMode" => "OK".to_string(),
+            "qAttached" => "1".to_string(),
+            "qC" => "QC1".to_string(),
+            "qfThreadInfo" => "m1".to_string(),
+            "qsThreadInfo" => "l".to_string(),
+            _ => String::new(),
+        })
+    }
+
+    /// `Z` and `z` packets: `type,addr,kind`
+    fn breakpoint(&mut self, insert: bool, args: &str) -> Result<String, Chip8Error> {
+        let mut fields = args.split(',');
+        let kind = fields.next().and_then(|kind| kind.parse::<u8>().ok());
+        let addr = parse_hex(fields.next().unwrap_or(""))?;
+        let len = parse_hex(fields.next().unwrap_or(""))?;
+        let range = || addr.checked_add(len)
+            .map(|end| addr..end)
+            .ok_or(Chip8Error::InvalidState("Malformed watchpoint range"));
+
+        let breakpoint = match kind {
+            Some(0) => Breakpoint::Address { addr, condition: None },
+            Some(2) => Breakpoint::Watch { range: range()?, access: Access::Write },
+            Some(3) => Breakpoint::Watch { range: range()?, access: Access::Read },
+            Some(4) => Breakpoint::Watch { range: range()?, access: Access::ReadWrite },
+            _ => return Ok(String::new()),
+        };
+        let key = (kind.unwrap(), addr, len);
+        if insert {
+            if !self.breakpoints.contains_key(&key) {
+                let id = self.vm.breakpoints_mut().add(breakpoint);
+                self.breakpoints.insert(key, id);
+            }
+        } else if let Some(id) = self.breakpoints.remove(&key) {
+            self.vm.breakpoints_mut().remove(id);
+        }
+        Ok("OK".to_string())
+    }
+
+    fn read_register(&self, idx: usize) -> String {
+        match idx {
+            0..=15 => format!("{:02x}", self.vm.register(Register::new(idx as u8).unwrap())),
+            16 => format!("{:04x}", self.vm.i()),
+            17 => format!("{:04x}", self.vm.pc()),
+            _ => format!("{:02x}", self.vm.sp()),
+        }
+    }
+
+    fn write_register(&mut self, idx: usize, bytes: &[u8]) -> Result<(), Chip8Error> {
+        if bytes.len() != register_size(idx) {
+            return Err(Chip8Error::InvalidState("Register value has the wrong size"));
+        }
+        let value = bytes.iter().fold(0usize, |value, byte| (value << 8) | *byte as usize);
+        match idx {
+            0..=15 => self.vm.set_register(Register::new(idx as u8).unwrap(), value as u8),
+            16 => self.vm.set_i(value),
+            17 => self.vm.set_pc(value),
+            // The stack pointer is read-only
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn single_step(&mut self) -> String {
+        if self.vm.exited() {
+            return "W00".to_string();
+        }
+        match self.vm.step_instruction() {
+            Ok(outcome) => match outcome.stop {
+                Some(reason) => self.stop_reply(reason),
+                None => "S05".to_string(),
+            },
+            Err(err) => fault_reply(&err),
+        }
+    }
+
+    /// Runs the `Vm` until it stops, exits or the debugger interrupts it
+    fn resume(&mut self) -> Result<String, Chip8Error> {
+        self.stream.set_read_timeout(Some(FRAME))?;
+        let reply = self.run_until_stop();
+        self.stream.set_read_timeout(None)?;
+        reply
+    }
+
+    fn run_until_stop(&mut self) -> Result<String, Chip8Error> {
+        loop {
+            if self.vm.exited() {
+                return Ok("W00".to_string());
+            }
+            match self.vm.run_frame() {
+                Ok(Some(reason)) => return Ok(self.stop_reply(reason)),
+                Ok(None) => (),
+                Err(err) => return Ok(fault_reply(&err)),
+            }
+
+            // Waiting for input paces the execution to one step per frame
+            let mut byte = [0];
+            match self.stream.read(&mut byte) {
+                Ok(0) => return Err(Chip8Error::Io("Debugger disconnected", None)),
+                Ok(_) if byte[0] == 0x03 => return Ok("S02".to_string()),
+                Ok(_) => (),
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint { .. } => "S05".to_string(),
+            StopReason::Watchpoint { id, addr, .. } => {
+                let kind = match self.vm.breakpoints().get(id) {
+                    Some(&Breakpoint::Watch { access: Access::Read, .. }) => "rwatch",
+                    Some(&Breakpoint::Watch { access: Access::ReadWrite, .. }) => "awatch",
+                    _ => "watch",
+                };
+                format!("T05{}:{:x};", kind, addr)
+            },
+        }
+    }
+}
+
+/// Stop reply for a fault of the program
+fn fault_reply(err: &Chip8Error) -> String {
+    debug!("Program stopped on fault: {}", err);
+    match *err {
+        // SIGILL
+        Chip8Error::UnknownOpcode { .. } => "S04".to_string(),
+        // SIGSEGV
+        _ => "S0b".to_string(),
+    }
+}
+
+fn register_size(idx: usize) -> usize {
+    match idx {
+        16 | 17 => 2,
+        _ => 1,
+    }
+}
+
+fn checksum_of(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte))
+}
+
+/// Escapes the characters that have a meaning in packets
+fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for &byte in data.iter() {
+        match byte {
+            b'$' | b'#' | b'}' | b'*' => {
+                escaped.push(b'}');
+                escaped.push(byte ^ 0x20);
+            },
+            _ => escaped.push(byte),
+        }
+    }
+    escaped
+}
+
+fn unescape(data: &[u8]) -> String {
+    let mut unescaped = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => unescaped.extend(bytes.next().map(|byte| byte ^ 0x20)),
+            _ => unescaped.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&unescaped).into_owned()
+}
+
+fn split(args: &str, separator: char) -> Result<(&str, &str), Chip8Error> {
+    let idx = args.find(separator).ok_or(Chip8Error::InvalidState("Malformed packet"))?;
+    Ok((&args[..idx], &args[idx + 1..]))
+}
+
+fn parse_hex(hex: &str) -> Result<usize, Chip8Error> {
+    usize::from_str_radix(hex, 16).map_err(|_| Chip8Error::InvalidState("Malformed number"))
+}
+
+// `is_multiple_of` needs a newer compiler than this crate supports
+#[allow(unknown_lints, clippy::manual_is_multiple_of)]
+fn decode_hex(hex: &str) -> Result<Vec<u8>, Chip8Error> {
+    if hex.len() % 2 != 0 || !hex.is_ascii() {
+        return Err(Chip8Error::InvalidState("Malformed hex data"));
+    }
+    (0..hex.len()).step_by(2)
+        .map(|idx| u8::from_str_radix(&hex[idx..idx + 2], 16).map_err(|_| Chip8Error::InvalidState("Malformed hex data")))
+        .collect()
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use std::sync::mpsc;
+    use std::thread;
+
+    struct Client {
+        stream: TcpStream,
+    }
+
+    impl Client {
+        fn request(&mut self, data: &str) -> String {
+            let packet = format!("${}#{:02x}", data, checksum_of(data.as_bytes()));
+            self.stream.write_all(packet.as_bytes()).unwrap();
+
+            let mut byte = [0];
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'+');
+            self.stream.read_exact(&mut byte).unwrap();
+            assert_eq!(byte[0], b'$');
+
+            let mut reply = Vec::new();
+            loop {
+                self.stream.read_exact(&mut byte).unwrap();
+                if byte[0] == b'#' {
+                    break;
+                }
+                reply.push(byte[0]);
+            }
+            let mut checksum = [0; 2];
+            self.stream.read_exact(&mut checksum).unwrap();
+            assert_eq!(::std::str::from_utf8(&checksum).unwrap(), format!("{:02x}", checksum_of(&reply)));
+            self.stream.write_all(b"+").unwrap();
+            unescape(&reply)
+        }
+    }
+
+    #[test]
+    fn loopback_session() {
+        let (addr_tx, addr_rx) = mpsc::channel();
+        let server = thread::spawn(move || {
+            let rom = assemble("
+                start:  ADD V1, 1       ; 0x200
+                        LD I, 0x300     ; 0x202
+                        LD [I], V1      ; 0x204
+                        JP start        ; 0x206
+                count:  ADD V2, 1       ; 0x208
+                        JP count        ; 0x20A
+            ").unwrap();
+            let mut vm = Vm::with_seed(0);
+            vm.load_rom(&mut &rom[..]).unwrap();
+
+            let server = GdbServer::bind("127.0.0.1:0").unwrap();
+            addr_tx.send(server.local_addr().unwrap()).unwrap();
+            server.serve(&mut vm).unwrap();
+            assert!(vm.breakpoints().is_empty());
+            (vm.register(Register::V0), vm.ram()[0x400])
+        });
+
+        let mut client = Client { stream: TcpStream::connect(addr_rx.recv().unwrap()).unwrap() };
+        assert!(client.request("qSupported:xmlRegisters=i386").contains("qXfer:features:read+"));
+        let xml = client.request("qXfer:features:read:target.xml:0,fff");
+        assert!(xml.starts_with("l<?xml"));
+        assert!(xml.contains("<reg name=\"pc\" bitsize=\"16\" type=\"code_ptr\"/>"));
+        assert_eq!(client.request("?"), "S05");
+
+        // V0..VF, I, pc, sp
+        assert_eq!(client.request("g"), format!("{}00000200{}", "00".repeat(16), "00"));
+
+        assert_eq!(client.request("Z0,204,2"), "OK");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p11"), "0204");
+        assert_eq!(client.request("p1"), "01");
+
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p11"), "0206");
+        assert_eq!(client.request("m300,2"), "0001");
+
+        assert_eq!(client.request("z0,204,2"), "OK");
+        assert_eq!(client.request("Z2,301,1"), "OK");
+        assert_eq!(client.request("c"), "T05watch:301;");
+        assert_eq!(client.request("p1"), "02");
+
+        let mut regs = client.request("g");
+        regs.replace_range(0..2, "2a");
+        assert_eq!(client.request(&format!("G{}", regs)), "OK");
+        assert_eq!(client.request("M400,2:beef"), "OK");
+        assert_eq!(client.request("m400,2"), "beef");
+        assert_eq!(client.request("mfff0,100"), "E01");
+        assert_eq!(client.request("Mffffffffffffffff,1:00"), "E01");
+        assert_eq!(client.request("Z2,ffffffffffffffff,2"), "E01");
+        assert_eq!(client.request("vCont?"), "");
+
+        // Stepping around the loop back to a breakpoint does not skip it
+        assert_eq!(client.request("Z0,208,2"), "OK");
+        assert_eq!(client.request("c208"), "S05");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("s"), "S05");
+        assert_eq!(client.request("p11"), "0208");
+        assert_eq!(client.request("c"), "S05");
+        assert_eq!(client.request("p11"), "0208");
+        assert_eq!(client.request("p2"), "01");
+        assert_eq!(client.request("z0,208,2"), "OK");
+
+        assert_eq!(client.request("D"), "OK");
+        assert_eq!(server.join().unwrap(), (0x2A, 0xBE));
+    }
+}
@@ -1,10 +1,11 @@
 //! Raw and high-level instruction abstractions
 
+use std::fmt;
 
 /// A register index/name
 ///
 /// There are 16 data registers, `V0`..`VF`
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Register {
     V0 = 0x0,
     V1 = 0x1,
@@ -25,6 +26,7 @@ pub enum Register {
 }
 
 impl Register {
+    #[allow(clippy::result_unit_err)]
     pub fn new(bits: u8) -> Result<Register, ()> {
         use self::Register::*;
 
@@ -52,6 +54,12 @@ impl Register {
     }
 }
 
+impl fmt::Display for Register {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "V{:X}", *self as u8)
+    }
+}
+
 /// First register in an opcode
 pub type Vx = Register;
 /// Second register in an opcode
@@ -65,7 +73,7 @@ pub type Byte = u8;
 /// A nibble (hex digit)
 ///
 /// Valid values are within `0x0` .. `0xF`.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Nibble {
     pub bits: u8,
 }
@@ -80,7 +88,7 @@ impl Nibble {
 /// Absolute memory address
 ///
 /// Valid addresses are within `0x0` .. `0xFFF`.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Addr {
     pub bits: u16,
 }
@@ -92,11 +100,26 @@ impl Addr {
     }
 }
 
+/// Absolute memory address for the extended XO-CHIP memory
+///
+/// Valid addresses are within `0x0` .. `0xFFFF`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LongAddr {
+    pub bits: u16,
+}
+
+impl LongAddr {
+    /// Creates a new `LongAddr`
+    pub fn new(bits: u16) -> LongAddr {
+        LongAddr { bits }
+    }
+}
+
 
 /// Raw instruction
 ///
 /// Helper around the raw bits, not necessarily a valid instruction.
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct RawInstruction {
     bits: u16
 }
@@ -104,7 +127,7 @@ pub struct RawInstruction {
 impl RawInstruction {
     /// Creates a new raw instruction without any checks of the `bits`
     pub fn new(bits: u16) -> RawInstruction {
-        RawInstruction{ bits: bits }
+        RawInstruction{ bits }
     }
 
     /// The *raw bits*
@@ -142,12 +165,23 @@ impl RawInstruction {
     pub fn k(&self) -> Byte {
         (self.bits & 0x00FF) as u8
     }
+
+    /// The raw bits as 2 bytes in memory order
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [(self.bits >> 8) as u8, self.bits as u8]
+    }
+
+    /// Returns `true` if this is the first half of a 4 byte instruction,
+    /// i.e. XO-CHIP `LoadLongI`
+    pub fn is_long(&self) -> bool {
+        self.bits == 0xF000
+    }
 }
 
 /// High-level instruction
 ///
 /// A valid instruction that can be executed as-is.
-#[derive(Clone, Copy,Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
     /// Jumps to machine subroutine at `Addr`.
     ///
@@ -187,27 +221,35 @@ pub enum Instruction {
     Add(Vx, Vy),            // 8xy4 - ADD Vx, Vy
     /// Subtracts `Vy` from `Vx`, then stores the result in `Vx`.
     ///
-    /// `VF` is set to `1` if `Vx` is larger than `Vy` prior subtraction, `0` otherwise.
+    /// `VF` is set to `1` if `Vx` is larger than or equal to `Vy` prior subtraction,
+    /// i.e. if there is no borrow, `0` otherwise.
     Sub(Vx, Vy),            // 8xy5 - SUB Vx, Vy
     /// Shifts `Vy` right by one bit, then stores the result in `Vx`.
     ///
     /// Stores the least-significant bit prior shift of `Vy` in `VF`.
+    ///
+    /// Note that some interpreters shift `Vx` in place instead, see `Quirks`.
     ShiftRight(Vx, Vy),     // 8xy6 - SHR Vx {, Vy}
     /// Subtracts `Vx` from `Vy`, then stores the result in `Vx`.
     ///
-    /// `VF` is set to `1` if `Vx` is larger than `Vy` prior subtraction, `0` otherwise.
+    /// `VF` is set to `1` if `Vy` is larger than or equal to `Vx` prior subtraction,
+    /// i.e. if there is no borrow, `0` otherwise.
     ///
     /// Note that this is the same as `Sub` with inverted register operands.
     SubInv(Vx, Vy),         // 8xy7 - SUBN Vx, Vy
     /// Shifts `Vy` left by one bit, then stores the result in `Vx`.
     ///
     /// Stores the most-significant bit prior shift of `Vy` in `VF`.
+    ///
+    /// Note that some interpreters shift `Vx` in place instead, see `Quirks`.
     ShiftLeft(Vx, Vy),      // 8xyE - SHL Vx {, Vy}
     /// Skips the next instruction if `Vx` and `Vy` are not equal
     SkipNotEqual(Vx, Vy),   // 9xy0 - SNE Vx, Vy
     /// Sets the `I` register to `Addr`
     LoadI(Addr),            // Annn - LD I, addr
     /// Jumps to `V0 + Addr`
+    ///
+    /// Note that some interpreters use `Vx + Addr` instead, see `Quirks`.
     LongJump(Addr),         // Bnnn - JP V0, addr
     /// Sets `Vx` to a random byte ANDed with `Byte`
     Rand(Vx, Byte),         // Cxkk - RND Vx, byte
@@ -215,7 +257,10 @@ pub enum Instruction {
     ///
     /// Sets `VF` to `1` if any pixels are set to unlit state, `0` otherwise.
     ///
-    /// Note that sprites wrap around onto the opposite side of the screen.
+    /// A `Nibble` of `0` draws a 16x16 sprite with 32 bytes of data instead.
+    ///
+    /// Note that sprites wrap around onto the opposite side of the screen,
+    /// unless the `Quirks` ask for clipping.
     Draw(Vx, Vy, Nibble),   // Dxyn - DRW Vx, Vy, nibble
     /// Skips the next instruction if key `Vx` is pressed
     SkipPressed(Vx),        // Ex9E - SKP Vx
@@ -237,12 +282,64 @@ pub enum Instruction {
     StoreBCD(Vx),           // Fx33 - LD B, Vx
     /// Stores the registers `V0` to `Vx` inclusive at address `I`.
     ///
-    /// Register `I` is set to `I + Vx + 1` afterwards.
+    /// Register `I` is set to `I + Vx + 1` afterwards, unless the `Quirks` say otherwise.
     StoreRegisters(Vx),     // Fx55 - LD [I], Vx
     /// Reads the registers `V0` to `Vx` inclusive from address `I`.
     ///
-    /// Register `I` is set to `I + Vx + 1` afterwards.
+    /// Register `I` is set to `I + Vx + 1` afterwards, unless the `Quirks` say otherwise.
     LoadRegisters(Vx),      // Fx65 - LD Vx, [I]
+
+    // SUPER-CHIP 1.1 instructions
+
+    /// Scrolls the screen down by `Nibble` pixels
+    ScrollDown(Nibble),     // 00Cn - SCD nibble
+    /// Scrolls the screen right by 4 pixels
+    ScrollRight,            // 00FB - SCR
+    /// Scrolls the screen left by 4 pixels
+    ScrollLeft,             // 00FC - SCL
+    /// Stops execution of the program
+    Exit,                   // 00FD - EXIT
+    /// Switches to the low resolution (64x32) screen mode.
+    ///
+    /// Clears the screen.
+    LowRes,                 // 00FE - LOW
+    /// Switches to the high resolution (128x64) screen mode.
+    ///
+    /// Clears the screen.
+    HighRes,                // 00FF - HIGH
+    /// Stores the address of the large (8x10) hexadecimal digit `Vx` in the `I` register
+    LoadLargeHexGlyph(Vx),  // Fx30 - LD HF, Vx
+    /// Stores the registers `V0` to `Vx` inclusive in the RPL user flags
+    StoreFlags(Vx),         // Fx75 - LD R, Vx
+    /// Reads the registers `V0` to `Vx` inclusive from the RPL user flags
+    LoadFlags(Vx),          // Fx85 - LD Vx, R
+
+    // XO-CHIP instructions
+
+    /// Stores the registers `Vx` to `Vy` inclusive at address `I`.
+    ///
+    /// Register `I` is not modified. If `Vx` is larger than `Vy` the registers
+    /// are stored in reverse order.
+    SaveRange(Vx, Vy),      // 5xy2 - LD [I], Vx - Vy
+    /// Reads the registers `Vx` to `Vy` inclusive from address `I`.
+    ///
+    /// Register `I` is not modified. If `Vx` is larger than `Vy` the registers
+    /// are read in reverse order.
+    LoadRange(Vx, Vy),      // 5xy3 - LD Vx - Vy, [I]
+    /// Sets the `I` register to `LongAddr`.
+    ///
+    /// Note that this instruction is 4 bytes long, the address is stored
+    /// in the 2 bytes following the opcode.
+    LoadLongI(LongAddr),    // F000 nnnn - LD I, long addr
+    /// Selects the bitplanes `Nibble` that `Draw`, `Clear` and scrolling operate on
+    SelectPlanes(Nibble),   // Fn01 - PLANE nibble
+    /// Loads 16 bytes from address `I` into the audio pattern buffer
+    LoadAudio,              // F002 - AUDIO
+    /// Sets the playback pitch of the audio pattern buffer to `Vx`
+    SetPitch(Vx),           // Fx3A - PITCH Vx
+    /// Scrolls the screen up by `Nibble` pixels
+    ScrollUp(Nibble),       // 00Dn - SCU nibble
+
     /// Placeholder for an unknown or illegal instruction.
     ///
     /// Note that this is not a real CHIP-8 instruction.
@@ -252,14 +349,24 @@ pub enum Instruction {
 impl Instruction {
     /// Creates a new instruction from raw bits,
     /// or `Instruction::Unknown` if no valid match could be found
+    ///
+    /// Note that 4 byte instructions (see `RawInstruction::is_long`) are
+    /// also `Instruction::Unknown`, use `from_raw_long` for those.
     pub fn from_raw(raw: &RawInstruction) -> Instruction {
         use self::Instruction::*;
 
         match raw.n_high().bits {
             0x0 => {
-                match raw.k() {
-                    0xE0 => Clear,
-                    0xEE => Return,
+                match raw.addr().bits {
+                    0x0E0 => Clear,
+                    0x0EE => Return,
+                    0x0C0 ..= 0x0CF => ScrollDown(raw.n_low()),
+                    0x0D0 ..= 0x0DF => ScrollUp(raw.n_low()),
+                    0x0FB => ScrollRight,
+                    0x0FC => ScrollLeft,
+                    0x0FD => Exit,
+                    0x0FE => LowRes,
+                    0x0FF => HighRes,
                     _ => Sys(raw.addr())
                 }
             },
@@ -267,7 +374,14 @@ impl Instruction {
             0x2 => Call(raw.addr()),
             0x3 => SkipEqualK(raw.x(), raw.k()),
             0x4 => SkipNotEqualK(raw.x(), raw.k()),
-            0x5 => SkipEqual(raw.x(), raw.y()),
+            0x5 => {
+                match raw.n_low().bits {
+                    0x0 => SkipEqual(raw.x(), raw.y()),
+                    0x2 => SaveRange(raw.x(), raw.y()),
+                    0x3 => LoadRange(raw.x(), raw.y()),
+                    _ => Unknown
+                }
+            },
             0x6 => SetK(raw.x(), raw.k()),
             0x7 => AddK(raw.x(), raw.k()),
             0x8 => {
@@ -284,7 +398,12 @@ impl Instruction {
                     _ => Unknown
                 }
             },
-            0x9 => SkipNotEqual(raw.x(), raw.y()),
+            0x9 => {
+                match raw.n_low().bits {
+                    0x0 => SkipNotEqual(raw.x(), raw.y()),
+                    _ => Unknown
+                }
+            },
             0xA => LoadI(raw.addr()),
             0xB => LongJump(raw.addr()),
             0xC => Rand(raw.x(), raw.k()),
@@ -307,12 +426,185 @@ impl Instruction {
                     0x33 => StoreBCD(raw.x()),
                     0x55 => StoreRegisters(raw.x()),
                     0x65 => LoadRegisters(raw.x()),
+                    0x30 => LoadLargeHexGlyph(raw.x()),
+                    0x75 => StoreFlags(raw.x()),
+                    0x85 => LoadFlags(raw.x()),
+                    0x01 => SelectPlanes(Nibble::new(raw.x() as u8)),
+                    0x02 if raw.x() as u8 == 0 => LoadAudio,
+                    0x3A => SetPitch(raw.x()),
                     _ => Unknown,
                 }
             }
             _ => Unknown
         }
     }
+
+    /// Creates a new instruction from the raw bits of a 4 byte instruction,
+    /// where `operand` are the 2 bytes following the opcode `raw`
+    ///
+    /// Falls back to `from_raw` if `raw` is not a 4 byte instruction.
+    pub fn from_raw_long(raw: &RawInstruction, operand: &RawInstruction) -> Instruction {
+        if raw.is_long() {
+            Instruction::LoadLongI(LongAddr::new(operand.bits()))
+        } else {
+            Instruction::from_raw(raw)
+        }
+    }
+
+    /// Size of the encoded instruction in bytes
+    pub fn size(&self) -> usize {
+        match *self {
+            Instruction::LoadLongI(_) => 4,
+            _ => 2,
+        }
+    }
+
+    /// Encodes the instruction into raw bits, the inverse of `from_raw`
+    ///
+    /// For 4 byte instructions these are only the raw bits of the opcode,
+    /// use `to_bytes` to get the complete instruction.
+    ///
+    /// # Panics
+    ///
+    /// Panics for `Instruction::Unknown`, which has no encoding.
+    pub fn encode(&self) -> RawInstruction {
+        use self::Instruction::*;
+
+        let x = |vx: Vx| (vx as u16) << 8;
+        let xy = |vx: Vx, vy: Vy| x(vx) | (vy as u16) << 4;
+
+        RawInstruction::new(match *self {
+            Sys(addr)               => addr.bits,
+            Clear                   => 0x00E0,
+            Return                  => 0x00EE,
+            Jump(addr)              => 0x1000 | addr.bits,
+            Call(addr)              => 0x2000 | addr.bits,
+            SkipEqualK(vx, k)       => 0x3000 | x(vx) | k as u16,
+            SkipNotEqualK(vx, k)    => 0x4000 | x(vx) | k as u16,
+            SkipEqual(vx, vy)       => 0x5000 | xy(vx, vy),
+            SetK(vx, k)             => 0x6000 | x(vx) | k as u16,
+            AddK(vx, k)             => 0x7000 | x(vx) | k as u16,
+            Set(vx, vy)             => 0x8000 | xy(vx, vy),
+            Or(vx, vy)              => 0x8001 | xy(vx, vy),
+            And(vx, vy)             => 0x8002 | xy(vx, vy),
+            XOr(vx, vy)             => 0x8003 | xy(vx, vy),
+            Add(vx, vy)             => 0x8004 | xy(vx, vy),
+            Sub(vx, vy)             => 0x8005 | xy(vx, vy),
+            ShiftRight(vx, vy)      => 0x8006 | xy(vx, vy),
+            SubInv(vx, vy)          => 0x8007 | xy(vx, vy),
+            ShiftLeft(vx, vy)       => 0x800E | xy(vx, vy),
+            SkipNotEqual(vx, vy)    => 0x9000 | xy(vx, vy),
+            LoadI(addr)             => 0xA000 | addr.bits,
+            LongJump(addr)          => 0xB000 | addr.bits,
+            Rand(vx, k)             => 0xC000 | x(vx) | k as u16,
+            Draw(vx, vy, n)         => 0xD000 | xy(vx, vy) | n.bits as u16,
+            SkipPressed(vx)         => 0xE09E | x(vx),
+            SkipNotPressed(vx)      => 0xE0A1 | x(vx),
+            GetTimer(vx)            => 0xF007 | x(vx),
+            WaitKey(vx)             => 0xF00A | x(vx),
+            SetTimer(vx)            => 0xF015 | x(vx),
+            SetSoundTimer(vx)       => 0xF018 | x(vx),
+            AddToI(vx)              => 0xF01E | x(vx),
+            LoadHexGlyph(vx)        => 0xF029 | x(vx),
+            StoreBCD(vx)            => 0xF033 | x(vx),
+            StoreRegisters(vx)      => 0xF055 | x(vx),
+            LoadRegisters(vx)       => 0xF065 | x(vx),
+            ScrollDown(n)           => 0x00C0 | n.bits as u16,
+            ScrollRight             => 0x00FB,
+            ScrollLeft              => 0x00FC,
+            Exit                    => 0x00FD,
+            LowRes                  => 0x00FE,
+            HighRes                 => 0x00FF,
+            LoadLargeHexGlyph(vx)   => 0xF030 | x(vx),
+            StoreFlags(vx)          => 0xF075 | x(vx),
+            LoadFlags(vx)           => 0xF085 | x(vx),
+            SaveRange(vx, vy)       => 0x5002 | xy(vx, vy),
+            LoadRange(vx, vy)       => 0x5003 | xy(vx, vy),
+            LoadLongI(_)            => 0xF000,
+            SelectPlanes(n)         => 0xF001 | (n.bits as u16) << 8,
+            LoadAudio               => 0xF002,
+            SetPitch(vx)            => 0xF03A | x(vx),
+            ScrollUp(n)             => 0x00D0 | n.bits as u16,
+            Unknown                 => panic!("Instruction::Unknown can not be encoded"),
+        })
+    }
+
+    /// Encodes the complete instruction into bytes in memory order,
+    /// 2 or 4 bytes depending on its `size`
+    ///
+    /// # Panics
+    ///
+    /// Panics for `Instruction::Unknown`, which has no encoding.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = self.encode().to_bytes().to_vec();
+        if let Instruction::LoadLongI(addr) = *self {
+            bytes.extend_from_slice(&RawInstruction::new(addr.bits).to_bytes());
+        }
+        bytes
+    }
+}
+
+/// Formats the instruction in the assembly syntax of Cowgod's Chip-8 Technical Reference,
+/// e.g. `LD V1, 0x2A` or `DRW V0, V1, 0x5`
+impl fmt::Display for Instruction {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+
+        match *self {
+            Sys(addr)               => write!(fmt, "SYS 0x{:03X}", addr.bits),
+            Clear                   => write!(fmt, "CLS"),
+            Return                  => write!(fmt, "RET"),
+            Jump(addr)              => write!(fmt, "JP 0x{:03X}", addr.bits),
+            Call(addr)              => write!(fmt, "CALL 0x{:03X}", addr.bits),
+            SkipEqualK(vx, k)       => write!(fmt, "SE {}, 0x{:02X}", vx, k),
+            SkipNotEqualK(vx, k)    => write!(fmt, "SNE {}, 0x{:02X}", vx, k),
+            SkipEqual(vx, vy)       => write!(fmt, "SE {}, {}", vx, vy),
+            SetK(vx, k)             => write!(fmt, "LD {}, 0x{:02X}", vx, k),
+            AddK(vx, k)             => write!(fmt, "ADD {}, 0x{:02X}", vx, k),
+            Set(vx, vy)             => write!(fmt, "LD {}, {}", vx, vy),
+            Or(vx, vy)              => write!(fmt, "OR {}, {}", vx, vy),
+            And(vx, vy)             => write!(fmt, "AND {}, {}", vx, vy),
+            XOr(vx, vy)             => write!(fmt, "XOR {}, {}", vx, vy),
+            Add(vx, vy)             => write!(fmt, "ADD {}, {}", vx, vy),
+            Sub(vx, vy)             => write!(fmt, "SUB {}, {}", vx, vy),
+            ShiftRight(vx, vy)      => write!(fmt, "SHR {}, {}", vx, vy),
+            SubInv(vx, vy)          => write!(fmt, "SUBN {}, {}", vx, vy),
+            ShiftLeft(vx, vy)       => write!(fmt, "SHL {}, {}", vx, vy),
+            SkipNotEqual(vx, vy)    => write!(fmt, "SNE {}, {}", vx, vy),
+            LoadI(addr)             => write!(fmt, "LD I, 0x{:03X}", addr.bits),
+            LongJump(addr)          => write!(fmt, "JP V0, 0x{:03X}", addr.bits),
+            Rand(vx, k)             => write!(fmt, "RND {}, 0x{:02X}", vx, k),
+            Draw(vx, vy, n)         => write!(fmt, "DRW {}, {}, 0x{:X}", vx, vy, n.bits),
+            SkipPressed(vx)         => write!(fmt, "SKP {}", vx),
+            SkipNotPressed(vx)      => write!(fmt, "SKNP {}", vx),
+            GetTimer(vx)            => write!(fmt, "LD {}, DT", vx),
+            WaitKey(vx)             => write!(fmt, "LD {}, K", vx),
+            SetTimer(vx)            => write!(fmt, "LD DT, {}", vx),
+            SetSoundTimer(vx)       => write!(fmt, "LD ST, {}", vx),
+            AddToI(vx)              => write!(fmt, "ADD I, {}", vx),
+            LoadHexGlyph(vx)        => write!(fmt, "LD F, {}", vx),
+            StoreBCD(vx)            => write!(fmt, "LD B, {}", vx),
+            StoreRegisters(vx)      => write!(fmt, "LD [I], {}", vx),
+            LoadRegisters(vx)       => write!(fmt, "LD {}, [I]", vx),
+            ScrollDown(n)           => write!(fmt, "SCD 0x{:X}", n.bits),
+            ScrollRight             => write!(fmt, "SCR"),
+            ScrollLeft              => write!(fmt, "SCL"),
+            Exit                    => write!(fmt, "EXIT"),
+            LowRes                  => write!(fmt, "LOW"),
+            HighRes                 => write!(fmt, "HIGH"),
+            LoadLargeHexGlyph(vx)   => write!(fmt, "LD HF, {}", vx),
+            StoreFlags(vx)          => write!(fmt, "LD R, {}", vx),
+            LoadFlags(vx)           => write!(fmt, "LD {}, R", vx),
+            SaveRange(vx, vy)       => write!(fmt, "LD [I], {} - {}", vx, vy),
+            LoadRange(vx, vy)       => write!(fmt, "LD {} - {}, [I]", vx, vy),
+            LoadLongI(addr)         => write!(fmt, "LD I, LONG 0x{:04X}", addr.bits),
+            SelectPlanes(n)         => write!(fmt, "PLANE 0x{:X}", n.bits),
+            LoadAudio               => write!(fmt, "AUDIO"),
+            SetPitch(vx)            => write!(fmt, "PITCH {}", vx),
+            ScrollUp(n)             => write!(fmt, "SCU 0x{:X}", n.bits),
+            Unknown                 => write!(fmt, "???"),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -328,4 +620,74 @@ mod tests {
     fn known_register() {
         assert_eq!(Register::new(0xF).unwrap() as u8, Register::VF as u8)
     }
+
+    #[test]
+    fn super_chip_instructions() {
+        let decode = |bits| Instruction::from_raw(&RawInstruction::new(bits));
+
+        assert!(matches!(decode(0x00C5), Instruction::ScrollDown(Nibble { bits: 5 })));
+        assert!(matches!(decode(0x00FB), Instruction::ScrollRight));
+        assert!(matches!(decode(0x00FC), Instruction::ScrollLeft));
+        assert!(matches!(decode(0x00FD), Instruction::Exit));
+        assert!(matches!(decode(0x00FE), Instruction::LowRes));
+        assert!(matches!(decode(0x00FF), Instruction::HighRes));
+        assert!(matches!(decode(0xF330), Instruction::LoadLargeHexGlyph(Register::V3)));
+        assert!(matches!(decode(0xF775), Instruction::StoreFlags(Register::V7)));
+        assert!(matches!(decode(0xF785), Instruction::LoadFlags(Register::V7)));
+    }
+
+    #[test]
+    fn xo_chip_instructions() {
+        let decode = |bits| Instruction::from_raw(&RawInstruction::new(bits));
+
+        assert!(matches!(decode(0x5122), Instruction::SaveRange(Register::V1, Register::V2)));
+        assert!(matches!(decode(0x5213), Instruction::LoadRange(Register::V2, Register::V1)));
+        assert!(matches!(decode(0x5121), Instruction::Unknown));
+        assert!(matches!(decode(0xF201), Instruction::SelectPlanes(Nibble { bits: 2 })));
+        assert!(matches!(decode(0xF002), Instruction::LoadAudio));
+        assert!(matches!(decode(0xF43A), Instruction::SetPitch(Register::V4)));
+        assert!(matches!(decode(0x00D7), Instruction::ScrollUp(Nibble { bits: 7 })));
+        assert!(matches!(decode(0xF000), Instruction::Unknown));
+
+        let long = Instruction::from_raw_long(&RawInstruction::new(0xF000), &RawInstruction::new(0xBEEF));
+        assert!(matches!(long, Instruction::LoadLongI(LongAddr { bits: 0xBEEF })));
+        assert_eq!(long.size(), 4);
+    }
+
+    #[test]
+    fn display() {
+        use super::Register::*;
+
+        assert_eq!(Instruction::SetK(V1, 0x2A).to_string(), "LD V1, 0x2A");
+        assert_eq!(Instruction::Draw(V0, VA, Nibble::new(5)).to_string(), "DRW V0, VA, 0x5");
+        assert_eq!(Instruction::LoadRegisters(VF).to_string(), "LD VF, [I]");
+        assert_eq!(Instruction::LoadLongI(LongAddr::new(0xBEEF)).to_string(), "LD I, LONG 0xBEEF");
+    }
+
+    #[test]
+    fn encode_round_trip() {
+        for bits in 0..=0xFFFFu16 {
+            let raw = RawInstruction::new(bits);
+            let ins = Instruction::from_raw(&raw);
+            if ins == Instruction::Unknown {
+                continue;
+            }
+            assert_eq!(ins.encode(), raw, "{:?} encoded to {:04X}", ins, ins.encode().bits());
+            assert_eq!(Instruction::from_raw(&ins.encode()), ins);
+        }
+    }
+
+    #[test]
+    fn encode_long() {
+        let ins = Instruction::LoadLongI(LongAddr::new(0xBEEF));
+        assert_eq!(ins.to_bytes(), vec![0xF0, 0x00, 0xBE, 0xEF]);
+        assert_eq!(Instruction::from_raw_long(&ins.encode(), &RawInstruction::new(0xBEEF)), ins);
+        assert_eq!(Instruction::Clear.to_bytes(), vec![0x00, 0xE0]);
+    }
+
+    #[test]
+    fn sys_is_not_clear() {
+        let ins = Instruction::from_raw(&RawInstruction::new(0x01E0));
+        assert!(matches!(ins, Instruction::Sys(Addr { bits: 0x1E0 })));
+    }
 }
@@ -0,0 +1,162 @@
+//! State of the 16 keys of the keypad
+//!
+//! The `Keypad` of a `Vm` keeps track of which keys are held down and of
+//! the press and release edges while `WaitKey` waits. Depending on the
+//! `WaitKeyMode` of the `Quirks`, `WaitKey` completes as soon as a key is
+//! pressed, or only once a key that was pressed while waiting is released
+//! again, like on the COSMAC VIP. Holding a key down does not complete
+//! `WaitKey` again, only a new press does.
+
+use error::Chip8Error;
+use instructions::Register;
+
+/// Number of keys on the keypad
+pub const NUM_KEYS: usize = 16;
+
+/// When `WaitKey` stops waiting
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WaitKeyMode {
+    /// When a key is pressed
+    Press,
+    /// When a key that was pressed while waiting is released
+    Release,
+}
+
+/// Pressed keys and the pending `WaitKey`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Keypad {
+    /// Bitmask of the pressed keys, bit `n` for key `n`
+    keys: u16,
+    /// Bitmask of the keys pressed since `WaitKey` started waiting
+    pressed_while_waiting: u16,
+    /// Register `WaitKey` stores the key into
+    waiting: Option<Register>,
+}
+
+/// Returns the bit of the key with index `idx`
+fn key_bit(idx: u8) -> Result<u16, Chip8Error> {
+    if (idx as usize) < NUM_KEYS {
+        Ok(1 << idx)
+    } else {
+        Err(Chip8Error::InvalidKey(idx))
+    }
+}
+
+impl Keypad {
+    /// Creates a keypad without any pressed keys
+    pub fn new() -> Keypad {
+        Keypad::default()
+    }
+
+    /// Returns the bitmask of the pressed keys, bit `n` for key `n`
+    pub fn bits(&self) -> u16 {
+        self.keys
+    }
+
+    /// Returns `true` if the key with index `idx` is pressed
+    pub fn pressed(&self, idx: u8) -> Result<bool, Chip8Error> {
+        Ok(self.keys & key_bit(idx)? != 0)
+    }
+
+    /// Returns the register `WaitKey` stores the key into while it waits
+    pub fn waiting(&self) -> Option<Register> {
+        self.waiting
+    }
+
+    /// Starts waiting for a key to store into `vx`
+    pub(crate) fn wait(&mut self, vx: Register) {
+        self.waiting = Some(vx);
+        self.pressed_while_waiting = 0;
+    }
+
+    /// Presses the key with index `idx`
+    ///
+    /// Returns the register and the key if this completes `WaitKey`.
+    pub(crate) fn press(&mut self, idx: u8, mode: WaitKeyMode) -> Result<Option<(Register, u8)>, Chip8Error> {
+        let bit = key_bit(idx)?;
+        let edge = self.keys & bit == 0;
+        self.keys |= bit;
+        if !edge || self.waiting.is_none() {
+            return Ok(None);
+        }
+        match mode {
+            WaitKeyMode::Press => Ok(self.waiting.take().map(|vx| (vx, idx))),
+            WaitKeyMode::Release => {
+                self.pressed_while_waiting |= bit;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Releases the key with index `idx`
+    ///
+    /// Returns the register and the key if this completes `WaitKey`.
+    pub(crate) fn release(&mut self, idx: u8, mode: WaitKeyMode) -> Result<Option<(Register, u8)>, Chip8Error> {
+        let bit = key_bit(idx)?;
+        let edge = self.keys & bit != 0;
+        self.keys &= !bit;
+        if edge && mode == WaitKeyMode::Release && self.pressed_while_waiting & bit != 0 {
+            self.pressed_while_waiting = 0;
+            return Ok(self.waiting.take().map(|vx| (vx, idx)));
+        }
+        Ok(None)
+    }
+
+    /// Returns the bitmask of the keys pressed since `WaitKey` started waiting
+    pub(crate) fn pressed_while_waiting(&self) -> u16 {
+        self.pressed_while_waiting
+    }
+
+    /// Restores the state saved from `bits`, `pressed_while_waiting` and `waiting`
+    pub(crate) fn restore(keys: u16, pressed_while_waiting: u16, waiting: Option<Register>) -> Keypad {
+        Keypad { keys, pressed_while_waiting, waiting }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instructions::Register::*;
+
+    #[test]
+    fn bits() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x1, WaitKeyMode::Press).unwrap();
+        keypad.press(0xF, WaitKeyMode::Press).unwrap();
+        assert_eq!(keypad.bits(), 0x8002);
+        assert!(keypad.pressed(0xF).unwrap());
+        keypad.release(0x1, WaitKeyMode::Press).unwrap();
+        assert_eq!(keypad.bits(), 0x8000);
+
+        assert!(matches!(keypad.pressed(0x10), Err(Chip8Error::InvalidKey(0x10))));
+        assert!(keypad.press(0xFF, WaitKeyMode::Press).is_err());
+        assert!(keypad.release(0x10, WaitKeyMode::Press).is_err());
+        assert_eq!(keypad.bits(), 0x8000);
+    }
+
+    #[test]
+    fn wait_press() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x3, WaitKeyMode::Press).unwrap();
+        keypad.wait(V5);
+        // Holding a key down is no new press
+        assert_eq!(keypad.press(0x3, WaitKeyMode::Press).unwrap(), None);
+        assert_eq!(keypad.press(0x7, WaitKeyMode::Press).unwrap(), Some((V5, 0x7)));
+        assert_eq!(keypad.waiting(), None);
+        assert_eq!(keypad.press(0x8, WaitKeyMode::Press).unwrap(), None);
+    }
+
+    #[test]
+    fn wait_release() {
+        let mut keypad = Keypad::new();
+        keypad.press(0x3, WaitKeyMode::Release).unwrap();
+        keypad.wait(V5);
+        // Keys held before waiting do not count
+        assert_eq!(keypad.release(0x3, WaitKeyMode::Release).unwrap(), None);
+        assert_eq!(keypad.press(0x7, WaitKeyMode::Release).unwrap(), None);
+        assert_eq!(keypad.press(0x9, WaitKeyMode::Release).unwrap(), None);
+        assert_eq!(keypad.waiting(), Some(V5));
+        assert_eq!(keypad.release(0x9, WaitKeyMode::Release).unwrap(), Some((V5, 0x9)));
+        assert_eq!(keypad.release(0x7, WaitKeyMode::Release).unwrap(), None);
+    }
+}
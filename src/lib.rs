@@ -13,6 +13,41 @@
 //! The `vm` module contains the actual virtual machine implementation
 //! (`Vm`).
 //!
+//! The `disasm` module turns programs back into assembly listings, the
+//! `asm` module assembles such listings into programs. The `octo` module
+//! compiles programs written in the Octo assembly language.
+//!
+//! The `keypad` module contains the `Keypad` with the pressed keys and
+//! when `WaitKey` stops waiting for them.
+//!
+//! The `rng` module contains the `Rng` sources of random bytes for the `Vm`.
+//!
+//! The `quirks` module contains the `Quirks` that select how the `Vm`
+//! interprets instructions that differ between CHIP-8 interpreters. The
+//! `timing` module contains the `CycleCosts` of instructions, e.g. to run
+//! programs at the speed of the COSMAC VIP.
+//!
+//! The `breakpoints` module contains the `Breakpoints` and watchpoints
+//! that stop the execution of a `Vm` for debuggers. The `gdb` module lets
+//! debuggers attach to a `Vm` with the GDB remote serial protocol. The
+//! `trace` module records every executed instruction with a `Tracer`.
+//!
+//! The `observer` module contains the `VmObserver` callbacks for drawing,
+//! sound, memory writes and other events of a `Vm`.
+//!
+//! The `export` module writes the screen of a `Vm` as text, PBM or PNG
+//! image, e.g. for golden tests with the `chip8-run` binary, and records
+//! it as animated GIF.
+//!
+//! The `audio` module generates the samples of the sound timer of a `Vm`
+//! and writes them as WAV file.
+//!
+//! The `movie` module records the input of a run with a `Recording` and
+//! plays it back bit-identically with a `Playback`.
+//!
+//! The `rewind` module contains the `Rewind` buffer to restore earlier
+//! states of a `Vm`.
+//!
 //! The `error` module contains the `Chip8Error` implementation of
 //! `std:error::Error` for any kinds of errors that might occur using
 //! the `chip8_vm` crate.
@@ -23,8 +58,23 @@ extern crate rand;
 #[macro_use]
 extern crate log;
 
+pub mod asm;
+pub mod audio;
+pub mod breakpoints;
+pub mod disasm;
 pub mod error;
+pub mod export;
+pub mod gdb;
 pub mod instructions;
+pub mod keypad;
+pub mod movie;
+pub mod observer;
+pub mod octo;
+pub mod quirks;
+pub mod rewind;
+pub mod rng;
+pub mod timing;
+pub mod trace;
 pub mod vm;
 
 pub use instructions::*;
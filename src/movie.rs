@@ -0,0 +1,436 @@
+//! Input movies to reproduce runs exactly
+//!
+//! A `Recording` runs a program frame by frame and logs every key press
+//! and release with the frame it happened before, together with the seed
+//! of the random numbers, a hash of the ROM and a checksum of the save
+//! state after every frame. The resulting `Movie` can be written to a text
+//! file and replayed with a `Playback`, which feeds the same input into a
+//! fresh `Vm` and fails with `Chip8Error::Desync` at the first frame whose
+//! state differs from the recording.
+//!
+//! Movie files look like this, one line per input and checksum:
+//!
+//! ```text
+//! CHIP8-MOVIE 1
+//! rom 1a2b3c4d 246
+//! seed 42
+//! ram 4096
+//! quirks shift_in_place=0 load_store=x_plus_one vf_reset=1 jump_vx=0 clip_sprites=1 display_wait=1 wait_key=release
+//! press 12 5
+//! release 15 5
+//! frame 0 0badf00d
+//! ```
+
+use std::io::{BufRead, BufReader, Read, Write};
+
+use error::Chip8Error;
+use export::crc32;
+use keypad::WaitKeyMode;
+use quirks::{LoadStoreIncrement, Quirks};
+use rng::XorShiftRng;
+use vm::{Vm, PROGRAM_START, XO_CHIP_RAM_SIZE};
+
+/// First line of movie files, with the format version
+const MOVIE_HEADER: &str = "CHIP8-MOVIE 1";
+
+/// Key press or release before a frame
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Input {
+    /// Number of the frame the input happened before, starting at 0
+    pub frame: usize,
+    /// Index of the key
+    pub key: u8,
+    /// `true` for pressing, `false` for releasing the key
+    pub pressed: bool,
+}
+
+/// Recorded run of a program
+#[derive(Clone, Debug, PartialEq)]
+pub struct Movie {
+    /// CRC-32 of the ROM
+    pub rom_hash: u32,
+    /// Size of the ROM in bytes
+    pub rom_len: usize,
+    /// Seed of the `XorShiftRng` for `Rand`
+    pub seed: u64,
+    /// Size of the RAM in bytes
+    pub ram_size: usize,
+    pub quirks: Quirks,
+    /// Key presses and releases in the order they happened
+    pub inputs: Vec<Input>,
+    /// CRC-32 of the save state after each frame
+    pub checksums: Vec<u32>,
+}
+
+impl Movie {
+    /// Number of recorded frames
+    pub fn frames(&self) -> usize {
+        self.checksums.len()
+    }
+
+    /// Creates the `Vm` the recording starts with
+    fn start(&self, rom: &[u8]) -> Result<Vm, Chip8Error> {
+        if crc32(rom) != self.rom_hash || rom.len() != self.rom_len {
+            return Err(Chip8Error::InvalidMovie("ROM does not match the movie"));
+        }
+        let mut vm = Vm::with_ram_size(self.quirks, self.ram_size);
+        vm.set_rng(Box::new(XorShiftRng::new(self.seed)));
+        vm.load_rom(&mut &rom[..])?;
+        Ok(vm)
+    }
+
+    /// Writes the movie as text to `writer`
+    pub fn write(&self, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+        let quirks = &self.quirks;
+        let load_store = match quirks.load_store {
+            LoadStoreIncrement::XPlusOne => "x_plus_one",
+            LoadStoreIncrement::X => "x",
+            LoadStoreIncrement::Unchanged => "unchanged",
+        };
+        writeln!(writer, "{}", MOVIE_HEADER)?;
+        writeln!(writer, "rom {:08x} {}", self.rom_hash, self.rom_len)?;
+        writeln!(writer, "seed {}", self.seed)?;
+        writeln!(writer, "ram {}", self.ram_size)?;
+        let wait_key = match quirks.wait_key {
+            WaitKeyMode::Press => "press",
+            WaitKeyMode::Release => "release",
+        };
+        writeln!(writer, "quirks shift_in_place={} load_store={} vf_reset={} jump_vx={} clip_sprites={} display_wait={} wait_key={}",
+                 quirks.shift_in_place as u8, load_store, quirks.vf_reset as u8, quirks.jump_vx as u8,
+                 quirks.clip_sprites as u8, quirks.display_wait as u8, wait_key)?;
+        for input in self.inputs.iter() {
+            let kind = if input.pressed { "press" } else { "release" };
+            writeln!(writer, "{} {} {:X}", kind, input.frame, input.key)?;
+        }
+        for (frame, checksum) in self.checksums.iter().enumerate() {
+            writeln!(writer, "frame {} {:08x}", frame, checksum)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a movie written by `write`
+    pub fn read(reader: &mut dyn Read) -> Result<Movie, Chip8Error> {
+        let mut lines = BufReader::new(reader).lines();
+        if lines.next().transpose()?.as_deref() != Some(MOVIE_HEADER) {
+            return Err(Chip8Error::InvalidMovie("Not a movie file of a supported version"));
+        }
+
+        let mut movie = Movie {
+            rom_hash: 0,
+            rom_len: 0,
+            seed: 0,
+            ram_size: 0,
+            quirks: Quirks::default(),
+            inputs: Vec::new(),
+            checksums: Vec::new(),
+        };
+        for line in lines {
+            let line = line?;
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields[..] {
+                [] => {},
+                ["rom", hash, len] => {
+                    movie.rom_hash = parse_hex(hash)?;
+                    movie.rom_len = parse(len)?;
+                },
+                ["seed", seed] => movie.seed = parse(seed)?,
+                ["ram", size] => movie.ram_size = parse(size)?,
+                ["quirks", ref quirks @ ..] => movie.quirks = parse_quirks(quirks)?,
+                [kind @ "press", frame, key] | [kind @ "release", frame, key] => {
+                    let key = parse_hex(key)?;
+                    if key > 0xF {
+                        return Err(Chip8Error::InvalidKey(key as u8));
+                    }
+                    movie.inputs.push(Input { frame: parse(frame)?, key: key as u8, pressed: kind == "press" });
+                },
+                ["frame", frame, checksum] => {
+                    if parse::<usize>(frame)? != movie.checksums.len() {
+                        return Err(Chip8Error::InvalidMovie("Frame checksums are not in order"));
+                    }
+                    movie.checksums.push(parse_hex(checksum)?);
+                },
+                _ => return Err(Chip8Error::InvalidMovie("Unknown line in movie")),
+            }
+        }
+        if movie.rom_len == 0 || movie.ram_size == 0 {
+            return Err(Chip8Error::InvalidMovie("Movie does not describe the ROM and RAM"));
+        }
+        if movie.ram_size <= PROGRAM_START || movie.ram_size > XO_CHIP_RAM_SIZE {
+            return Err(Chip8Error::InvalidMovie("RAM size is not supported"));
+        }
+        Ok(movie)
+    }
+}
+
+fn parse<T: ::std::str::FromStr>(field: &str) -> Result<T, Chip8Error> {
+    field.parse().map_err(|_| Chip8Error::InvalidMovie("Invalid number in movie"))
+}
+
+fn parse_hex(field: &str) -> Result<u32, Chip8Error> {
+    u32::from_str_radix(field, 16).map_err(|_| Chip8Error::InvalidMovie("Invalid number in movie"))
+}
+
+fn parse_quirks(fields: &[&str]) -> Result<Quirks, Chip8Error> {
+    let mut quirks = Quirks::default();
+    for field in fields {
+        let (name, value) = match field.find('=') {
+            Some(pos) => (&field[..pos], &field[pos + 1..]),
+            None => return Err(Chip8Error::InvalidMovie("Invalid quirk in movie")),
+        };
+        let flag = value == "1";
+        match name {
+            "shift_in_place" => quirks.shift_in_place = flag,
+            "vf_reset" => quirks.vf_reset = flag,
+            "jump_vx" => quirks.jump_vx = flag,
+            "clip_sprites" => quirks.clip_sprites = flag,
+            "display_wait" => quirks.display_wait = flag,
+            "load_store" => quirks.load_store = match value {
+                "x_plus_one" => LoadStoreIncrement::XPlusOne,
+                "x" => LoadStoreIncrement::X,
+                "unchanged" => LoadStoreIncrement::Unchanged,
+                _ => return Err(Chip8Error::InvalidMovie("Invalid quirk in movie")),
+            },
+            "wait_key" => quirks.wait_key = match value {
+                "press" => WaitKeyMode::Press,
+                "release" => WaitKeyMode::Release,
+                _ => return Err(Chip8Error::InvalidMovie("Invalid quirk in movie")),
+            },
+            _ => return Err(Chip8Error::InvalidMovie("Invalid quirk in movie")),
+        }
+    }
+    Ok(quirks)
+}
+
+/// Checksum of the entire state of `vm`
+fn checksum(vm: &Vm) -> Result<u32, Chip8Error> {
+    let mut state = Vec::new();
+    vm.save_state(&mut state)?;
+    Ok(crc32(&state))
+}
+
+/// Runs a program and records its input into a `Movie`
+///
+/// All input has to go through `set_key` and `unset_key` to be recorded.
+pub struct Recording {
+    vm: Vm,
+    movie: Movie,
+}
+
+impl Recording {
+    /// Starts recording `rom` on a fresh `Vm`, with random numbers from
+    /// `seed`, `ram_size` bytes of RAM and `quirks`
+    pub fn new(rom: &[u8], seed: u64, quirks: Quirks, ram_size: usize) -> Result<Recording, Chip8Error> {
+        let movie = Movie {
+            rom_hash: crc32(rom),
+            rom_len: rom.len(),
+            seed,
+            ram_size,
+            quirks,
+            inputs: Vec::new(),
+            checksums: Vec::new(),
+        };
+        let vm = movie.start(rom)?;
+        Ok(Recording { vm, movie })
+    }
+
+    /// Returns the `Vm` that runs the program
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Presses the key with index `idx` before the next frame
+    pub fn set_key(&mut self, idx: u8) -> Result<(), Chip8Error> {
+        self.vm.set_key(idx)?;
+        self.record(idx, true);
+        Ok(())
+    }
+
+    /// Releases the key with index `idx` before the next frame
+    pub fn unset_key(&mut self, idx: u8) -> Result<(), Chip8Error> {
+        self.vm.unset_key(idx)?;
+        self.record(idx, false);
+        Ok(())
+    }
+
+    fn record(&mut self, key: u8, pressed: bool) {
+        let frame = self.movie.frames();
+        self.movie.inputs.push(Input { frame, key, pressed });
+    }
+
+    /// Runs the program for one frame of 1/60 s
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        self.vm.run_frame()?;
+        let checksum = checksum(&self.vm)?;
+        self.movie.checksums.push(checksum);
+        Ok(())
+    }
+
+    /// Stops recording and returns the movie
+    pub fn finish(self) -> Movie {
+        self.movie
+    }
+}
+
+/// Replays a `Movie` on a fresh `Vm`
+pub struct Playback {
+    vm: Vm,
+    movie: Movie,
+    frame: usize,
+    next_input: usize,
+}
+
+impl Playback {
+    /// Starts replaying `movie` of `rom`, which has to be the recorded ROM
+    pub fn new(movie: Movie, rom: &[u8]) -> Result<Playback, Chip8Error> {
+        let vm = movie.start(rom)?;
+        Ok(Playback { vm, movie, frame: 0, next_input: 0 })
+    }
+
+    /// Returns the `Vm` that runs the program
+    pub fn vm(&self) -> &Vm {
+        &self.vm
+    }
+
+    /// Number of the next frame to replay
+    pub fn frame(&self) -> usize {
+        self.frame
+    }
+
+    /// Returns `true` if all recorded frames were replayed
+    pub fn finished(&self) -> bool {
+        self.frame >= self.movie.frames()
+    }
+
+    /// Replays the input before the next frame and runs the program for one
+    /// frame of 1/60 s
+    ///
+    /// Fails with `Chip8Error::Desync` if the state after the frame differs
+    /// from the recording.
+    pub fn run_frame(&mut self) -> Result<(), Chip8Error> {
+        while let Some(&input) = self.movie.inputs.get(self.next_input) {
+            if input.frame != self.frame {
+                break;
+            }
+            if input.pressed {
+                self.vm.set_key(input.key)?;
+            } else {
+                self.vm.unset_key(input.key)?;
+            }
+            self.next_input += 1;
+        }
+
+        self.vm.run_frame()?;
+        let frame = self.frame;
+        self.frame += 1;
+        if let Some(&expected) = self.movie.checksums.get(frame) {
+            let found = checksum(&self.vm)?;
+            if found != expected {
+                return Err(Chip8Error::Desync { frame, expected, found });
+            }
+        }
+        Ok(())
+    }
+
+    /// Replays all remaining frames
+    pub fn run(&mut self) -> Result<(), Chip8Error> {
+        while !self.finished() {
+            self.run_frame()?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use instructions::Register;
+    use vm::RAM_SIZE;
+
+    fn rom() -> Vec<u8> {
+        assemble("
+            loop:   LD V0, K
+                    RND V1, 0xFF
+                    ADD V2, V1
+                    LD F, V2
+                    DRW V0, V0, 5
+                    JP loop
+        ").unwrap()
+    }
+
+    fn record() -> Movie {
+        let mut recording = Recording::new(&rom(), 7, Quirks::cosmac_vip(), RAM_SIZE).unwrap();
+        for frame in 0..30 {
+            if frame % 5 == 1 {
+                recording.set_key(frame % 16).unwrap();
+            }
+            if frame % 5 == 3 {
+                recording.unset_key((frame - 2) % 16).unwrap();
+            }
+            recording.run_frame().unwrap();
+        }
+        recording.finish()
+    }
+
+    #[test]
+    fn round_trip() {
+        let movie = record();
+        assert_eq!(movie.frames(), 30);
+        assert_eq!(movie.inputs.len(), 12);
+        assert_eq!(movie.inputs[1], Input { frame: 3, key: 1, pressed: false });
+
+        let mut file = Vec::new();
+        movie.write(&mut file).unwrap();
+        assert!(file.starts_with(b"CHIP8-MOVIE 1\n"));
+        assert_eq!(Movie::read(&mut &file[..]).unwrap(), movie);
+    }
+
+    #[test]
+    fn playback() {
+        let movie = record();
+        let mut playback = Playback::new(movie.clone(), &rom()).unwrap();
+        playback.run().unwrap();
+        assert!(playback.finished());
+        assert_eq!(playback.frame(), 30);
+        assert_ne!(playback.vm().register(Register::V2), 0);
+    }
+
+    #[test]
+    fn desync() {
+        let mut movie = record();
+        movie.inputs.remove(2);
+        let mut playback = Playback::new(movie, &rom()).unwrap();
+        match playback.run() {
+            Err(Chip8Error::Desync { frame: 6, .. }) => {},
+            other => panic!("Expected desync at frame 6, got {:?}", other),
+        }
+
+        let mut movie = record();
+        movie.seed += 1;
+        // The state of the random numbers is part of the checksum
+        assert!(matches!(Playback::new(movie, &rom()).unwrap().run(), Err(Chip8Error::Desync { frame: 0, .. })));
+
+        let mut other_rom = rom();
+        other_rom[1] ^= 1;
+        assert!(matches!(Playback::new(record(), &other_rom), Err(Chip8Error::InvalidMovie(_))));
+    }
+
+    #[test]
+    fn invalid_files() {
+        let invalid: &[&str] = &[
+            "",
+            "CHIP8-MOVIE 2\n",
+            "CHIP8-MOVIE 1\nram 4096\n",
+            "CHIP8-MOVIE 1\nrom 0 12\nram 4096\nframe 1 0\n",
+            "CHIP8-MOVIE 1\nrom 0 12\nram 4096\nquirks jump_vx\n",
+            "CHIP8-MOVIE 1\nrom 0 12\nram 4096\nbogus\n",
+            "CHIP8-MOVIE 1\nrom 0 12\nram 16\n",
+            "CHIP8-MOVIE 1\nrom 0 12\nram 65537\n",
+        ];
+        for file in invalid {
+            assert!(Movie::read(&mut file.as_bytes()).is_err(), "{:?}", file);
+        }
+        assert!(matches!(Movie::read(&mut &b"CHIP8-MOVIE 1\nrom 0 12\nram 4096\npress 0 10\n"[..]),
+                         Err(Chip8Error::InvalidKey(0x10))));
+    }
+}
@@ -0,0 +1,208 @@
+//! Callbacks for events of a `Vm`
+//!
+//! Observers added with `Vm::add_observer` are called while instructions
+//! are executed, so frontends and tools can react to drawing, sound and
+//! memory changes as they happen instead of comparing the state of the
+//! `Vm` after every frame. All callbacks do nothing by default.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use instructions::{Instruction, Register};
+
+/// Receiver of the events of a `Vm`
+#[allow(unused_variables)]
+pub trait VmObserver {
+    /// Called before the instruction `ins` at `pc` is executed
+    fn on_instruction(&mut self, pc: usize, ins: &Instruction) {}
+
+    /// Called after a sprite was drawn at `x`, `y`, which are already wrapped
+    /// to the screen
+    ///
+    /// `rows` is the sprite data, one byte per row or two for 16x16 sprites,
+    /// repeated for each selected bitplane. `collided` is `true` if a lit
+    /// pixel was unlit.
+    fn on_draw(&mut self, x: usize, y: usize, rows: &[u8], collided: bool) {}
+
+    /// Called after the selected bitplanes of the screen were cleared, or all
+    /// of them when the resolution was switched
+    fn on_clear(&mut self) {}
+
+    /// Called after the selected bitplanes of the screen were scrolled by
+    /// `dx` columns and `dy` rows
+    fn on_scroll(&mut self, dx: isize, dy: isize) {}
+
+    /// Called when the sound timer is started
+    fn on_sound_start(&mut self) {}
+
+    /// Called when the sound timer ran out or was stopped
+    fn on_sound_stop(&mut self) {}
+
+    /// Called when the program starts waiting for a key to store in `vx`
+    fn on_wait_key(&mut self, vx: Register) {}
+
+    /// Called after the program wrote `data` to the RAM at `addr`
+    fn on_memory_write(&mut self, addr: usize, data: &[u8]) {}
+
+    /// Called after the subroutine at `addr` was called from `pc`
+    fn on_call(&mut self, pc: usize, addr: usize) {}
+
+    /// Called after returning from the subroutine at `pc` to `addr`
+    fn on_return(&mut self, pc: usize, addr: usize) {}
+}
+
+impl<T: VmObserver> VmObserver for Rc<RefCell<T>> {
+    fn on_instruction(&mut self, pc: usize, ins: &Instruction) {
+        self.borrow_mut().on_instruction(pc, ins);
+    }
+
+    fn on_draw(&mut self, x: usize, y: usize, rows: &[u8], collided: bool) {
+        self.borrow_mut().on_draw(x, y, rows, collided);
+    }
+
+    fn on_clear(&mut self) {
+        self.borrow_mut().on_clear();
+    }
+
+    fn on_scroll(&mut self, dx: isize, dy: isize) {
+        self.borrow_mut().on_scroll(dx, dy);
+    }
+
+    fn on_sound_start(&mut self) {
+        self.borrow_mut().on_sound_start();
+    }
+
+    fn on_sound_stop(&mut self) {
+        self.borrow_mut().on_sound_stop();
+    }
+
+    fn on_wait_key(&mut self, vx: Register) {
+        self.borrow_mut().on_wait_key(vx);
+    }
+
+    fn on_memory_write(&mut self, addr: usize, data: &[u8]) {
+        self.borrow_mut().on_memory_write(addr, data);
+    }
+
+    fn on_call(&mut self, pc: usize, addr: usize) {
+        self.borrow_mut().on_call(pc, addr);
+    }
+
+    fn on_return(&mut self, pc: usize, addr: usize) {
+        self.borrow_mut().on_return(pc, addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use vm::Vm;
+
+    /// Records all events except instructions as strings
+    #[derive(Default)]
+    struct Log(Vec<String>);
+
+    impl VmObserver for Log {
+        fn on_draw(&mut self, x: usize, y: usize, rows: &[u8], collided: bool) {
+            self.0.push(format!("draw {} {} {:?} {}", x, y, rows, collided));
+        }
+
+        fn on_clear(&mut self) {
+            self.0.push("clear".to_string());
+        }
+
+        fn on_scroll(&mut self, dx: isize, dy: isize) {
+            self.0.push(format!("scroll {} {}", dx, dy));
+        }
+
+        fn on_sound_start(&mut self) {
+            self.0.push("sound start".to_string());
+        }
+
+        fn on_sound_stop(&mut self) {
+            self.0.push("sound stop".to_string());
+        }
+
+        fn on_wait_key(&mut self, vx: Register) {
+            self.0.push(format!("wait {:?}", vx));
+        }
+
+        fn on_memory_write(&mut self, addr: usize, data: &[u8]) {
+            self.0.push(format!("write {:X} {:?}", addr, data));
+        }
+
+        fn on_call(&mut self, pc: usize, addr: usize) {
+            self.0.push(format!("call {:X} {:X}", pc, addr));
+        }
+
+        fn on_return(&mut self, pc: usize, addr: usize) {
+            self.0.push(format!("return {:X} {:X}", pc, addr));
+        }
+    }
+
+    #[test]
+    fn events() {
+        let rom = assemble("
+                    CALL draw
+                    LD V0, 2
+                    LD ST, V0
+                    LD I, 0x300
+                    LD B, V0
+                    LD V1, K
+            end:    JP end
+            draw:   CLS
+                    LD I, sprite
+                    DRW V0, V0, 1
+                    DRW V0, V0, 1
+                    SCD 2
+                    RET
+            sprite: DB 0x80
+        ").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        let log = Rc::new(RefCell::new(Log::default()));
+        vm.add_observer(Box::new(log.clone()));
+        vm.step(0.1).unwrap();
+        vm.set_key(3).unwrap();
+        vm.step(0.1).unwrap();
+
+        assert_eq!(log.borrow().0, [
+            "call 200 20E",
+            "clear",
+            "draw 0 0 [128] false",
+            "draw 0 0 [128] true",
+            "scroll 0 2",
+            "return 218 202",
+            "sound start",
+            "write 300 [0, 0, 2]",
+            "wait V1",
+            "sound stop",
+        ]);
+    }
+
+    /// Records the executed instructions
+    #[derive(Default)]
+    struct Instructions(Vec<String>);
+
+    impl VmObserver for Instructions {
+        fn on_instruction(&mut self, pc: usize, ins: &Instruction) {
+            self.0.push(format!("{:X} {}", pc, ins));
+        }
+    }
+
+    #[test]
+    fn instructions() {
+        let rom = assemble("
+                    LD V0, 2
+            end:    JP end
+        ").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        let log = Rc::new(RefCell::new(Instructions::default()));
+        vm.add_observer(Box::new(log.clone()));
+        vm.run_cycles(3).unwrap();
+
+        assert_eq!(log.borrow().0, ["200 LD V0, 0x02", "202 JP 0x202", "202 JP 0x202"]);
+    }
+}
@@ -0,0 +1,1091 @@
+//! Compiler for the Octo assembly language
+//!
+//! Most modern CHIP-8 programs are written in Octo, a structured assembly
+//! language. This front end compiles Octo sources into the bytes of a ROM
+//! that can be loaded with `Vm::load_rom`, encoding every instruction with
+//! `Instruction::encode`:
+//!
+//! ```text
+//! :alias x v1
+//! :const SPEED 2
+//!
+//! : main
+//!     i := sprite
+//!     loop
+//!         sprite x v2 5
+//!         x += SPEED
+//!         if x == 60 then x := 0
+//!     again
+//!
+//! : sprite 0xF0 0x90 0x90 0x90 0xF0
+//! ```
+//!
+//! Supported are labels (`: name`, `:next`), `:const`, `:alias`, `:org`,
+//! `:byte`, `:pointer`, `:call`, `:unpack`, `:macro` and `:calc`, all
+//! instructions including the SUPER-CHIP and XO-CHIP ones, the control
+//! flow of `if ... then`, `if ... begin ... else ... end` and
+//! `loop ... while ... again`, and the pseudo comparisons `<`, `>`, `<=`
+//! and `>=` which use `vF` (or the register aliased as `compare-temp`).
+//! Numbers that are not part of an instruction are emitted as data bytes.
+//!
+//! Like in Octo, expressions in `:calc` are evaluated from right to left
+//! without operator precedence, e.g. `2 * 3 + 4` is `14`. If the program
+//! contains a `main` label, it starts with a jump to it.
+//!
+//! Errors are reported as `Chip8Error::Asm` with the line and column of the
+//! offending token.
+
+use std::collections::{HashMap, VecDeque};
+use std::f64;
+
+use error::Chip8Error;
+use instructions::{Instruction, Register, Addr, Nibble, LongAddr};
+use vm::{PROGRAM_START, XO_CHIP_RAM_SIZE};
+
+/// Maximum number of macro expansions, to stop runaway recursion
+const MAX_EXPANSIONS: usize = 100_000;
+
+/// Compiles the Octo program `source` into a ROM loaded at `PROGRAM_START`
+pub fn compile(source: &str) -> Result<Vec<u8>, Chip8Error> {
+    let mut compiler = Compiler::new(source);
+    compiler.program()?;
+    Ok(compiler.rom)
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+fn error<S: Into<String>>(token: &Token, message: S) -> Chip8Error {
+    Chip8Error::Asm { line: token.line, column: token.column, message: message.into() }
+}
+
+/// Splits `source` at whitespace, dropping `#` comments
+fn tokenize(source: &str) -> VecDeque<Token> {
+    let mut tokens = VecDeque::new();
+    for (idx, text) in source.lines().enumerate() {
+        let mut start = None;
+        for (offset, c) in text.char_indices().chain(Some((text.len(), ' '))) {
+            match (start, c.is_whitespace()) {
+                (None, false) if c == '#' => break,
+                (None, false) => start = Some(offset),
+                (Some(begin), true) => {
+                    tokens.push_back(Token {
+                        text: text[begin..offset].to_string(),
+                        line: idx + 1,
+                        column: begin + 1,
+                    });
+                    start = None;
+                },
+                _ => (),
+            }
+        }
+    }
+    tokens
+}
+
+/// Parses a decimal, hexadecimal (`0x`) or binary (`0b`) number
+fn number(text: &str) -> Option<f64> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or_else(|| digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(binary) = digits.strip_prefix("0b").or_else(|| digits.strip_prefix("0B")) {
+        i64::from_str_radix(binary, 2).ok()?
+    } else if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+    Some(if negative { -value } else { value } as f64)
+}
+
+/// Parses a register name like `v0` or `vA`
+fn register_name(text: &str) -> Option<Register> {
+    let mut chars = text.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some('v'), Some(digit), None) | (Some('V'), Some(digit), None) => {
+            digit.to_digit(16).and_then(|bits| Register::new(bits as u8).ok())
+        },
+        _ => None,
+    }
+}
+
+/// Kinds of values in instructions and data
+#[derive(Clone, Copy, Debug)]
+enum Width {
+    Nibble,
+    /// Bytes also accept negative values down to `-128`
+    Byte,
+    Addr,
+    LongAddr,
+}
+
+impl Width {
+    fn check(self, value: f64, token: &Token) -> Result<u16, Chip8Error> {
+        let (min, max, what) = match self {
+            Width::Nibble => (0, 0xF, "nibble"),
+            Width::Byte => (-0x80, 0xFF, "byte"),
+            Width::Addr => (0, 0xFFF, "12 bit address"),
+            Width::LongAddr => (0, 0xFFFF, "16 bit address"),
+        };
+        let value = value.floor() as i64;
+        if value < min || value > max {
+            return Err(error(token, format!("Value {} does not fit in a {}", value, what)));
+        }
+        Ok(value as u16 & max as u16)
+    }
+}
+
+/// Reference to a label that is defined later
+#[derive(Clone, Debug)]
+struct Fixup {
+    /// Address of the bytes to patch
+    addr: usize,
+    kind: FixupKind,
+    /// The label name
+    token: Token,
+}
+
+#[derive(Clone, Copy, Debug)]
+enum FixupKind {
+    /// Low 12 bits of an instruction
+    Addr,
+    /// 16 bit word
+    Long,
+    /// High byte of `:unpack`, with the nibble or `None` for `:unpack long`
+    UnpackHigh(Option<u8>),
+    /// Low byte of `:unpack`
+    UnpackLow,
+}
+
+/// Open control flow structure
+#[derive(Clone, Debug)]
+enum Flow {
+    /// `if ... begin` with the address of the jump to the `else` or `end`
+    Begin(usize, Token),
+    /// `else` with the address of the jump to the `end`
+    Else(usize, Token),
+    /// `loop` with its start and the addresses of the jumps of its `while`s
+    Loop(usize, Vec<usize>, Token),
+}
+
+#[derive(Clone, Debug)]
+struct Macro {
+    args: Vec<String>,
+    body: Vec<Token>,
+}
+
+/// Condition of `if` and `while`
+struct Condition {
+    vx: Register,
+    op: Token,
+    rhs: Option<Token>,
+}
+
+struct Compiler {
+    tokens: VecDeque<Token>,
+    /// Token reported for errors at the end of the source
+    end: Token,
+    /// Program bytes starting at `PROGRAM_START`
+    rom: Vec<u8>,
+    pc: usize,
+    labels: HashMap<String, usize>,
+    constants: HashMap<String, f64>,
+    aliases: HashMap<String, Register>,
+    macros: HashMap<String, Macro>,
+    fixups: Vec<Fixup>,
+    flow: Vec<Flow>,
+    expansions: usize,
+}
+
+impl Compiler {
+    fn new(source: &str) -> Compiler {
+        let line_count = source.lines().count();
+        let end = Token {
+            text: String::new(),
+            line: line_count.max(1),
+            column: source.lines().last().map(|line| line.len() + 1).unwrap_or(1),
+        };
+        Compiler {
+            tokens: tokenize(source),
+            end,
+            rom: Vec::new(),
+            pc: PROGRAM_START,
+            labels: HashMap::new(),
+            constants: HashMap::new(),
+            aliases: HashMap::new(),
+            macros: HashMap::new(),
+            fixups: Vec::new(),
+            flow: Vec::new(),
+            expansions: 0,
+        }
+    }
+
+    /// Compiles all tokens and resolves the forward references
+    fn program(&mut self) -> Result<(), Chip8Error> {
+        let has_main = self.tokens.iter().zip(self.tokens.iter().skip(1))
+            .any(|(colon, name)| colon.text == ":" && name.text == "main");
+        if has_main {
+            let main = Token { text: "main".to_string(), line: 1, column: 1 };
+            self.jump(&main)?;
+        }
+
+        while !self.tokens.is_empty() {
+            self.statement()?;
+        }
+
+        if let Some(flow) = self.flow.pop() {
+            let token = match flow {
+                Flow::Begin(_, token) | Flow::Else(_, token) | Flow::Loop(_, _, token) => token,
+            };
+            return Err(error(&token, format!("`{}` is never closed", token.text)));
+        }
+
+        for fixup in ::std::mem::take(&mut self.fixups) {
+            let value = match self.labels.get(&fixup.token.text) {
+                Some(&addr) => addr as f64,
+                None => return Err(error(&fixup.token, format!("Undefined name `{}`", fixup.token.text))),
+            };
+            self.patch(fixup.addr, fixup.kind, value, &fixup.token)?;
+        }
+        Ok(())
+    }
+
+    fn next(&mut self) -> Result<Token, Chip8Error> {
+        match self.tokens.pop_front() {
+            Some(token) => Ok(token),
+            None => Err(error(&self.end, "Unexpected end of input")),
+        }
+    }
+
+    fn peek_is(&self, text: &str) -> bool {
+        self.tokens.front().map(|token| token.text == text).unwrap_or(false)
+    }
+
+    fn expect(&mut self, text: &str) -> Result<Token, Chip8Error> {
+        let token = self.next()?;
+        if token.text != text {
+            return Err(error(&token, format!("Expected `{}`, found `{}`", text, token.text)));
+        }
+        Ok(token)
+    }
+
+    /// Reads a name for a new label, constant, alias or macro
+    fn name(&mut self) -> Result<Token, Chip8Error> {
+        let token = self.next()?;
+        if number(&token.text).is_some() || self.is_register(&token) || token.text.starts_with(':') {
+            return Err(error(&token, format!("`{}` can not be used as a name", token.text)));
+        }
+        Ok(token)
+    }
+
+    fn is_register(&self, token: &Token) -> bool {
+        register_name(&token.text).is_some() || self.aliases.contains_key(&token.text)
+    }
+
+    fn register(&mut self) -> Result<Register, Chip8Error> {
+        let token = self.next()?;
+        match register_name(&token.text).or_else(|| self.aliases.get(&token.text).cloned()) {
+            Some(vx) => Ok(vx),
+            None => Err(error(&token, format!("Expected a register, found `{}`", token.text))),
+        }
+    }
+
+    /// Value of a number, constant or already defined label
+    fn lookup(&self, token: &Token) -> Option<f64> {
+        number(&token.text)
+            .or_else(|| self.constants.get(&token.text).cloned())
+            .or_else(|| self.labels.get(&token.text).map(|&addr| addr as f64))
+    }
+
+    fn value(&mut self, width: Width) -> Result<u16, Chip8Error> {
+        let token = self.next()?;
+        match self.lookup(&token) {
+            Some(value) => width.check(value, &token),
+            None => Err(error(&token, format!("Undefined name `{}`", token.text))),
+        }
+    }
+
+    /// Value of an address operand, which may be a label that is defined later
+    ///
+    /// Forward references are patched at `addr` once all labels are known.
+    fn reference(&mut self, addr: usize, kind: FixupKind) -> Result<u16, Chip8Error> {
+        let token = self.next()?;
+        match self.lookup(&token) {
+            Some(value) => {
+                let width = match kind {
+                    FixupKind::Addr => Width::Addr,
+                    _ => Width::LongAddr,
+                };
+                width.check(value, &token)
+            },
+            None if number(&token.text).is_none() && !self.is_register(&token) => {
+                self.fixups.push(Fixup { addr, kind, token });
+                Ok(0)
+            },
+            None => Err(error(&token, format!("Expected an address, found `{}`", token.text))),
+        }
+    }
+
+    fn emit(&mut self, byte: u8) -> Result<(), Chip8Error> {
+        if self.pc >= XO_CHIP_RAM_SIZE {
+            let token = self.tokens.front().cloned().unwrap_or_else(|| self.end.clone());
+            return Err(error(&token, "Program does not fit in memory"));
+        }
+        let offset = self.pc - PROGRAM_START;
+        if offset >= self.rom.len() {
+            self.rom.resize(offset + 1, 0);
+        }
+        self.rom[offset] = byte;
+        self.pc += 1;
+        Ok(())
+    }
+
+    fn instruction(&mut self, ins: Instruction) -> Result<(), Chip8Error> {
+        for byte in ins.to_bytes() {
+            self.emit(byte)?;
+        }
+        Ok(())
+    }
+
+    /// Emits a jump to the address operand `token`
+    fn jump(&mut self, token: &Token) -> Result<(), Chip8Error> {
+        self.tokens.push_front(token.clone());
+        let addr = self.reference(self.pc, FixupKind::Addr)?;
+        self.instruction(Instruction::Jump(Addr::new(addr)))
+    }
+
+    /// Emits a jump that is patched later, returning its address
+    fn jump_placeholder(&mut self) -> Result<usize, Chip8Error> {
+        let addr = self.pc;
+        self.instruction(Instruction::Jump(Addr::new(0)))?;
+        Ok(addr)
+    }
+
+    fn patch(&mut self, addr: usize, kind: FixupKind, value: f64, token: &Token) -> Result<(), Chip8Error> {
+        let offset = addr - PROGRAM_START;
+        match kind {
+            FixupKind::Addr => {
+                let value = Width::Addr.check(value, token)?;
+                self.rom[offset] = (self.rom[offset] & 0xF0) | (value >> 8) as u8;
+                self.rom[offset + 1] = value as u8;
+            },
+            FixupKind::Long => {
+                let value = Width::LongAddr.check(value, token)?;
+                self.rom[offset] = (value >> 8) as u8;
+                self.rom[offset + 1] = value as u8;
+            },
+            FixupKind::UnpackHigh(nibble) => {
+                let value = Width::LongAddr.check(value, token)?;
+                self.rom[offset] = match nibble {
+                    Some(nibble) => (nibble << 4) | ((value >> 8) & 0xF) as u8,
+                    None => (value >> 8) as u8,
+                };
+            },
+            FixupKind::UnpackLow => {
+                let value = Width::LongAddr.check(value, token)?;
+                self.rom[offset] = value as u8;
+            },
+        }
+        Ok(())
+    }
+
+    /// Points the jump at `addr` to the current address
+    fn patch_jump(&mut self, addr: usize, token: &Token) -> Result<(), Chip8Error> {
+        let pc = self.pc as f64;
+        self.patch(addr, FixupKind::Addr, pc, token)
+    }
+
+    fn statement(&mut self) -> Result<(), Chip8Error> {
+        use instructions::Instruction::*;
+
+        let token = self.next()?;
+        match &token.text[..] {
+            ":" => {
+                let name = self.name()?;
+                self.define_label(name, self.pc)?;
+            },
+            ":next" => {
+                let name = self.name()?;
+                self.define_label(name, self.pc + 1)?;
+            },
+            ":const" => {
+                let name = self.name()?;
+                let value = self.next()?;
+                match self.lookup(&value) {
+                    Some(value) => self.define_constant(name, value)?,
+                    None => return Err(error(&value, format!("Undefined name `{}`", value.text))),
+                }
+            },
+            ":calc" => {
+                let name = self.name()?;
+                let value = self.calc_block()?;
+                self.constants.insert(name.text, value);
+            },
+            ":alias" => {
+                let name = self.name()?;
+                let vx = self.register()?;
+                self.aliases.insert(name.text, vx);
+            },
+            ":org" => {
+                let addr = self.value(Width::LongAddr)? as usize;
+                if addr < PROGRAM_START {
+                    return Err(error(&token, "`:org` must not be below the program start"));
+                }
+                self.pc = addr;
+            },
+            ":byte" => {
+                let byte = if self.peek_is("{") {
+                    let block = self.tokens.front().cloned().unwrap();
+                    let value = self.calc_block()?;
+                    Width::Byte.check(value, &block)?
+                } else {
+                    self.value(Width::Byte)?
+                };
+                self.emit(byte as u8)?;
+            },
+            ":pointer" => {
+                let addr = self.reference(self.pc, FixupKind::Long)?;
+                self.emit((addr >> 8) as u8)?;
+                self.emit(addr as u8)?;
+            },
+            ":call" => {
+                let addr = self.reference(self.pc, FixupKind::Addr)?;
+                self.instruction(Call(Addr::new(addr)))?;
+            },
+            ":unpack" => {
+                let nibble = if self.peek_is("long") {
+                    self.next()?;
+                    None
+                } else {
+                    Some(self.value(Width::Nibble)? as u8)
+                };
+                let pending = self.fixups.len();
+                let addr = self.reference(self.pc + 1, FixupKind::UnpackHigh(nibble))?;
+                let high_byte = match nibble {
+                    Some(nibble) => (nibble << 4) | ((addr >> 8) & 0xF) as u8,
+                    None => (addr >> 8) as u8,
+                };
+                self.instruction(SetK(Register::V0, high_byte))?;
+                if self.fixups.len() > pending {
+                    // Forward reference, the low byte needs patching as well
+                    let token = self.fixups[pending].token.clone();
+                    self.fixups.push(Fixup { addr: self.pc + 1, kind: FixupKind::UnpackLow, token });
+                }
+                self.instruction(SetK(Register::V1, addr as u8))?;
+            },
+            ":macro" => {
+                let name = self.name()?;
+                let mut args = Vec::new();
+                while !self.peek_is("{") {
+                    args.push(self.name()?.text);
+                }
+                let body = self.block()?;
+                self.macros.insert(name.text, Macro { args, body });
+            },
+            ":breakpoint" => {
+                // Breakpoints are set on the `Vm` instead
+                self.next()?;
+            },
+            ":monitor" => {
+                self.next()?;
+                self.next()?;
+            },
+
+            "return" | ";" => self.instruction(Return)?,
+            "clear" => self.instruction(Clear)?,
+            "exit" => self.instruction(Exit)?,
+            "lores" => self.instruction(LowRes)?,
+            "hires" => self.instruction(HighRes)?,
+            "scroll-left" => self.instruction(ScrollLeft)?,
+            "scroll-right" => self.instruction(ScrollRight)?,
+            "audio" => self.instruction(LoadAudio)?,
+            "scroll-down" => {
+                let n = self.value(Width::Nibble)?;
+                self.instruction(ScrollDown(Nibble::new(n as u8)))?;
+            },
+            "scroll-up" => {
+                let n = self.value(Width::Nibble)?;
+                self.instruction(ScrollUp(Nibble::new(n as u8)))?;
+            },
+            "plane" => {
+                let n = self.value(Width::Nibble)?;
+                self.instruction(SelectPlanes(Nibble::new(n as u8)))?;
+            },
+            "bcd" => {
+                let vx = self.register()?;
+                self.instruction(StoreBCD(vx))?;
+            },
+            "saveflags" => {
+                let vx = self.register()?;
+                self.instruction(StoreFlags(vx))?;
+            },
+            "loadflags" => {
+                let vx = self.register()?;
+                self.instruction(LoadFlags(vx))?;
+            },
+            "save" | "load" => {
+                let vx = self.register()?;
+                let ins = if self.peek_is("-") {
+                    self.next()?;
+                    let vy = self.register()?;
+                    if token.text == "save" { SaveRange(vx, vy) } else { LoadRange(vx, vy) }
+                } else if token.text == "save" {
+                    StoreRegisters(vx)
+                } else {
+                    LoadRegisters(vx)
+                };
+                self.instruction(ins)?;
+            },
+            "sprite" => {
+                let vx = self.register()?;
+                let vy = self.register()?;
+                let n = self.value(Width::Nibble)?;
+                self.instruction(Draw(vx, vy, Nibble::new(n as u8)))?;
+            },
+            "jump" => {
+                let addr = self.reference(self.pc, FixupKind::Addr)?;
+                self.instruction(Jump(Addr::new(addr)))?;
+            },
+            "jump0" => {
+                let addr = self.reference(self.pc, FixupKind::Addr)?;
+                self.instruction(LongJump(Addr::new(addr)))?;
+            },
+            "delay" | "buzzer" | "pitch" => {
+                self.expect(":=")?;
+                let vx = self.register()?;
+                self.instruction(match &token.text[..] {
+                    "delay" => SetTimer(vx),
+                    "buzzer" => SetSoundTimer(vx),
+                    _ => SetPitch(vx),
+                })?;
+            },
+            "i" => self.index()?,
+
+            "if" => {
+                let condition = self.condition()?;
+                let body = self.next()?;
+                match &body.text[..] {
+                    "then" => self.conditional(&condition, false)?,
+                    "begin" => {
+                        self.conditional(&condition, true)?;
+                        let jump = self.jump_placeholder()?;
+                        self.flow.push(Flow::Begin(jump, token));
+                    },
+                    _ => return Err(error(&body, format!("Expected `then` or `begin`, found `{}`", body.text))),
+                }
+            },
+            "else" => {
+                match self.flow.pop() {
+                    Some(Flow::Begin(jump, _)) => {
+                        let end = self.jump_placeholder()?;
+                        self.patch_jump(jump, &token)?;
+                        self.flow.push(Flow::Else(end, token));
+                    },
+                    _ => return Err(error(&token, "`else` without `if ... begin`")),
+                }
+            },
+            "end" => {
+                match self.flow.pop() {
+                    Some(Flow::Begin(jump, _)) | Some(Flow::Else(jump, _)) => self.patch_jump(jump, &token)?,
+                    _ => return Err(error(&token, "`end` without `if ... begin`")),
+                }
+            },
+            "loop" => self.flow.push(Flow::Loop(self.pc, Vec::new(), token)),
+            "while" => {
+                let condition = self.condition()?;
+                self.conditional(&condition, true)?;
+                let jump = self.jump_placeholder()?;
+                let innermost = self.flow.iter_mut().rev().filter_map(|flow| match *flow {
+                    Flow::Loop(_, ref mut whiles, _) => Some(whiles),
+                    _ => None,
+                }).next();
+                match innermost {
+                    Some(whiles) => whiles.push(jump),
+                    None => return Err(error(&token, "`while` outside of `loop`")),
+                }
+            },
+            "again" => {
+                match self.flow.pop() {
+                    Some(Flow::Loop(start, whiles, _)) => {
+                        self.instruction(Jump(Addr::new(start as u16)))?;
+                        for jump in whiles {
+                            self.patch_jump(jump, &token)?;
+                        }
+                    },
+                    _ => return Err(error(&token, "`again` without `loop`")),
+                }
+            },
+
+            _ if self.is_register(&token) => {
+                self.tokens.push_front(token);
+                self.assignment()?;
+            },
+            _ if number(&token.text).is_some() => {
+                let byte = Width::Byte.check(number(&token.text).unwrap(), &token)?;
+                self.emit(byte as u8)?;
+            },
+            _ if self.macros.contains_key(&token.text) => self.expand(&token)?,
+            _ if self.constants.contains_key(&token.text) => {
+                let byte = Width::Byte.check(self.constants[&token.text], &token)?;
+                self.emit(byte as u8)?;
+            },
+            _ if token.text.starts_with(':') => {
+                return Err(error(&token, format!("Unknown directive `{}`", token.text)));
+            },
+            _ => {
+                // A bare name calls the subroutine at that label
+                self.tokens.push_front(token);
+                let addr = self.reference(self.pc, FixupKind::Addr)?;
+                self.instruction(Call(Addr::new(addr)))?;
+            },
+        }
+        Ok(())
+    }
+
+    fn define_label(&mut self, name: Token, addr: usize) -> Result<(), Chip8Error> {
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(error(&name, format!("`{}` is already defined", name.text)));
+        }
+        self.labels.insert(name.text, addr);
+        Ok(())
+    }
+
+    fn define_constant(&mut self, name: Token, value: f64) -> Result<(), Chip8Error> {
+        if self.labels.contains_key(&name.text) || self.constants.contains_key(&name.text) {
+            return Err(error(&name, format!("`{}` is already defined", name.text)));
+        }
+        self.constants.insert(name.text, value);
+        Ok(())
+    }
+
+    /// `i := ...` and `i += vx`
+    fn index(&mut self) -> Result<(), Chip8Error> {
+        use instructions::Instruction::*;
+
+        let op = self.next()?;
+        match &op.text[..] {
+            "+=" => {
+                let vx = self.register()?;
+                self.instruction(AddToI(vx))
+            },
+            ":=" if self.peek_is("hex") || self.peek_is("bighex") => {
+                let large = self.next()?.text == "bighex";
+                let vx = self.register()?;
+                self.instruction(if large { LoadLargeHexGlyph(vx) } else { LoadHexGlyph(vx) })
+            },
+            ":=" if self.peek_is("long") => {
+                self.next()?;
+                let addr = self.reference(self.pc + 2, FixupKind::Long)?;
+                self.instruction(LoadLongI(LongAddr::new(addr)))
+            },
+            ":=" => {
+                let addr = self.reference(self.pc, FixupKind::Addr)?;
+                self.instruction(LoadI(Addr::new(addr)))
+            },
+            _ => Err(error(&op, format!("Expected `:=` or `+=`, found `{}`", op.text))),
+        }
+    }
+
+    /// `vx := ...`, `vx += ...` and the other register operations
+    fn assignment(&mut self) -> Result<(), Chip8Error> {
+        use instructions::Instruction::*;
+
+        let vx = self.register()?;
+        let op = self.next()?;
+        let rhs_is_register = self.tokens.front().map(|token| self.is_register(token)).unwrap_or(false);
+
+        let ins = match &op.text[..] {
+            ":=" if self.peek_is("random") => {
+                self.next()?;
+                Rand(vx, self.value(Width::Byte)? as u8)
+            },
+            ":=" if self.peek_is("key") => {
+                self.next()?;
+                WaitKey(vx)
+            },
+            ":=" if self.peek_is("delay") => {
+                self.next()?;
+                GetTimer(vx)
+            },
+            ":=" if rhs_is_register => Set(vx, self.register()?),
+            ":=" => SetK(vx, self.value(Width::Byte)? as u8),
+            "+=" if rhs_is_register => Add(vx, self.register()?),
+            "+=" => AddK(vx, self.value(Width::Byte)? as u8),
+            "-=" if rhs_is_register => Sub(vx, self.register()?),
+            "-=" => AddK(vx, (self.value(Width::Byte)? as u8).wrapping_neg()),
+            "=-" => SubInv(vx, self.register()?),
+            "|=" => Or(vx, self.register()?),
+            "&=" => And(vx, self.register()?),
+            "^=" => XOr(vx, self.register()?),
+            ">>=" => ShiftRight(vx, self.register()?),
+            "<<=" => ShiftLeft(vx, self.register()?),
+            _ => return Err(error(&op, format!("Unknown register operation `{}`", op.text))),
+        };
+        self.instruction(ins)
+    }
+
+    fn condition(&mut self) -> Result<Condition, Chip8Error> {
+        let vx = self.register()?;
+        let op = self.next()?;
+        let rhs = match &op.text[..] {
+            "key" | "-key" => None,
+            "==" | "!=" | "<" | ">" | "<=" | ">=" => Some(self.next()?),
+            _ => return Err(error(&op, format!("Unknown comparison `{}`", op.text))),
+        };
+        Ok(Condition { vx, op, rhs })
+    }
+
+    /// Emits the instructions that skip the next one unless `condition` holds,
+    /// or if it holds when `negated`
+    fn conditional(&mut self, condition: &Condition, negated: bool) -> Result<(), Chip8Error> {
+        use instructions::Instruction::*;
+
+        let mut op = &condition.op.text[..];
+        if negated {
+            op = match op {
+                "==" => "!=",
+                "!=" => "==",
+                "key" => "-key",
+                "-key" => "key",
+                "<" => ">=",
+                ">" => "<=",
+                ">=" => "<",
+                _ => ">",
+            };
+        }
+        let vx = condition.vx;
+        if let Some(ref rhs) = condition.rhs {
+            self.tokens.push_front(rhs.clone());
+        }
+        let rhs_is_register = condition.rhs.as_ref().map(|rhs| self.is_register(rhs)).unwrap_or(false);
+
+        match op {
+            "key" => self.instruction(SkipNotPressed(vx)),
+            "-key" => self.instruction(SkipPressed(vx)),
+            "==" if rhs_is_register => {
+                let vy = self.register()?;
+                self.instruction(SkipNotEqual(vx, vy))
+            },
+            "==" => {
+                let k = self.value(Width::Byte)? as u8;
+                self.instruction(SkipNotEqualK(vx, k))
+            },
+            "!=" if rhs_is_register => {
+                let vy = self.register()?;
+                self.instruction(SkipEqual(vx, vy))
+            },
+            "!=" => {
+                let k = self.value(Width::Byte)? as u8;
+                self.instruction(SkipEqualK(vx, k))
+            },
+            _ => {
+                // Pseudo comparisons subtract into the temporary register and test the flag
+                let temp = self.aliases.get("compare-temp").cloned().unwrap_or(Register::VF);
+                if rhs_is_register {
+                    let vy = self.register()?;
+                    self.instruction(Set(temp, vy))?;
+                } else {
+                    let k = self.value(Width::Byte)? as u8;
+                    self.instruction(SetK(temp, k))?;
+                }
+                self.instruction(match op {
+                    ">" | "<=" => Sub(temp, vx),
+                    _ => SubInv(temp, vx),
+                })?;
+                self.instruction(match op {
+                    ">" | "<" => SkipEqualK(Register::VF, 1),
+                    _ => SkipNotEqualK(Register::VF, 1),
+                })
+            },
+        }
+    }
+
+    /// Reads the tokens of a `{ ... }` block, without the braces
+    fn block(&mut self) -> Result<Vec<Token>, Chip8Error> {
+        let open = self.expect("{")?;
+        let mut body = Vec::new();
+        let mut depth = 0;
+        loop {
+            let token = match self.tokens.pop_front() {
+                Some(token) => token,
+                None => return Err(error(&open, "`{` is never closed")),
+            };
+            match &token.text[..] {
+                "{" => depth += 1,
+                "}" if depth == 0 => return Ok(body),
+                "}" => depth -= 1,
+                _ => (),
+            }
+            body.push(token);
+        }
+    }
+
+    fn expand(&mut self, name: &Token) -> Result<(), Chip8Error> {
+        self.expansions += 1;
+        if self.expansions > MAX_EXPANSIONS {
+            return Err(error(name, "Too many macro expansions"));
+        }
+        let mac = self.macros[&name.text].clone();
+        let mut args = HashMap::new();
+        for arg in mac.args.iter() {
+            args.insert(arg, self.next()?);
+        }
+        for token in mac.body.iter().rev() {
+            let token = match args.get(&token.text) {
+                Some(arg) => Token { text: arg.text.clone(), ..token.clone() },
+                None => token.clone(),
+            };
+            self.tokens.push_front(token);
+        }
+        Ok(())
+    }
+
+    /// Evaluates a `{ ... }` block of `:calc`
+    fn calc_block(&mut self) -> Result<f64, Chip8Error> {
+        let open = self.tokens.front().cloned().unwrap_or_else(|| self.end.clone());
+        let tokens = self.block()?;
+        if tokens.is_empty() {
+            return Err(error(&open, "Empty expression"));
+        }
+        let mut pos = 0;
+        let value = self.calc(&tokens, &mut pos)?;
+        if pos < tokens.len() {
+            return Err(error(&tokens[pos], format!("Unexpected `{}`", tokens[pos].text)));
+        }
+        Ok(value)
+    }
+
+    /// Evaluates an expression from right to left
+    fn calc(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, Chip8Error> {
+        let lhs = self.calc_term(tokens, pos)?;
+        let op = match tokens.get(*pos) {
+            Some(token) if token.text != ")" => token,
+            _ => return Ok(lhs),
+        };
+        *pos += 1;
+        let rhs = self.calc(tokens, pos)?;
+        let int = |value: f64| value as i64;
+        let bool = |cond: bool| if cond { 1.0 } else { 0.0 };
+        Ok(match &op.text[..] {
+            "+" => lhs + rhs,
+            "-" => lhs - rhs,
+            "*" => lhs * rhs,
+            "/" => lhs / rhs,
+            "%" => lhs % rhs,
+            "pow" => lhs.powf(rhs),
+            "min" => lhs.min(rhs),
+            "max" => lhs.max(rhs),
+            "&" => (int(lhs) & int(rhs)) as f64,
+            "|" => (int(lhs) | int(rhs)) as f64,
+            "^" => (int(lhs) ^ int(rhs)) as f64,
+            "<<" => int(lhs).checked_shl(int(rhs) as u32).unwrap_or(0) as f64,
+            ">>" => int(lhs).checked_shr(int(rhs) as u32).unwrap_or(0) as f64,
+            "<" => bool(lhs < rhs),
+            ">" => bool(lhs > rhs),
+            "<=" => bool(lhs <= rhs),
+            ">=" => bool(lhs >= rhs),
+            "==" => bool(lhs == rhs),
+            "!=" => bool(lhs != rhs),
+            _ => return Err(error(op, format!("Unknown operator `{}`", op.text))),
+        })
+    }
+
+    fn calc_term(&self, tokens: &[Token], pos: &mut usize) -> Result<f64, Chip8Error> {
+        let token = match tokens.get(*pos) {
+            Some(token) => token,
+            None => return Err(error(tokens.last().unwrap(), "Unexpected end of expression")),
+        };
+        *pos += 1;
+
+        let unary: Option<fn(f64) -> f64> = match &token.text[..] {
+            "-" => Some(|value: f64| -value),
+            "~" => Some(|value: f64| !(value as i64) as f64),
+            "!" => Some(|value: f64| if value == 0.0 { 1.0 } else { 0.0 }),
+            "sin" => Some(f64::sin),
+            "cos" => Some(f64::cos),
+            "tan" => Some(f64::tan),
+            "exp" => Some(f64::exp),
+            "log" => Some(f64::ln),
+            "abs" => Some(f64::abs),
+            "sqrt" => Some(f64::sqrt),
+            "sign" => Some(f64::signum),
+            "ceil" => Some(f64::ceil),
+            "floor" => Some(f64::floor),
+            _ => None,
+        };
+        if let Some(function) = unary {
+            return Ok(function(self.calc_term(tokens, pos)?));
+        }
+
+        match &token.text[..] {
+            "(" => {
+                let value = self.calc(tokens, pos)?;
+                match tokens.get(*pos) {
+                    Some(close) if close.text == ")" => {
+                        *pos += 1;
+                        Ok(value)
+                    },
+                    _ => Err(error(token, "`(` is never closed")),
+                }
+            },
+            "@" => {
+                let addr = self.calc_term(tokens, pos)? as usize;
+                Ok(addr.checked_sub(PROGRAM_START)
+                    .and_then(|offset| self.rom.get(offset))
+                    .cloned()
+                    .unwrap_or(0) as f64)
+            },
+            "HERE" => Ok(self.pc as f64),
+            "PI" => Ok(f64::consts::PI),
+            "E" => Ok(f64::consts::E),
+            _ => match self.lookup(token) {
+                Some(value) => Ok(value),
+                None => Err(error(token, format!("Undefined name `{}`", token.text))),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use disasm::Octo;
+    use instructions::RawInstruction;
+    use vm::Vm;
+
+    fn octo_error(source: &str) -> (usize, usize, String) {
+        match compile(source) {
+            Err(Chip8Error::Asm { line, column, message }) => (line, column, message),
+            other => panic!("expected an assembly error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn disassembly_round_trip() {
+        for bits in 0..=0xFFFFu16 {
+            let ins = Instruction::from_raw(&RawInstruction::new(bits));
+            if ins == Instruction::Unknown {
+                continue;
+            }
+            let source = Octo(&ins).to_string();
+            assert_eq!(compile(&source).unwrap(), ins.to_bytes(), "{}", source);
+        }
+        let long = Instruction::LoadLongI(LongAddr::new(0xABCD));
+        assert_eq!(compile(&Octo(&long).to_string()).unwrap(), long.to_bytes());
+    }
+
+    #[test]
+    fn labels_and_data() {
+        let source = "
+            :alias x v1
+            :const SPEED 2
+            : main
+                i := sprite     # forward reference
+                x += SPEED
+                v2 -= 1
+                :call sub
+                sub
+            : sub
+                return
+            : sprite 0xF0 0x90 -1
+            :byte { 3 * 2 + 1 }
+            :pointer main
+            :unpack 0xA sprite
+        ";
+        assert_eq!(compile(source).unwrap(), vec![
+            0x12, 0x02,
+            0xA2, 0x0E,
+            0x71, 0x02,
+            0x72, 0xFF,
+            0x22, 0x0C,
+            0x22, 0x0C,
+            0x00, 0xEE,
+            0xF0, 0x90, 0xFF,
+            0x09,
+            0x02, 0x02,
+            0x60, 0xA2,
+            0x61, 0x0E,
+        ]);
+    }
+
+    #[test]
+    fn control_flow() {
+        let source = "
+            loop
+                if v0 == 3 then v1 := 1
+                if v0 key begin
+                    v2 := 2
+                else
+                    v2 := 3
+                end
+                while v0 != v1
+                v0 += 1
+            again
+        ";
+        assert_eq!(compile(source).unwrap(), vec![
+            0x40, 0x03, 0x61, 0x01,     // if then
+            0xE0, 0x9E, 0x12, 0x0C,     // if begin
+            0x62, 0x02, 0x12, 0x0E,     // else
+            0x62, 0x03,                 // end
+            0x90, 0x10, 0x12, 0x16,     // while
+            0x70, 0x01, 0x12, 0x00,     // again
+        ]);
+    }
+
+    #[test]
+    fn comparisons() {
+        assert_eq!(compile("if v1 < 5 then exit").unwrap(), vec![0x6F, 0x05, 0x8F, 0x17, 0x3F, 0x01, 0x00, 0xFD]);
+        assert_eq!(compile("if v1 >= v2 then exit").unwrap(), vec![0x8F, 0x20, 0x8F, 0x17, 0x4F, 0x01, 0x00, 0xFD]);
+
+        // Run every pseudo comparison with both orders and equal operands
+        type Comparison = fn(u8, u8) -> bool;
+        let ops: [(&str, Comparison); 4] = [
+            ("<", |a, b| a < b),
+            (">", |a, b| a > b),
+            ("<=", |a, b| a <= b),
+            (">=", |a, b| a >= b),
+        ];
+        for &(op, expected) in ops.iter() {
+            for &(a, b) in [(1, 2), (3, 2), (2, 2)].iter() {
+                let source = format!("v0 := {} v1 := {} if v0 {} v1 then exit loop again", a, b, op);
+                let rom = compile(&source).unwrap();
+                let mut vm = Vm::new();
+                vm.load_rom(&mut &rom[..]).unwrap();
+                vm.step(0.1).unwrap();
+                assert_eq!(vm.exited(), expected(a, b), "{}", source);
+            }
+        }
+    }
+
+    #[test]
+    fn macros_and_calc() {
+        let source = "
+            :macro twice ins arg { ins arg ins arg }
+            :calc size { 2 * 3 + 4 }
+            :calc half { ( size / 2 ) + 1 }
+            twice bcd v3
+            :byte size
+            :byte half
+            :byte { @ 0x201 }
+        ";
+        assert_eq!(compile(source).unwrap(), vec![0xF3, 0x33, 0xF3, 0x33, 14, 8, 0x33]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(octo_error("v0 := 256"), (1, 7, "Value 256 does not fit in a byte".to_string()));
+        assert_eq!(octo_error("jump nowhere"), (1, 6, "Undefined name `nowhere`".to_string()));
+        assert_eq!(octo_error("\n  loop\n v0 += 1"), (2, 3, "`loop` is never closed".to_string()));
+        assert_eq!(octo_error("v0 := v1 :bogus"), (1, 10, "Unknown directive `:bogus`".to_string()));
+        assert_eq!(octo_error("if v0 == 1 v1 := 2"), (1, 12, "Expected `then` or `begin`, found `v1`".to_string()));
+        assert_eq!(octo_error(": a : a"), (1, 7, "`a` is already defined".to_string()));
+        assert_eq!(octo_error("v0 :="), (1, 6, "Unexpected end of input".to_string()));
+    }
+}
@@ -0,0 +1,107 @@
+//! Interpreter quirks for ambiguous instructions
+//!
+//! Several CHIP-8 instructions behave differently depending on the
+//! interpreter a program was written for. `Quirks` selects one
+//! interpretation for each of them, with presets for the most common
+//! platforms.
+
+use keypad::WaitKeyMode;
+
+/// How `StoreRegisters` and `LoadRegisters` modify the `I` register
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoadStoreIncrement {
+    /// `I` is set to `I + Vx + 1` afterwards (COSMAC VIP)
+    XPlusOne,
+    /// `I` is set to `I + Vx` afterwards (CHIP-48)
+    X,
+    /// `I` is left unchanged (SUPER-CHIP)
+    Unchanged,
+}
+
+/// Behaviour of the ambiguous instructions
+///
+/// The `Default` is the behaviour the `Vm` always had, which happens
+/// to match the `xo_chip` preset.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    /// `ShiftRight` and `ShiftLeft` shift `Vx` in place and ignore `Vy`
+    pub shift_in_place: bool,
+    /// How `StoreRegisters` and `LoadRegisters` advance `I`
+    pub load_store: LoadStoreIncrement,
+    /// `Or`, `And` and `XOr` reset `VF` to `0`
+    pub vf_reset: bool,
+    /// `LongJump` jumps to `Vx + Addr` where `x` is the high nibble of `Addr`,
+    /// instead of `V0 + Addr`
+    pub jump_vx: bool,
+    /// Sprites are clipped at the screen edges instead of wrapping around
+    pub clip_sprites: bool,
+    /// `Draw` waits for the next frame (vertical blank) before execution
+    /// continues, which limits programs to 60 sprites per second
+    ///
+    /// `run_cycles` and `run_frame` execute nothing until the frame ends,
+    /// `StepOutcome::waiting_on_vblank` tells callers of `step_instruction`
+    /// to wait themselves.
+    pub display_wait: bool,
+    /// When `WaitKey` stops waiting for a key
+    pub wait_key: WaitKeyMode,
+}
+
+impl Quirks {
+    /// Original COSMAC VIP interpreter
+    pub fn cosmac_vip() -> Quirks {
+        Quirks {
+            shift_in_place: false,
+            load_store: LoadStoreIncrement::XPlusOne,
+            vf_reset: true,
+            jump_vx: false,
+            clip_sprites: true,
+            display_wait: true,
+            wait_key: WaitKeyMode::Release,
+        }
+    }
+
+    /// CHIP-48 interpreter for the HP-48 calculators
+    pub fn chip48() -> Quirks {
+        Quirks {
+            shift_in_place: true,
+            load_store: LoadStoreIncrement::X,
+            vf_reset: false,
+            jump_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            wait_key: WaitKeyMode::Press,
+        }
+    }
+
+    /// SUPER-CHIP 1.1 interpreter
+    pub fn super_chip() -> Quirks {
+        Quirks {
+            shift_in_place: true,
+            load_store: LoadStoreIncrement::Unchanged,
+            vf_reset: false,
+            jump_vx: true,
+            clip_sprites: true,
+            display_wait: false,
+            wait_key: WaitKeyMode::Press,
+        }
+    }
+
+    /// XO-CHIP as implemented by Octo
+    pub fn xo_chip() -> Quirks {
+        Quirks {
+            shift_in_place: false,
+            load_store: LoadStoreIncrement::XPlusOne,
+            vf_reset: false,
+            jump_vx: false,
+            clip_sprites: false,
+            display_wait: false,
+            wait_key: WaitKeyMode::Press,
+        }
+    }
+}
+
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks::xo_chip()
+    }
+}
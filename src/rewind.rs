@@ -0,0 +1,265 @@
+//! Rewinding the execution of a `Vm`
+//!
+//! A `Rewind` buffer records the state of a `Vm` once per frame. Only the
+//! state of the most recent frame is kept in full, every older frame is
+//! stored as a delta against its successor: the runs of bytes of the save
+//! state (registers, RAM, screen, timers, ...) that differ between them.
+//! Programs typically only touch a few bytes per frame, so the deltas stay
+//! small.
+
+use std::collections::VecDeque;
+
+use error::Chip8Error;
+use vm::Vm;
+
+/// Changed bytes closer together than this are stored as one run
+const MERGE_GAP: usize = 8;
+
+/// Changes that turn a frame's save state into the one of the frame before it
+#[derive(Clone, Debug)]
+enum Delta {
+    /// Runs of older bytes and the offset they start at
+    Runs(Vec<(usize, Vec<u8>)>),
+    /// The full older state, used if the state size changed
+    Full(Vec<u8>),
+}
+
+impl Delta {
+    /// Computes the delta from `newer` back to `older`
+    fn between(newer: &[u8], older: &[u8]) -> Delta {
+        if newer.len() != older.len() {
+            return Delta::Full(older.to_vec());
+        }
+
+        let mut runs: Vec<(usize, Vec<u8>)> = Vec::new();
+        let mut pos = 0;
+        while pos < older.len() {
+            if newer[pos] == older[pos] {
+                pos += 1;
+                continue;
+            }
+            let start = pos;
+            let mut end = pos + 1;
+            // Extend the run until MERGE_GAP equal bytes in a row are found
+            while end < older.len() {
+                let gap = older[end..].iter().zip(&newer[end..])
+                    .take(MERGE_GAP)
+                    .take_while(|&(a, b)| a == b)
+                    .count();
+                if gap == MERGE_GAP || end + gap == older.len() {
+                    break;
+                }
+                end += gap + 1;
+            }
+            runs.push((start, older[start..end].to_vec()));
+            pos = end;
+        }
+        Delta::Runs(runs)
+    }
+
+    /// Turns `state` into the older state
+    fn apply(&self, state: &mut Vec<u8>) {
+        match *self {
+            Delta::Runs(ref runs) => {
+                for &(offset, ref bytes) in runs.iter() {
+                    state[offset..offset + bytes.len()].copy_from_slice(bytes);
+                }
+            },
+            Delta::Full(ref older) => {
+                state.clone_from(older);
+            },
+        }
+    }
+
+    /// Approximate memory used by this delta in bytes
+    fn size(&self) -> usize {
+        match *self {
+            Delta::Runs(ref runs) => runs.iter().map(|(_, bytes)| bytes.len() + 16).sum(),
+            Delta::Full(ref older) => older.len(),
+        }
+    }
+}
+
+/// Ring buffer of past `Vm` states
+#[derive(Clone, Debug)]
+pub struct Rewind {
+    max_frames: usize,
+    max_bytes: usize,
+    /// Save state of the most recently recorded frame
+    head: Option<Vec<u8>>,
+    /// Deltas to older frames, the oldest one first
+    deltas: VecDeque<Delta>,
+    delta_bytes: usize,
+}
+
+impl Rewind {
+    /// Creates a new, empty rewind buffer
+    ///
+    /// It keeps at most `max_frames` frames to rewind to, using at most
+    /// `max_bytes` bytes for the deltas to them. The oldest frames are dropped
+    /// first. The full save state of the most recent frame is always kept on
+    /// top of that budget, so `memory_usage` can be up to its size above `max_bytes`.
+    pub fn new(max_frames: usize, max_bytes: usize) -> Rewind {
+        Rewind {
+            max_frames,
+            max_bytes,
+            head: None,
+            deltas: VecDeque::new(),
+            delta_bytes: 0,
+        }
+    }
+
+    /// Records the current state of `vm` as the most recent frame
+    pub fn record(&mut self, vm: &Vm) -> Result<(), Chip8Error> {
+        let mut state = Vec::new();
+        vm.save_state(&mut state)?;
+
+        if let Some(older) = self.head.take() {
+            let delta = Delta::between(&state, &older);
+            self.delta_bytes += delta.size();
+            self.deltas.push_back(delta);
+        }
+        self.head = Some(state);
+
+        while self.deltas.len() > self.max_frames || self.delta_bytes > self.max_bytes {
+            match self.deltas.pop_front() {
+                Some(delta) => self.delta_bytes -= delta.size(),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores `vm` to the frame before the most recently recorded one
+    ///
+    /// Returns `false` if there is no such frame, in which case `vm` is unchanged.
+    pub fn step_back(&mut self, vm: &mut Vm) -> Result<bool, Chip8Error> {
+        Ok(self.rewind(vm, 1)? == 1)
+    }
+
+    /// Restores `vm` to the frame `frames` frames before the most recently recorded one
+    ///
+    /// Rewinds as far as possible if fewer frames were recorded, and returns the
+    /// number of frames actually rewound. The restored frame becomes the most
+    /// recently recorded one, newer frames are discarded.
+    pub fn rewind(&mut self, vm: &mut Vm, frames: usize) -> Result<usize, Chip8Error> {
+        let frames = frames.min(self.deltas.len());
+        if frames == 0 {
+            return Ok(0);
+        }
+
+        let mut state = match self.head {
+            Some(ref state) => state.clone(),
+            None => return Ok(0),
+        };
+        for delta in self.deltas.iter().rev().take(frames) {
+            delta.apply(&mut state);
+        }
+        vm.load_state(&mut &state[..])?;
+
+        for _ in 0..frames {
+            if let Some(delta) = self.deltas.pop_back() {
+                self.delta_bytes -= delta.size();
+            }
+        }
+        self.head = Some(state);
+        Ok(frames)
+    }
+
+    /// Number of frames that can be rewound
+    pub fn frames(&self) -> usize {
+        self.deltas.len()
+    }
+
+    /// Approximate memory used by the recorded frames in bytes
+    pub fn memory_usage(&self) -> usize {
+        self.delta_bytes + self.head.as_ref().map(|state| state.len()).unwrap_or(0)
+    }
+
+    /// Forgets all recorded frames
+    pub fn clear(&mut self) {
+        self.head = None;
+        self.deltas.clear();
+        self.delta_bytes = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state_of(vm: &Vm) -> Vec<u8> {
+        let mut state = Vec::new();
+        vm.save_state(&mut state).unwrap();
+        state
+    }
+
+    fn counting_vm() -> Vm {
+        let mut vm = Vm::with_seed(99);
+        // ADD V0, 1 ; RND V1, 0xFF ; LD I, 0x300 ; LD [I], V1 ; DRW V1, V0, 1 ; JP 0x200
+        let rom = [0x70, 0x01, 0xC1, 0xFF, 0xA3, 0x00, 0xF1, 0x55, 0xD1, 0x01, 0x12, 0x00];
+        vm.load_rom(&mut &rom[..]).unwrap();
+        vm
+    }
+
+    #[test]
+    fn rewind_restores_exact_states() {
+        let mut vm = counting_vm();
+        let mut rewind = Rewind::new(100, 1 << 20);
+        let mut states = Vec::new();
+        for _ in 0..10 {
+            vm.run_frame().unwrap();
+            rewind.record(&vm).unwrap();
+            states.push(state_of(&vm));
+        }
+        assert_eq!(rewind.frames(), 9);
+
+        assert!(rewind.step_back(&mut vm).unwrap());
+        assert_eq!(state_of(&vm), states[8]);
+        assert_eq!(rewind.rewind(&mut vm, 3).unwrap(), 3);
+        assert_eq!(state_of(&vm), states[5]);
+        assert_eq!(rewind.rewind(&mut vm, 100).unwrap(), 5);
+        assert_eq!(state_of(&vm), states[0]);
+        assert!(!rewind.step_back(&mut vm).unwrap());
+
+        // Continuing after a rewind replays the same frames
+        vm.run_frame().unwrap();
+        assert_eq!(state_of(&vm), states[1]);
+    }
+
+    #[test]
+    fn memory_is_bounded() {
+        let mut vm = counting_vm();
+        let mut rewind = Rewind::new(5, 1 << 20);
+        for _ in 0..20 {
+            vm.run_frame().unwrap();
+            rewind.record(&vm).unwrap();
+        }
+        assert_eq!(rewind.frames(), 5);
+
+        let mut rewind = Rewind::new(1000, 0);
+        for _ in 0..20 {
+            vm.run_frame().unwrap();
+            rewind.record(&vm).unwrap();
+        }
+        assert_eq!(rewind.frames(), 0);
+        assert_eq!(rewind.memory_usage(), state_of(&vm).len());
+    }
+
+    #[test]
+    fn deltas_are_compact() {
+        let older = vec![0u8; 1000];
+        let mut newer = older.clone();
+        newer[10] = 1;
+        newer[12] = 1;
+        newer[900] = 1;
+        match Delta::between(&newer, &older) {
+            Delta::Runs(ref runs) => {
+                assert_eq!(runs.len(), 2);
+                assert_eq!(runs[0], (10, vec![0, 0, 0]));
+                assert_eq!(runs[1], (900, vec![0]));
+            },
+            Delta::Full(_) => panic!("expected runs"),
+        }
+    }
+}
@@ -0,0 +1,176 @@
+//! Random number sources for the `Rand` instruction
+//!
+//! The `Vm` owns one `Rng` which it draws all random bytes from.
+//! Seeding it, or replacing it with a `ScriptedRng`, makes runs
+//! reproducible.
+
+use error::Chip8Error;
+
+use rand::{thread_rng, Rng as RandRng};
+
+/// Source of random bytes for the `Vm`
+pub trait Rng {
+    /// Returns the next random byte
+    fn next_byte(&mut self) -> u8;
+
+    /// Returns the internal state, which can be restored with `restore`
+    fn state(&self) -> Vec<u8>;
+
+    /// Restores an internal state previously returned by `state`
+    fn restore(&mut self, state: &[u8]) -> Result<(), Chip8Error>;
+
+    /// Returns a boxed copy of this generator, including its state
+    fn box_clone(&self) -> Box<dyn Rng>;
+}
+
+/// Seedable xorshift64* generator, the default `Rng` of the `Vm`
+#[derive(Clone, Copy, Debug)]
+pub struct XorShiftRng {
+    state: u64,
+}
+
+impl XorShiftRng {
+    /// Creates a new generator from `seed`
+    ///
+    /// Equal seeds produce equal sequences of bytes.
+    pub fn new(seed: u64) -> XorShiftRng {
+        // Scramble the seed with splitmix64, xorshift must not start at 0
+        let mut z = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        XorShiftRng { state: if z == 0 { 1 } else { z } }
+    }
+
+    /// Creates a new generator with a seed from the operating system
+    pub fn from_entropy() -> XorShiftRng {
+        XorShiftRng::new(thread_rng().gen::<u64>())
+    }
+}
+
+impl Rng for XorShiftRng {
+    fn next_byte(&mut self) -> u8 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        (self.state.wrapping_mul(0x2545_F491_4F6C_DD1D) >> 56) as u8
+    }
+
+    fn state(&self) -> Vec<u8> {
+        self.state.to_be_bytes().to_vec()
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        if state.len() != 8 {
+            return Err(Chip8Error::InvalidState("RNG state has the wrong size"));
+        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(state);
+        let state = u64::from_be_bytes(bytes);
+        if state == 0 {
+            return Err(Chip8Error::InvalidState("RNG state must not be zero"));
+        }
+        self.state = state;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn Rng> {
+        Box::new(*self)
+    }
+}
+
+/// Generator that returns a fixed sequence of bytes, starting over at the end
+///
+/// Useful for tests that need to control the outcome of `Rand`.
+#[derive(Clone, Debug)]
+pub struct ScriptedRng {
+    bytes: Vec<u8>,
+    pos: usize,
+}
+
+impl ScriptedRng {
+    /// Creates a new generator returning `bytes` in order
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bytes` is empty.
+    pub fn new(bytes: Vec<u8>) -> ScriptedRng {
+        assert!(!bytes.is_empty(), "ScriptedRng needs at least one byte");
+        ScriptedRng { bytes, pos: 0 }
+    }
+}
+
+impl Rng for ScriptedRng {
+    fn next_byte(&mut self) -> u8 {
+        let byte = self.bytes[self.pos];
+        self.pos = (self.pos + 1) % self.bytes.len();
+        byte
+    }
+
+    fn state(&self) -> Vec<u8> {
+        let mut state = (self.pos as u64).to_be_bytes().to_vec();
+        state.extend_from_slice(&self.bytes);
+        state
+    }
+
+    fn restore(&mut self, state: &[u8]) -> Result<(), Chip8Error> {
+        if state.len() <= 8 {
+            return Err(Chip8Error::InvalidState("RNG state has the wrong size"));
+        }
+        let mut pos = [0; 8];
+        pos.copy_from_slice(&state[..8]);
+        let pos = u64::from_be_bytes(pos) as usize;
+        let bytes = &state[8..];
+        if pos >= bytes.len() {
+            return Err(Chip8Error::InvalidState("RNG state position is out of range"));
+        }
+        self.bytes = bytes.to_vec();
+        self.pos = pos;
+        Ok(())
+    }
+
+    fn box_clone(&self) -> Box<dyn Rng> {
+        Box::new(self.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seeded_is_deterministic() {
+        let mut a = XorShiftRng::new(42);
+        let mut b = XorShiftRng::new(42);
+        let a: Vec<u8> = (0..32).map(|_| a.next_byte()).collect();
+        let b: Vec<u8> = (0..32).map(|_| b.next_byte()).collect();
+        assert_eq!(a, b);
+        assert!(a.iter().any(|byte| *byte != a[0]));
+    }
+
+    #[test]
+    fn restore_state() {
+        let mut rng = XorShiftRng::new(0);
+        rng.next_byte();
+        let state = rng.state();
+        let expected = rng.next_byte();
+
+        let mut other = XorShiftRng::new(1);
+        other.restore(&state).unwrap();
+        assert_eq!(other.next_byte(), expected);
+        assert!(other.restore(&[0; 8]).is_err());
+    }
+
+    #[test]
+    fn scripted() {
+        let mut rng = ScriptedRng::new(vec![1, 2, 3]);
+        assert_eq!(rng.next_byte(), 1);
+        let state = rng.state();
+        assert_eq!(rng.next_byte(), 2);
+        assert_eq!(rng.next_byte(), 3);
+        assert_eq!(rng.next_byte(), 1);
+
+        rng.restore(&state).unwrap();
+        assert_eq!(rng.next_byte(), 2);
+    }
+}
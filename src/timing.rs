@@ -0,0 +1,107 @@
+//! Costs of instructions in cycles of the `Vm` clock
+//!
+//! By default every instruction takes a single cycle of the clock set with
+//! `Vm::set_clock_hz`. With `CycleCosts::CosmacVip` every instruction takes
+//! as many cycles as the interpreter of the COSMAC VIP spends on it instead,
+//! which matches the original speed at a clock of `COSMAC_VIP_CLOCK_HZ`.
+//!
+//! The COSMAC VIP costs are approximate: instructions whose time depends on
+//! the data they work on are charged an average, and the time the VIP spends
+//! on the display and the timer interrupt is not taken into account.
+
+use instructions::Instruction;
+use instructions::Instruction::*;
+
+/// Machine cycles per second of the COSMAC VIP, 8 clock pulses of its
+/// 1.76064 MHz CDP1802 each
+pub const COSMAC_VIP_CLOCK_HZ: u32 = 220_080;
+
+/// Machine cycles the COSMAC VIP interpreter spends on fetching and
+/// decoding every instruction
+const VIP_FETCH: u32 = 40;
+
+/// Cost of every instruction in cycles
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CycleCosts {
+    /// One cycle for every instruction
+    Uniform,
+    /// Machine cycles of the COSMAC VIP interpreter
+    CosmacVip,
+}
+
+// `#[default]` on a variant needs a newer compiler than this crate supports
+#[allow(unknown_lints, clippy::derivable_impls)]
+impl Default for CycleCosts {
+    /// One cycle for every instruction
+    fn default() -> CycleCosts {
+        CycleCosts::Uniform
+    }
+}
+
+impl CycleCosts {
+    /// Returns the number of cycles `ins` takes, at least `1`
+    pub fn cost(&self, ins: &Instruction) -> u32 {
+        match *self {
+            CycleCosts::Uniform => 1,
+            CycleCosts::CosmacVip => VIP_FETCH + cosmac_vip_cost(ins),
+        }
+    }
+}
+
+/// Machine cycles the COSMAC VIP interpreter spends on executing `ins`
+///
+/// Instructions the VIP does not know are charged like simple ones.
+fn cosmac_vip_cost(ins: &Instruction) -> u32 {
+    match *ins {
+        Clear => 3024,
+        Return => 10,
+        Jump(_) => 12,
+        Call(_) => 26,
+        SkipEqualK(..) | SkipNotEqualK(..) => 10,
+        SkipEqual(..) | SkipNotEqual(..) => 14,
+        SetK(..) => 6,
+        AddK(..) => 10,
+        Set(..) => 12,
+        Or(..) | And(..) | XOr(..) | Add(..) | Sub(..) | ShiftRight(..) | SubInv(..) | ShiftLeft(..) => 44,
+        LoadI(_) => 12,
+        LongJump(_) => 22,
+        Rand(..) => 36,
+        // Rows are shifted into place and XORed onto the screen one by one,
+        // 16x16 sprites draw 32 rows of a byte each
+        Draw(_, _, rows) => {
+            let rows = if rows.bits == 0 { 32 } else { rows.bits as u32 };
+            26 + 68 * rows
+        },
+        SkipPressed(_) | SkipNotPressed(_) => 14,
+        GetTimer(_) | WaitKey(_) | SetTimer(_) | SetSoundTimer(_) => 10,
+        AddToI(_) | LoadHexGlyph(_) => 16,
+        // Repeated subtraction, depending on the digits
+        StoreBCD(_) => 152,
+        StoreRegisters(vx) | LoadRegisters(vx) => 14 + 14 * (vx as u32 + 1),
+        _ => 12,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use instructions::{Addr, Nibble};
+    use instructions::Register::*;
+
+    #[test]
+    fn uniform() {
+        assert_eq!(CycleCosts::default(), CycleCosts::Uniform);
+        assert_eq!(CycleCosts::Uniform.cost(&Clear), 1);
+        assert_eq!(CycleCosts::Uniform.cost(&Draw(V0, V1, Nibble { bits: 15 })), 1);
+    }
+
+    #[test]
+    fn cosmac_vip() {
+        let costs = CycleCosts::CosmacVip;
+        assert_eq!(costs.cost(&SetK(V0, 1)), VIP_FETCH + 6);
+        assert_eq!(costs.cost(&Jump(Addr { bits: 0x200 })), VIP_FETCH + 12);
+        assert!(costs.cost(&Draw(V0, V1, Nibble { bits: 5 })) > 5 * costs.cost(&SetK(V0, 1)));
+        assert!(costs.cost(&Draw(V0, V1, Nibble { bits: 15 })) > costs.cost(&Draw(V0, V1, Nibble { bits: 5 })));
+        assert!(costs.cost(&StoreRegisters(VF)) > costs.cost(&StoreRegisters(V0)));
+    }
+}
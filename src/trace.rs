@@ -0,0 +1,178 @@
+//! Execution traces
+//!
+//! A `Tracer` set with `Vm::set_tracer` receives a `TraceRecord` for every
+//! executed instruction, with the state of the registers after it was
+//! executed. `TraceWriter` writes the records in one of the `Format`s, to
+//! compare runs with each other or with reference emulators.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+
+use error::Chip8Error;
+use instructions::{Instruction, RawInstruction};
+
+/// State after one executed instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TraceRecord {
+    /// Address of the instruction
+    pub pc: usize,
+    /// Raw bits of the instruction, the first 2 bytes for 4 byte instructions
+    pub raw: RawInstruction,
+    /// The decoded instruction
+    pub instruction: Instruction,
+    /// Data registers `V0` .. `VF`
+    pub registers: [u8; 16],
+    /// The `I` register
+    pub i: usize,
+    pub delay_timer: u8,
+    pub sound_timer: u8,
+    /// Number of return addresses on the stack
+    pub sp: usize,
+}
+
+/// Receiver of the `TraceRecord`s of a `Vm`
+pub trait Tracer {
+    /// Called after each executed instruction
+    fn record(&mut self, record: &TraceRecord);
+}
+
+impl<T: Tracer> Tracer for Rc<RefCell<T>> {
+    fn record(&mut self, record: &TraceRecord) {
+        self.borrow_mut().record(record);
+    }
+}
+
+/// Output format of a `TraceWriter`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// Fixed size records of 25 bytes, all values big-endian: `pc` (2 bytes),
+    /// raw opcode (2), `V0` .. `VF` (16), `I` (2), delay timer, sound timer
+    /// and stack depth (1 each)
+    Binary,
+    /// One line per instruction with fixed width fields, followed by the
+    /// disassembled instruction:
+    /// `0200 6A02 V0:00 .. VF:00 I:0000 DT:00 ST:00 SP:00 LD VA, 0x02`
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// `Tracer` that writes the records to a `Write`
+///
+/// Writing stops at the first error, which is returned by `finish`.
+pub struct TraceWriter<W: Write> {
+    writer: W,
+    format: Format,
+    error: Option<Chip8Error>,
+}
+
+impl<W: Write> TraceWriter<W> {
+    /// Creates a new tracer writing to `writer` in `format`
+    pub fn new(writer: W, format: Format) -> TraceWriter<W> {
+        TraceWriter { writer, format, error: None }
+    }
+
+    /// Returns the underlying writer
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    /// Flushes and returns the underlying writer, or the first error
+    pub fn finish(mut self) -> Result<W, Chip8Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+
+    fn write(&mut self, record: &TraceRecord) -> Result<(), Chip8Error> {
+        match self.format {
+            Format::Binary => {
+                let mut bytes = Vec::with_capacity(25);
+                bytes.extend_from_slice(&(record.pc as u16).to_be_bytes());
+                bytes.extend_from_slice(&record.raw.to_bytes());
+                bytes.extend_from_slice(&record.registers);
+                bytes.extend_from_slice(&(record.i as u16).to_be_bytes());
+                bytes.push(record.delay_timer);
+                bytes.push(record.sound_timer);
+                bytes.push(record.sp as u8);
+                self.writer.write_all(&bytes)?;
+            },
+            Format::Text => {
+                let mut line = format!("{:04X} {:04X}", record.pc, record.raw.bits());
+                for (idx, value) in record.registers.iter().enumerate() {
+                    line.push_str(&format!(" V{:X}:{:02X}", idx, value));
+                }
+                writeln!(self.writer, "{} I:{:04X} DT:{:02X} ST:{:02X} SP:{:02X} {}",
+                         line, record.i, record.delay_timer, record.sound_timer, record.sp,
+                         record.instruction)?;
+            },
+            Format::Json => {
+                let registers: Vec<String> = record.registers.iter().map(|value| value.to_string()).collect();
+                writeln!(self.writer,
+                         "{{\"pc\":{},\"opcode\":{},\"instruction\":\"{}\",\"v\":[{}],\"i\":{},\"dt\":{},\"st\":{},\"sp\":{}}}",
+                         record.pc, record.raw.bits(), record.instruction, registers.join(","),
+                         record.i, record.delay_timer, record.sound_timer, record.sp)?;
+            },
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write> Tracer for TraceWriter<W> {
+    fn record(&mut self, record: &TraceRecord) {
+        if self.error.is_none() {
+            self.error = self.write(record).err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use asm::assemble;
+    use vm::Vm;
+
+    fn traced(format: Format) -> Vec<u8> {
+        let rom = assemble("LD VA, 2\nLD I, 0x123\nCALL sub\nsub: LD DT, VA\nEXIT").unwrap();
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        let tracer = Rc::new(RefCell::new(TraceWriter::new(Vec::new(), format)));
+        vm.set_tracer(Some(Box::new(tracer.clone())));
+        vm.step(0.1).unwrap();
+        vm.set_tracer(None);
+        Rc::try_unwrap(tracer).ok().unwrap().into_inner().finish().unwrap()
+    }
+
+    #[test]
+    fn text() {
+        let trace = String::from_utf8(traced(Format::Text)).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[0], "0200 6A02 V0:00 V1:00 V2:00 V3:00 V4:00 V5:00 V6:00 V7:00 \
+                              V8:00 V9:00 VA:02 VB:00 VC:00 VD:00 VE:00 VF:00 I:0000 DT:00 ST:00 SP:00 LD VA, 0x02");
+        assert!(lines[3].starts_with("0206 FA15"));
+        assert!(lines[3].contains("I:0123 DT:02 ST:00 SP:01 LD DT, VA"));
+        assert!(lines[4].ends_with("EXIT"));
+    }
+
+    #[test]
+    fn binary() {
+        let trace = traced(Format::Binary);
+        assert_eq!(trace.len(), 5 * 25);
+        let record = &trace[3 * 25..4 * 25];
+        assert_eq!(&record[..4], &[0x02, 0x06, 0xFA, 0x15]);
+        assert_eq!(record[4 + 0xA], 2);
+        assert_eq!(&record[20..], &[0x01, 0x23, 2, 0, 1]);
+    }
+
+    #[test]
+    fn json() {
+        let trace = String::from_utf8(traced(Format::Json)).unwrap();
+        assert_eq!(trace.lines().next().unwrap(),
+                   "{\"pc\":512,\"opcode\":27138,\"instruction\":\"LD VA, 0x02\",\
+                    \"v\":[0,0,0,0,0,0,0,0,0,0,2,0,0,0,0,0],\"i\":0,\"dt\":0,\"st\":0,\"sp\":0}");
+    }
+}
@@ -1,25 +1,42 @@
 //! Virtual machine implementation
 
-extern crate rand;
-
-use std::io::{Read, Write, BufWriter};
+use std::io::{self, Read, Write, BufWriter};
+use audio::{Audio, AudioConfig};
+use breakpoints::{Access, Breakpoints, StopReason};
+use disasm;
 use error::Chip8Error;
 use instructions::Register;
 use instructions::{RawInstruction, Instruction};
+use keypad::Keypad;
+use observer::VmObserver;
+use quirks::{Quirks, LoadStoreIncrement};
+use rng::{Rng, XorShiftRng};
+use timing::CycleCosts;
+use trace::{TraceRecord, Tracer};
+use std::ops::Range;
 use std::slice::Chunks;
 
-use rand::Rng;
+mod state;
+
+pub use self::state::STATE_VERSION;
 
 /// Size of the RAM in bytes
-const RAM_SIZE: usize = 4096;
+pub const RAM_SIZE: usize = 4096;
+/// Size of the extended XO-CHIP RAM in bytes
+pub const XO_CHIP_RAM_SIZE: usize = 65536;
 /// Depth of the stack
 const STACK_SIZE: usize = 256;
 /// Number of data registers, i.e. `V0` .. `VF`
 const NUM_DATA_REGISTERS: usize = 16;
 /// Memory address for programm (ROM) start
-const PROGRAM_START: usize = 0x200;
-/// CPU clock speed
-const CLOCK_HZ: f32 = 600.0;
+pub const PROGRAM_START: usize = 0x200;
+/// Default CPU clock speed in cycles per second
+pub const CLOCK_HZ: u32 = 600;
+/// Rate of frames per second, at which the timers count down
+pub const FRAME_HZ: u32 = 60;
+/// Relative error of the cycles `step` computes from its `f32` duration,
+/// which is rounded to the nearest `f32` and so off by at most this much
+const DT_PRECISION: f64 = f32::EPSILON as f64;
 
 /// Memory address of built-in font sprites
 const FONT_ADDR: usize = 0;
@@ -46,156 +63,511 @@ const FONT: [u8; FONT_BYTES] = [
 	0xF0, 0x80, 0xF0, 0x80, 0xF0, // E
 	0xF0, 0x80, 0xF0, 0x80, 0x80, // F
 ];
-/// Width of the screen in pixels
+/// Memory address of built-in large font sprites
+const LARGE_FONT_ADDR: usize = FONT_ADDR + FONT_BYTES;
+/// Number of rows in one large font sprite
+const LARGE_FONT_HEIGHT: usize = 10;
+/// Size of one large font sprite
+const LARGE_FONT_BYTES: usize = LARGE_FONT_HEIGHT * 16;
+/// Data of the built-in large font
+const LARGE_FONT: [u8; LARGE_FONT_BYTES] = [
+	0xFF, 0xFF, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, // 0
+	0x18, 0x78, 0x78, 0x18, 0x18, 0x18, 0x18, 0x18, 0xFF, 0xFF, // 1
+	0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // 2
+	0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 3
+	0xC3, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0x03, 0x03, // 4
+	0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 5
+	0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 6
+	0xFF, 0xFF, 0x03, 0x03, 0x06, 0x0C, 0x18, 0x18, 0x18, 0x18, // 7
+	0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, // 8
+	0xFF, 0xFF, 0xC3, 0xC3, 0xFF, 0xFF, 0x03, 0x03, 0xFF, 0xFF, // 9
+	0x7E, 0xFF, 0xC3, 0xC3, 0xC3, 0xFF, 0xFF, 0xC3, 0xC3, 0xC3, // A
+	0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, 0xC3, 0xC3, 0xFC, 0xFC, // B
+	0x3C, 0xFF, 0xC3, 0xC0, 0xC0, 0xC0, 0xC0, 0xC3, 0xFF, 0x3C, // C
+	0xFC, 0xFE, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xC3, 0xFE, 0xFC, // D
+	0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, // E
+	0xFF, 0xFF, 0xC0, 0xC0, 0xFF, 0xFF, 0xC0, 0xC0, 0xC0, 0xC0, // F
+];
+/// Width of the low resolution screen in pixels
 const SCREEN_WIDTH: usize = 64;
-/// Height of the screen in pixels
+/// Height of the low resolution screen in pixels
 const SCREEN_HEIGHT: usize = 32;
-/// Total number of pixels of the screen
-const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
-
-/// Number of keys on the keypad
-const NUM_KEYS: usize = 16;
+/// Width of the high resolution screen in pixels
+const HIRES_SCREEN_WIDTH: usize = 128;
+/// Height of the high resolution screen in pixels
+const HIRES_SCREEN_HEIGHT: usize = 64;
+/// Total number of pixels of the screen in its largest resolution
+const SCREEN_PIXELS: usize = HIRES_SCREEN_WIDTH * HIRES_SCREEN_HEIGHT;
+/// Number of pixels scrolled by `ScrollLeft` and `ScrollRight`
+const SCROLL_PIXELS: isize = 4;
+
+/// Number of RPL user flags
+const NUM_FLAGS: usize = 16;
+
+/// Mask of the 16 bits of the `I` register
+const I_MASK: usize = 0xFFFF;
+
+/// Number of bitplanes of the screen
+const NUM_PLANES: usize = 2;
+/// Bitmask of all XO-CHIP bitplanes
+const ALL_PLANES: u8 = (1 << NUM_PLANES) - 1;
+/// Size of the audio pattern buffer in bytes
+const AUDIO_PATTERN_BYTES: usize = 16;
+/// Default audio pattern pitch, plays the pattern at 4000 bits per second
+const DEFAULT_PITCH: u8 = 64;
+
+/// Result of executing a single instruction with `Vm::step_instruction`
+#[derive(Clone, Copy, Debug)]
+pub struct StepOutcome {
+    /// The decoded instruction
+    pub instruction: Instruction,
+    /// Program counter before the instruction was executed
+    pub pc_before: usize,
+    /// Program counter after the instruction was executed
+    pub pc_after: usize,
+    /// `true` if the instruction modified the screen
+    pub screen_changed: bool,
+    /// `true` if execution is blocked until a key is pressed
+    pub waiting_on_key: bool,
+    /// `true` if execution is blocked until the next frame, because `Draw`
+    /// waits for the vertical blank
+    pub waiting_on_vblank: bool,
+    /// `true` if the instruction jumped to itself, i.e. the program is idling
+    pub idle_loop: bool,
+    /// Watchpoint that fired because of the instruction
+    pub stop: Option<StopReason>,
+}
 
 /// Virtual machine
 ///
 /// The virtual machine manages state like its registers,
 /// the RAM, stack, screen pixels, pressed keys as well as
 /// timers and some internal state.
-// This would require "impl<T> Clone for [T; ARBITRARY_CONSTANT] where T: Copy"
-// One can probably do this with a macro, but for now I'm too lazy.
-//#[derive(Clone, Copy)]
+///
+/// The entire state can be saved and restored with `save_state` and
+/// `load_state`, or copied with `clone`. The `Breakpoints` of a debugger
+/// are not part of the saved state, and neither are the `Tracer` and the
+/// `VmObserver`s, which clones do not inherit. Neither is the `Audio`
+/// generator, clones only inherit its configuration.
 pub struct Vm {
     reg: [u8; NUM_DATA_REGISTERS],
     i: usize,
     pc: usize,
     sp: usize,
     stack: [usize; STACK_SIZE],
-    ram: [u8; RAM_SIZE],
+    ram: Vec<u8>,
 
     timer: u8,
-    t_tick: f32,
-
     sound_timer: u8,
-    st_tick: f32,
+    clock_hz: u32,
+    cycle_costs: CycleCosts,
+    /// Cycles the current instruction takes beyond its first one
+    busy_cycles: u32,
+    /// Progress of the current frame in `1 / (FRAME_HZ * clock_hz)` seconds,
+    /// every cycle adds `FRAME_HZ` and the frame ends at `clock_hz`
+    frame_ticks: u32,
+    /// Fraction of a cycle left over from the time of earlier `step` calls
+    cycle_remainder: f64,
 
     screen: [u8; SCREEN_PIXELS],
-    keys: [u8; NUM_KEYS],
-    waiting_on_key: Option<Register>,
+    hires: bool,
+    planes: u8,
+    screen_changed: bool,
+    keypad: Keypad,
+    flags: [u8; NUM_FLAGS],
+    exited: bool,
+    audio_pattern: [u8; AUDIO_PATTERN_BYTES],
+    audio_pattern_loaded: bool,
+    pitch: u8,
+
+    quirks: Quirks,
+    rng: Box<dyn Rng>,
+    waiting_on_vblank: bool,
+
+    breakpoints: Breakpoints,
+    /// Address of the breakpoint `step` stopped at, which does not fire
+    /// again when execution resumes
+    resume_pc: Option<usize>,
+    /// First watchpoint that fired during the current instruction
+    watch_hit: Option<StopReason>,
+    tracer: Option<Box<dyn Tracer>>,
+    observers: Vec<Box<dyn VmObserver>>,
+    audio: Option<Audio>,
 }
 
 impl Vm {
     /// Creates a new `Vm` instance with default state
     pub fn new() -> Vm {
+        Vm::with_quirks(Quirks::default())
+    }
+
+    /// Creates a new `Vm` instance with default state, whose `Rand`
+    /// instructions return the same sequence for the same `seed`
+    pub fn with_seed(seed: u64) -> Vm {
+        let mut vm = Vm::new();
+        vm.set_rng(Box::new(XorShiftRng::new(seed)));
+        vm
+    }
+
+    /// Creates a new `Vm` instance with default state, interpreting
+    /// ambiguous instructions according to `quirks`
+    pub fn with_quirks(quirks: Quirks) -> Vm {
+        Vm::with_ram_size(quirks, RAM_SIZE)
+    }
+
+    /// Creates a new `Vm` instance with default state and `ram_size` bytes of RAM,
+    /// interpreting ambiguous instructions according to `quirks`
+    ///
+    /// XO-CHIP programs need `XO_CHIP_RAM_SIZE` bytes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ram_size` is not within `PROGRAM_START` .. `XO_CHIP_RAM_SIZE`.
+    pub fn with_ram_size(quirks: Quirks, ram_size: usize) -> Vm {
+        assert!(ram_size > PROGRAM_START && ram_size <= XO_CHIP_RAM_SIZE,
+                "RAM size {} is not supported", ram_size);
+
         let mut vm = Vm {
             reg: [0; NUM_DATA_REGISTERS],
             i: 0,
             pc: PROGRAM_START,
             sp: 0,
             stack: [0; STACK_SIZE],
-            ram: [0; RAM_SIZE],
+            ram: vec![0; ram_size],
 
             timer: 0,
-            t_tick: 0.0,
-
             sound_timer: 0,
-            st_tick: 0.0,
+            clock_hz: CLOCK_HZ,
+            cycle_costs: CycleCosts::Uniform,
+            busy_cycles: 0,
+            frame_ticks: 0,
+            cycle_remainder: 0.0,
 
             screen: [0; SCREEN_PIXELS],
-            keys: [0; NUM_KEYS],
-            waiting_on_key: None,
+            hires: false,
+            planes: 0b01,
+            screen_changed: false,
+            keypad: Keypad::new(),
+            flags: [0; NUM_FLAGS],
+            exited: false,
+            audio_pattern: [0; AUDIO_PATTERN_BYTES],
+            audio_pattern_loaded: false,
+            pitch: DEFAULT_PITCH,
+
+            quirks,
+            rng: Box::new(XorShiftRng::from_entropy()),
+            waiting_on_vblank: false,
+
+            breakpoints: Breakpoints::new(),
+            resume_pc: None,
+            watch_hit: None,
+            tracer: None,
+            observers: Vec::new(),
+            audio: None,
         };
         {
             let mut ram = BufWriter::new(&mut vm.ram[FONT_ADDR..(FONT_ADDR + FONT_BYTES)]);
             ram.write_all(FONT.as_ref()).unwrap();
             debug!("Initialized VM with built-in font");
         }
+        {
+            let mut ram = BufWriter::new(&mut vm.ram[LARGE_FONT_ADDR..(LARGE_FONT_ADDR + LARGE_FONT_BYTES)]);
+            ram.write_all(LARGE_FONT.as_ref()).unwrap();
+            debug!("Initialized VM with built-in large font");
+        }
         vm
     }
 
+    /// Returns the quirks this `Vm` interprets instructions with
+    pub fn quirks(&self) -> Quirks {
+        self.quirks
+    }
+
+    /// Changes the quirks this `Vm` interprets instructions with
+    pub fn set_quirks(&mut self, quirks: Quirks) {
+        self.quirks = quirks;
+    }
+
+    /// Replaces the source of random bytes for `Rand`
+    pub fn set_rng(&mut self, rng: Box<dyn Rng>) {
+        self.rng = rng;
+    }
+
     /// Loads the ROM contents from `reader` into RAM at the program start address
-    pub fn load_rom(&mut self, reader: &mut Read) -> Result<usize, Chip8Error> {
+    pub fn load_rom(&mut self, reader: &mut dyn Read) -> Result<usize, Chip8Error> {
         let mut rom = Vec::new();
-        try!(reader.read_to_end(&mut rom));
+        reader.read_to_end(&mut rom)?;
         let rom_len = rom.len();
-        let available_ram = RAM_SIZE - PROGRAM_START;
+        let available_ram = self.ram.len() - PROGRAM_START;
         if rom_len > available_ram {
             error!("ROM size ({}) is larger than available RAM ({})!", rom_len, available_ram);
             return Err(Chip8Error::Io("ROM was larger than available RAM", None))
         }
         // TODO: ROM needs to contain at least one instruction to be valid
-        let mut ram = BufWriter::new(&mut self.ram[PROGRAM_START..RAM_SIZE]);
-        try!(ram.write_all(rom.as_ref()));
+        let mut ram = BufWriter::new(&mut self.ram[PROGRAM_START..]);
+        ram.write_all(rom.as_ref())?;
         debug!("Loaded ROM of size {}", rom_len);
-        return Ok(rom_len);
+        Ok(rom_len)
+    }
+
+    /// Returns the contents of the RAM
+    pub fn ram(&self) -> &[u8] {
+        &self.ram
+    }
+
+    /// Copies `bytes` into the RAM starting at `addr`
+    pub fn write_ram(&mut self, addr: usize, bytes: &[u8]) -> Result<(), Chip8Error> {
+        let pc = self.pc;
+        let dst = self.ram_range(addr, bytes.len(), pc)?;
+        self.ram[dst].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Returns the value of the data register `vx`
+    pub fn register(&self, vx: Register) -> u8 {
+        self.reg[vx as usize]
+    }
+
+    /// Sets the data register `vx` to `value`
+    pub fn set_register(&mut self, vx: Register, value: u8) {
+        self.reg[vx as usize] = value;
+    }
+
+    /// Returns the address of the next instruction
+    pub fn pc(&self) -> usize {
+        self.pc
+    }
+
+    /// Continues execution at `addr`
+    pub fn set_pc(&mut self, addr: usize) {
+        self.pc = addr;
+    }
+
+    /// Returns the value of the `I` register
+    pub fn i(&self) -> usize {
+        self.i
+    }
+
+    /// Sets the `I` register to `value`, wrapped to 16 bits
+    pub fn set_i(&mut self, value: usize) {
+        self.i = value & I_MASK;
+    }
+
+    /// Returns the number of return addresses on the stack
+    pub fn sp(&self) -> usize {
+        self.sp
+    }
+
+    /// Returns the value of the delay timer
+    pub fn delay_timer(&self) -> u8 {
+        self.timer
+    }
+
+    /// Returns the value of the sound timer
+    pub fn sound_timer(&self) -> u8 {
+        self.sound_timer
+    }
+
+    /// Returns the breakpoints and watchpoints `step` stops at
+    pub fn breakpoints(&self) -> &Breakpoints {
+        &self.breakpoints
+    }
+
+    /// Returns the breakpoints and watchpoints `step` stops at, for changing them
+    pub fn breakpoints_mut(&mut self) -> &mut Breakpoints {
+        &mut self.breakpoints
+    }
+
+    /// Sets the `Tracer` that records every executed instruction, or removes
+    /// it with `None`, and returns the previous one
+    pub fn set_tracer(&mut self, tracer: Option<Box<dyn Tracer>>) -> Option<Box<dyn Tracer>> {
+        ::std::mem::replace(&mut self.tracer, tracer)
+    }
+
+    /// Adds an observer that is called on the events of this `Vm`
+    pub fn add_observer(&mut self, observer: Box<dyn VmObserver>) {
+        self.observers.push(observer);
+    }
+
+    /// Removes all observers
+    pub fn clear_observers(&mut self) {
+        self.observers.clear();
+    }
+
+    /// Starts generating audio samples with `config`, or stops with `None`
+    pub fn set_audio(&mut self, config: Option<AudioConfig>) {
+        self.audio = config.map(Audio::new);
+    }
+
+    /// Removes and returns the audio samples generated since the last call,
+    /// empty if audio is not enabled
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.audio.as_mut().map(|audio| audio.take_samples()).unwrap_or_default()
     }
 
     #[allow(dead_code)]
-    pub fn dump_ram(&self, writer: &mut Write) {
+    pub fn dump_ram(&self, writer: &mut dyn Write) {
         writer.write_all(&self.ram).unwrap();
     }
 
+    /// Returns `true` if `Draw` waits for the vertical blank at the end of
+    /// the current frame, see `Quirks::display_wait`
+    pub fn waiting_on_vblank(&self) -> bool {
+        self.waiting_on_vblank
+    }
+
     /// Returns `True` if the sound timer is active
     pub fn beeping(&self) -> bool {
         self.sound_timer > 0
     }
 
     /// Marks the key with index `idx` as being set
-    pub fn set_key(&mut self, idx: u8) {
+    ///
+    /// Completes a pending `WaitKey` if the key was not pressed yet and the
+    /// `Quirks` ask for `WaitKeyMode::Press`.
+    pub fn set_key(&mut self, idx: u8) -> Result<(), Chip8Error> {
         debug!("Set key {}", idx);
-        self.keys[idx as usize] = 1;
-        if let Some(vx) = self.waiting_on_key {
+        let done = self.keypad.press(idx, self.quirks.wait_key)?;
+        self.complete_wait_key(done);
+        Ok(())
+    }
+
+    /// Marks they key with index `idx` as being unset
+    ///
+    /// Completes a pending `WaitKey` if the key was pressed while waiting and
+    /// the `Quirks` ask for `WaitKeyMode::Release`.
+    pub fn unset_key(&mut self, idx: u8) -> Result<(), Chip8Error> {
+        debug!("Unset key {}", idx);
+        let done = self.keypad.release(idx, self.quirks.wait_key)?;
+        self.complete_wait_key(done);
+        Ok(())
+    }
+
+    /// Stores the key that completed `WaitKey`, if any
+    fn complete_wait_key(&mut self, done: Option<(Register, u8)>) {
+        if let Some((vx, idx)) = done {
             debug!("No longer waiting on key");
             self.reg[vx as usize] = idx;
-            self.waiting_on_key = None;
         }
     }
 
-    /// Marks they key with index `idx` as being unset
-    pub fn unset_key(&mut self, idx: u8) {
-        debug!("Unset key {}", idx);
-        self.keys[idx as usize] = 0;
+    /// Returns the pressed keys as bitmask, bit `n` for key `n`
+    pub fn keys(&self) -> u16 {
+        self.keypad.bits()
     }
 
-    fn exec(&mut self, ins: &Instruction) -> bool {
+    /// Returns the state of the keypad
+    pub fn keypad(&self) -> &Keypad {
+        &self.keypad
+    }
+
+    /// Returns `true` if the key with index `idx` is set
+    fn key_pressed(&self, idx: u8) -> Result<bool, Chip8Error> {
+        self.keypad.pressed(idx)
+    }
+
+    /// Checks that `len` bytes starting at `addr` are within the RAM,
+    /// `pc` is the address of the accessing instruction
+    fn ram_range(&self, addr: usize, len: usize, pc: usize) -> Result<Range<usize>, Chip8Error> {
+        match addr.checked_add(len) {
+            Some(end) if end <= self.ram.len() => Ok(addr..end),
+            _ => {
+                let last = addr.saturating_add(len).saturating_sub(1);
+                error!("Memory access at 0x{:X} out of bounds", last);
+                Err(Chip8Error::MemoryOutOfBounds { addr: last, pc })
+            },
+        }
+    }
+
+    /// Records the first watchpoint that fires for an `access` of `range`
+    /// by the instruction at `pc`
+    fn watch(&mut self, range: &Range<usize>, access: Access, pc: usize) {
+        if self.watch_hit.is_none() {
+            if let Some((id, addr)) = self.breakpoints.check_access(range, access) {
+                self.watch_hit = Some(StopReason::Watchpoint { id, pc, addr, access });
+            }
+        }
+    }
+
+    /// Calls the observers after the program wrote to `range`
+    fn notify_write(&mut self, range: Range<usize>) {
+        for observer in self.observers.iter_mut() {
+            observer.on_memory_write(range.start, &self.ram[range.clone()]);
+        }
+    }
+
+    /// Reads the 2 raw bytes at `addr`
+    fn fetch(&self, addr: usize) -> Result<RawInstruction, Chip8Error> {
+        let codes = &self.ram[self.ram_range(addr, 2, addr)?];
+        Ok(RawInstruction::new(((codes[0] as u16) << 8) | codes[1] as u16))
+    }
+
+    /// Reads and decodes the instruction at `addr`
+    fn decode(&self, addr: usize) -> Result<(RawInstruction, Instruction), Chip8Error> {
+        let raw_ins = self.fetch(addr)?;
+        let ins = if raw_ins.is_long() {
+            let operand = self.fetch(addr + 2)?;
+            Instruction::from_raw_long(&raw_ins, &operand)
+        } else {
+            Instruction::from_raw(&raw_ins)
+        };
+        Ok((raw_ins, ins))
+    }
+
+    fn exec(&mut self, ins: &Instruction) -> Result<bool, Chip8Error> {
         use instructions::Instruction::*;
 
+        // Address of the instruction itself, for errors
+        let pc = self.pc - ins.size();
+
         match *ins {
             // Sys(addr) intentionally left unimplemented.
 
             Clear => {
-                for b in self.screen.iter_mut() {
-                    *b = 0;
-                }
+                let planes = self.planes;
+                self.clear_screen(planes);
             },
             Return => {
+                if self.sp == 0 {
+                    return Err(Chip8Error::StackUnderflow { pc });
+                }
                 self.pc = self.stack[self.sp];
                 self.sp-=1;
+                for observer in self.observers.iter_mut() {
+                    observer.on_return(pc, self.pc);
+                }
             },
             Jump(addr) => {
-                let idle = self.pc-2 == addr.bits as usize;
+                let idle = pc == addr.bits as usize;
                 self.pc = addr.bits as usize;
-                if idle { return true; }
+                if idle { return Ok(true); }
             }
             Call(addr) => {
+                if self.sp + 1 >= STACK_SIZE {
+                    return Err(Chip8Error::StackOverflow { pc });
+                }
                 self.sp+=1;
                 self.stack[self.sp] = self.pc;
                 self.pc = addr.bits as usize;
+                for observer in self.observers.iter_mut() {
+                    observer.on_call(pc, self.pc);
+                }
             },
             SkipEqualK(vx, k) => {
                 if self.reg[vx as usize] == k {
-                    self.pc += 2;
+                    self.skip();
                 }
             },
             SkipNotEqualK(vx, k) => {
                 if self.reg[vx as usize] != k {
-                    self.pc += 2;
+                    self.skip();
                 }
             },
             SkipEqual(vx, vy) => {
                 let x = self.reg[vx as usize];
                 let y = self.reg[vy as usize];
                 if x == y {
-                    self.pc += 2;
+                    self.skip();
                 }
             },
             SetK(vx, byte) => {
@@ -205,68 +577,84 @@ impl Vm {
                 self.reg[vx as usize] = self.reg[vx as usize].wrapping_add(byte);
             },
             Set(vx, vy) => self.reg[vx as usize] = self.reg[vy as usize],
-            Or(vx, vy)  => self.reg[vx as usize] |= self.reg[vy as usize],
-            And(vx, vy) => self.reg[vx as usize] &= self.reg[vy as usize],
-            XOr(vx, vy) => self.reg[vx as usize] ^= self.reg[vy as usize],
+            Or(vx, vy)  => {
+                self.reg[vx as usize] |= self.reg[vy as usize];
+                self.reset_vf();
+            },
+            And(vx, vy) => {
+                self.reg[vx as usize] &= self.reg[vy as usize];
+                self.reset_vf();
+            },
+            XOr(vx, vy) => {
+                self.reg[vx as usize] ^= self.reg[vy as usize];
+                self.reset_vf();
+            },
+            // The arithmetic instructions write VF after the result, so the
+            // flag wins if `vx` is VF
             Add(vx, vy) => {
                 let x = self.reg[vx as usize] as u16;
                 let y = self.reg[vy as usize] as u16;
                 let res = x + y;
 
+                self.reg[vx as usize] = res as u8;
+
                 // VF is carryover
                 self.reg[Register::VF as usize] = (res > 255) as u8;
-
-                self.reg[vx as usize] = res as u8;
             },
             Sub(vx, vy) => {
                 let x = self.reg[vx as usize];
                 let y = self.reg[vy as usize];
 
-                // VF is Not Borrow i.e. x > y
-                self.reg[Register::VF as usize] = (x > y) as u8;
-
                 self.reg[vx as usize] = x.wrapping_sub(y);
+
+                // VF is Not Borrow i.e. x >= y
+                self.reg[Register::VF as usize] = (x >= y) as u8;
             },
             ShiftRight(vx, vy) => {
-                let y = self.reg[vy as usize];
+                let y = self.reg[self.shift_source(vx, vy) as usize];
+
+                self.reg[vx as usize] = y >> 1;
 
                 // VF is lsb before shift
                 self.reg[Register::VF as usize] = 0x1 & y;
-
-                self.reg[vx as usize] = y >> 1;
             },
             SubInv(vx, vy) => {
                 let x = self.reg[vx as usize];
                 let y = self.reg[vy as usize];
 
-                // VF is Not Borrow i.e. y > x
-                self.reg[Register::VF as usize] = (y > x) as u8;
-
                 self.reg[vx as usize] = y.wrapping_sub(x);
+
+                // VF is Not Borrow i.e. y >= x
+                self.reg[Register::VF as usize] = (y >= x) as u8;
             },
             ShiftLeft(vx, vy) => {
-                let y = self.reg[vy as usize];
+                let y = self.reg[self.shift_source(vx, vy) as usize];
+
+                self.reg[vx as usize] = y << 1;
 
                 // VF is msb before shift
                 self.reg[Register::VF as usize] = y >> 7;
-
-                self.reg[vx as usize] = y << 1;
             }
             SkipNotEqual(vx, vy) => {
                 let x = self.reg[vx as usize];
                 let y = self.reg[vy as usize];
                 if x != y {
-                    self.pc += 2;
+                    self.skip();
                 }
             },
             LoadI(addr) => {
                 self.i = addr.bits as usize;
             },
             LongJump(addr) => {
-                self.pc = (self.reg[Register::V0 as usize] as u16 + addr.bits) as usize;
+                let offset = if self.quirks.jump_vx {
+                    self.reg[(addr.bits >> 8) as usize]
+                } else {
+                    self.reg[Register::V0 as usize]
+                };
+                self.pc = (offset as u16 + addr.bits) as usize;
             },
             Rand(vx, byte) => {
-                self.reg[vx as usize] = rand::thread_rng().gen::<u8>() & byte;
+                self.reg[vx as usize] = self.rng.next_byte() & byte;
             }
             Draw(vx, vy, n) => {
                 let x = self.reg[vx as usize] as usize;
@@ -274,50 +662,99 @@ impl Vm {
                 let i = self.i;
                 let n = n.bits as usize;
 
-                let sprite = &self.ram[i..i+n];
+                // A height of 0 means a 16x16 sprite, with two bytes per row
+                let (cols, rows) = if n == 0 { (16, 16) } else { (8, n) };
+                let sprite_bytes = rows * cols / 8;
+                let planes: Vec<usize> = (0..NUM_PLANES).filter(|p| self.planes & (1 << p) != 0).collect();
+                let src = self.ram_range(i, sprite_bytes * planes.len(), pc)?;
+                self.watch(&src, Access::Read, pc);
+                let sprites = &self.ram[src];
+
+                let width = self.screen_width();
+                let height = self.screen_height();
+
+                // The sprite origin always wraps, only the pixels past the
+                // edges are affected by clipping
+                let x = x % width;
+                let y = y % height;
+                let clip = self.quirks.clip_sprites;
 
                 self.reg[Register::VF as usize] = 0;
-                for (sy, byte) in sprite.iter().enumerate() {
-                    let dy = (y + sy) % SCREEN_HEIGHT;
-                    for sx in 0usize..8 {
-                        let px = (*byte >> (7 - sx)) & 0b00000001;
-                        let dx = (x + sx) % SCREEN_WIDTH;
-                        let idx = dy * SCREEN_WIDTH + dx;
-                        self.screen[idx] ^= px;
-
-                        // Vf is if there was a collision
-                        self.reg[Register::VF as usize] |= (self.screen[idx] == 0 && px == 1) as u8;
+                // Each selected plane gets its own sprite data, one after another
+                for (plane, sprite) in planes.iter().zip(sprites.chunks(sprite_bytes)) {
+                    let plane_bit = 1 << plane;
+                    for (sy, row) in sprite.chunks(cols / 8).enumerate() {
+                        if clip && y + sy >= height {
+                            break;
+                        }
+                        let dy = (y + sy) % height;
+                        let bits = row.iter().fold(0u16, |bits, byte| (bits << 8) | *byte as u16);
+                        for sx in 0usize..cols {
+                            if clip && x + sx >= width {
+                                break;
+                            }
+                            if (bits >> (cols - 1 - sx)) & 0b00000001 == 0 {
+                                continue;
+                            }
+                            let dx = (x + sx) % width;
+                            let idx = dy * width + dx;
+                            self.screen[idx] ^= plane_bit;
+                            self.screen_changed = true;
+
+                            // Vf is if there was a collision
+                            self.reg[Register::VF as usize] |= (self.screen[idx] & plane_bit == 0) as u8;
+                        }
                     }
                 }
+                let collided = self.reg[Register::VF as usize] != 0;
+                for observer in self.observers.iter_mut() {
+                    observer.on_draw(x, y, sprites, collided);
+                }
+
+                if self.quirks.display_wait {
+                    self.waiting_on_vblank = true;
+                }
             },
             SkipPressed(vx) => {
                 let idx = self.reg[vx as usize];
-                if self.keys[idx as usize] == 1 {
-                    self.pc += 2;
+                if self.key_pressed(idx)? {
+                    self.skip();
                 }
             }
             SkipNotPressed(vx) => {
                 let idx = self.reg[vx as usize];
-                if self.keys[idx  as usize] != 1 {
-                    self.pc += 2;
+                if !self.key_pressed(idx)? {
+                    self.skip();
                 }
             }
             GetTimer(vx) => {
                 self.reg[vx as usize] = self.timer;
             },
             WaitKey(vx) => {
-                self.waiting_on_key = Some(vx);
+                self.keypad.wait(vx);
+                for observer in self.observers.iter_mut() {
+                    observer.on_wait_key(vx);
+                }
             },
             SetTimer(vx) => {
                 self.timer = self.reg[vx as usize];
-                self.t_tick = 1.0 / 60.0;
             },
             SetSoundTimer(vx) => {
+                let was_beeping = self.beeping();
                 self.sound_timer = self.reg[vx as usize];
-                self.st_tick = 1.0 / 60.0;
+                match (was_beeping, self.beeping()) {
+                    (false, true) => for observer in self.observers.iter_mut() {
+                        observer.on_sound_start();
+                    },
+                    (true, false) => for observer in self.observers.iter_mut() {
+                        observer.on_sound_stop();
+                    },
+                    _ => {},
+                }
             },
             AddToI(vx) => {
-                self.i += self.reg[vx as usize] as usize;
+                // `I` is 16 bits wide, which only matters with 64 KiB of RAM
+                self.i = (self.i + self.reg[vx as usize] as usize) & I_MASK;
             },
             LoadHexGlyph(vx) => {
                 let x = self.reg[vx as usize];
@@ -325,95 +762,472 @@ impl Vm {
             }
             StoreBCD(vx) => {
                 let mut x = self.reg[vx as usize];
+                let dst = self.ram_range(self.i, 3, pc)?;
+                self.watch(&dst, Access::Write, pc);
 
                 let mut place = 100;
-                for i in 0usize..3 {
+                for i in dst.clone() {
                     let bcd = x / place;
-                    self.ram[self.i + i] = bcd;
+                    self.ram[i] = bcd;
                     x -= bcd * place;
                     place /= 10;
                 }
+                self.notify_write(dst);
             }
             StoreRegisters(vx) => {
                 let vx = vx as usize;
                 let i = self.i;
 
-                let mut dst = &mut self.ram[i..i+vx+1];
-                for (x,b) in dst.iter_mut().enumerate() {
+                let dst = self.ram_range(i, vx + 1, pc)?;
+                self.watch(&dst, Access::Write, pc);
+                for (x,b) in self.ram[dst.clone()].iter_mut().enumerate() {
                     *b = self.reg[x];
                 }
-                self.i += vx+1;
+                self.notify_write(dst);
+                self.advance_i(vx);
             },
             LoadRegisters(vx) => {
                 let vx = vx as usize;
                 let i = self.i;
 
-                let src = &self.ram[i..i+vx+1];
+                let src = self.ram_range(i, vx + 1, pc)?;
+                self.watch(&src, Access::Read, pc);
+                let src = &self.ram[src];
                 for (x,b) in src.iter().enumerate() {
                     self.reg[x] = *b;
                 }
-                self.i += vx+1;
+                self.advance_i(vx);
+            },
+            ScrollDown(n) => self.scroll(0, n.bits as isize),
+            ScrollRight => self.scroll(SCROLL_PIXELS, 0),
+            ScrollLeft => self.scroll(-SCROLL_PIXELS, 0),
+            Exit => {
+                debug!("Program exited");
+                self.exited = true;
+            },
+            LowRes => {
+                self.hires = false;
+                // Switching the resolution clears all planes, not just the selected ones
+                self.clear_screen(ALL_PLANES);
+            },
+            HighRes => {
+                self.hires = true;
+                self.clear_screen(ALL_PLANES);
+            },
+            LoadLargeHexGlyph(vx) => {
+                let x = self.reg[vx as usize];
+                self.i = LARGE_FONT_ADDR + (x as usize & 0xF) * LARGE_FONT_HEIGHT;
+            },
+            StoreFlags(vx) => {
+                let vx = vx as usize;
+                self.flags[..vx + 1].copy_from_slice(&self.reg[..vx + 1]);
+            },
+            LoadFlags(vx) => {
+                let vx = vx as usize;
+                self.reg[..vx + 1].copy_from_slice(&self.flags[..vx + 1]);
+            },
+            SaveRange(vx, vy) => {
+                let dst = self.ram_range(self.i, Vm::register_range(vx, vy).count(), pc)?;
+                self.watch(&dst, Access::Write, pc);
+                for (addr, r) in dst.clone().zip(Vm::register_range(vx, vy)) {
+                    self.ram[addr] = self.reg[r];
+                }
+                self.notify_write(dst);
+            },
+            LoadRange(vx, vy) => {
+                let src = self.ram_range(self.i, Vm::register_range(vx, vy).count(), pc)?;
+                self.watch(&src, Access::Read, pc);
+                for (addr, r) in src.zip(Vm::register_range(vx, vy)) {
+                    self.reg[r] = self.ram[addr];
+                }
+            },
+            LoadLongI(addr) => {
+                self.i = addr.bits as usize;
+            },
+            SelectPlanes(n) => {
+                self.planes = n.bits & ALL_PLANES;
+            },
+            LoadAudio => {
+                let src = self.ram_range(self.i, AUDIO_PATTERN_BYTES, pc)?;
+                self.watch(&src, Access::Read, pc);
+                self.audio_pattern.copy_from_slice(&self.ram[src]);
+                self.audio_pattern_loaded = true;
             },
+            SetPitch(vx) => {
+                self.pitch = self.reg[vx as usize];
+            },
+            ScrollUp(n) => self.scroll(0, -(n.bits as isize)),
             ref other => {
                 debug!("Instruction not implemented {:?} skipping...", other)
             }
         }
-        return false;
+        Ok(false)
     }
 
-    fn time_step(&mut self, dt:f32) {
-        if self.timer > 0 {
-            self.t_tick -= dt;
-            if self.t_tick <= 0.0 {
-                self.timer -= 1;
-                self.t_tick = 1.0 / 60.0;
+    /// Skips the next instruction, which might be a 4 byte instruction
+    fn skip(&mut self) {
+        let long = self.fetch(self.pc).map(|next| next.is_long()).unwrap_or(false);
+        self.pc += if long { 4 } else { 2 };
+    }
+
+    /// Register indices from `vx` to `vy` inclusive, in reverse if `vx` is larger
+    fn register_range(vx: Register, vy: Register) -> Box<dyn Iterator<Item=usize>> {
+        let (x, y) = (vx as usize, vy as usize);
+        if x <= y {
+            Box::new(x..y + 1)
+        } else {
+            Box::new((y..x + 1).rev())
+        }
+    }
+
+    /// Sets all pixels of the bitmask `planes` to the unlit state
+    fn clear_screen(&mut self, planes: u8) {
+        for b in self.screen.iter_mut() {
+            *b &= !planes;
+        }
+        self.screen_changed = true;
+        for observer in self.observers.iter_mut() {
+            observer.on_clear();
+        }
+    }
+
+    /// Moves all pixels of the selected planes by `dx` columns and `dy` rows,
+    /// pixels moved in from outside the screen are unlit
+    fn scroll(&mut self, dx: isize, dy: isize) {
+        let width = self.screen_width() as isize;
+        let height = self.screen_height() as isize;
+        let planes = self.planes;
+        let old = self.screen;
+
+        for y in 0..height {
+            for x in 0..width {
+                let (sx, sy) = (x - dx, y - dy);
+                let inside = sx >= 0 && sx < width && sy >= 0 && sy < height;
+                let moved = if inside { old[(sy * width + sx) as usize] } else { 0 };
+                let idx = (y * width + x) as usize;
+                self.screen[idx] = (old[idx] & !planes) | (moved & planes);
             }
         }
+        self.screen_changed = true;
+        for observer in self.observers.iter_mut() {
+            observer.on_scroll(dx, dy);
+        }
+    }
+
+    /// Register that `ShiftRight` and `ShiftLeft` read from
+    fn shift_source(&self, vx: Register, vy: Register) -> Register {
+        if self.quirks.shift_in_place { vx } else { vy }
+    }
+
+    /// Resets `VF` after a logic instruction, if the quirk asks for it
+    fn reset_vf(&mut self) {
+        if self.quirks.vf_reset {
+            self.reg[Register::VF as usize] = 0;
+        }
+    }
 
+    /// Advances `I` after `StoreRegisters` or `LoadRegisters` with `Vx`
+    fn advance_i(&mut self, vx: usize) {
+        match self.quirks.load_store {
+            LoadStoreIncrement::XPlusOne => self.i = (self.i + vx + 1) & I_MASK,
+            LoadStoreIncrement::X => self.i = (self.i + vx) & I_MASK,
+            LoadStoreIncrement::Unchanged => {},
+        }
+    }
+
+    /// Returns the speed of the clock in cycles per second
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    /// Sets the speed of the clock to `hz` cycles per second, `CLOCK_HZ` by default
+    ///
+    /// The progress of the current frame is kept, the timers still count
+    /// down at `FRAME_HZ`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `hz` is `0`.
+    pub fn set_clock_hz(&mut self, hz: u32) {
+        assert!(hz > 0, "Clock speed must not be 0");
+        self.frame_ticks = (self.frame_ticks as u64 * hz as u64 / self.clock_hz as u64) as u32;
+        self.clock_hz = hz;
+    }
+
+    /// Returns the costs of instructions in cycles
+    pub fn cycle_costs(&self) -> CycleCosts {
+        self.cycle_costs
+    }
+
+    /// Sets the costs of instructions in cycles, `CycleCosts::Uniform` by default
+    ///
+    /// `CycleCosts::CosmacVip` runs programs at their original speed with a
+    /// clock of `timing::COSMAC_VIP_CLOCK_HZ`.
+    pub fn set_cycle_costs(&mut self, costs: CycleCosts) {
+        self.cycle_costs = costs;
+    }
+
+    /// Advances the time by one cycle, ending the frame if the cycle completes it
+    ///
+    /// The timers count down and `Draw` stops waiting for the vertical blank
+    /// at the end of every frame.
+    fn end_cycle(&mut self) {
+        if self.audio.is_some() {
+            let rate = self.audio_pattern_rate();
+            let pattern = if self.audio_pattern_loaded { Some((&self.audio_pattern[..], rate)) } else { None };
+            if let Some(ref mut audio) = self.audio {
+                audio.generate(1, self.clock_hz, self.sound_timer > 0, pattern);
+            }
+        }
+
+        self.frame_ticks += FRAME_HZ;
+        while self.frame_ticks >= self.clock_hz {
+            self.frame_ticks -= self.clock_hz;
+            self.end_frame();
+        }
+    }
+
+    /// Counts the timers down and ends waiting for the vertical blank
+    fn end_frame(&mut self) {
+        trace!("End of frame");
+
+        self.waiting_on_vblank = false;
+        if self.timer > 0 {
+            self.timer -= 1;
+        }
         if self.sound_timer > 0 {
-            self.st_tick -= dt;
-            if self.st_tick <= 0.0 {
-                self.sound_timer -= 1;
-                self.st_tick = 1.0 / 60.0;
+            self.sound_timer -= 1;
+            if self.sound_timer == 0 {
+                for observer in self.observers.iter_mut() {
+                    observer.on_sound_stop();
+                }
+            }
+        }
+    }
+
+    /// Runs one cycle, executing the next instruction unless the current one
+    /// takes more cycles or the program waits for a key or the vertical blank
+    fn run_cycle(&mut self) -> Result<Option<StopReason>, Chip8Error> {
+        let mut stop = None;
+        if self.busy_cycles > 0 {
+            self.busy_cycles -= 1;
+        } else if self.keypad.waiting().is_none() && !self.waiting_on_vblank {
+            if let Some(reason) = self.check_breakpoints() {
+                return Ok(Some(reason));
+            }
+            let outcome = self.step_instruction()?;
+            self.busy_cycles = self.cycle_costs.cost(&outcome.instruction).saturating_sub(1);
+            stop = outcome.stop;
+        }
+        self.end_cycle();
+        Ok(stop)
+    }
+
+    /// Runs the program for `cycles` cycles of the clock, see `set_clock_hz`
+    ///
+    /// Stops at the first instruction that can not be executed and returns the
+    /// fault, in which case the program counter still points at that instruction.
+    ///
+    /// Returns early with the `StopReason` if one of the `breakpoints` fires,
+    /// dropping the remaining cycles. Execution resumes with the next call,
+    /// without the same breakpoint firing again right away. Also returns early
+    /// once the program exited.
+    pub fn run_cycles(&mut self, cycles: u32) -> Result<Option<StopReason>, Chip8Error> {
+        for cycle in 0..cycles {
+            trace!("Executing cycle {}/{}", cycle, cycles);
+            if self.exited {
+                debug!("Cancel remaining cycles after program exit");
+                return Ok(None);
+            }
+            if let Some(reason) = self.run_cycle()? {
+                debug!("Stopped at {:?}", reason);
+                return Ok(Some(reason));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Runs the program up to the end of the current frame, i.e. for one
+    /// frame of `1 / FRAME_HZ` seconds if the previous call completed a frame
+    ///
+    /// Faults and breakpoints stop the frame early like with `run_cycles`,
+    /// the next call then completes the frame.
+    pub fn run_frame(&mut self) -> Result<Option<StopReason>, Chip8Error> {
+        while !self.exited {
+            if let Some(reason) = self.run_cycle()? {
+                debug!("Stopped at {:?}", reason);
+                return Ok(Some(reason));
+            }
+            // Every cycle that does not end the frame leaves at least `FRAME_HZ` ticks
+            if self.frame_ticks < FRAME_HZ {
+                break;
             }
         }
+        Ok(None)
     }
 
-    // dt: Time in seconds since last step
-    /// Executes remaining instructions since the last step
-    pub fn step(&mut self, dt:f32) {
+    /// Runs the program for `dt` seconds
+    ///
+    /// The cycles of `dt` are run with `run_cycles`, fractions of a cycle carry
+    /// over to the next call.
+    pub fn step(&mut self, dt:f32) -> Result<Option<StopReason>, Chip8Error> {
+        let exact = dt as f64 * self.clock_hz as f64 + self.cycle_remainder;
+        // `dt` is only as precise as an `f32`, e.g. `3.0 / 600.0` falls short
+        // of 3 cycles, so a cycle that is complete within that precision counts
+        // as complete and the remainder can be slightly negative
+        let cycles = (exact + exact.abs() * DT_PRECISION).floor().max(0.0);
+        self.cycle_remainder = exact - cycles;
+        self.run_cycles(cycles as u32)
+    }
+
+    /// Returns the breakpoint that fires before the next instruction
+    fn check_breakpoints(&mut self) -> Option<StopReason> {
+        let pc = self.pc;
+        if self.resume_pc.take() == Some(pc) || self.breakpoints.is_empty() {
+            return None;
+        }
+        // Faults are left for `step_instruction` to report
+        let ins = self.decode(pc).ok()?.1;
+        let id = self.breakpoints.check(self, pc, &ins)?;
+        self.resume_pc = Some(pc);
+        Some(StopReason::Breakpoint { id, pc })
+    }
+
+    /// Executes exactly one instruction, without advancing the timers
+    ///
+    /// While blocked on `WaitKey` nothing is executed and the pending `WaitKey`
+    /// is returned again, likewise `Exit` after the program exited.
+    ///
+    /// The wait for the vertical blank after `Draw` is left to the caller,
+    /// as it only ends with the frame: `run_cycles` and `run_frame` execute
+    /// nothing until the end of the frame while the outcome reports
+    /// `waiting_on_vblank`.
+    ///
+    /// If the instruction can not be executed the fault is returned and the
+    /// program counter still points at that instruction.
+    pub fn step_instruction(&mut self) -> Result<StepOutcome, Chip8Error> {
+        let pc = self.pc;
+        // The breakpoint that stopped before this instruction is passed now
+        self.resume_pc = None;
+        let blocked = if let Some(vx) = self.keypad.waiting() {
+            Some(Instruction::WaitKey(vx))
+        } else if self.exited {
+            Some(Instruction::Exit)
+        } else {
+            None
+        };
+        if let Some(instruction) = blocked {
+            return Ok(StepOutcome {
+                instruction,
+                pc_before: pc,
+                pc_after: pc,
+                screen_changed: false,
+                waiting_on_key: self.keypad.waiting().is_some(),
+                waiting_on_vblank: self.waiting_on_vblank,
+                idle_loop: false,
+                stop: None,
+            });
+        }
 
-        let sub_steps = (CLOCK_HZ * dt).round() as usize;
-        let ddt = dt / sub_steps as f32;
+        let (raw_ins, ins) = self.decode(pc)?;
+        if let Instruction::Unknown = ins {
+            error!("Unknown opcode 0x{:04X} at 0x{:04X}", raw_ins.bits(), pc);
+            return Err(Chip8Error::UnknownOpcode { raw: raw_ins.bits(), pc });
+        }
 
-        for step in 0..sub_steps {
-            trace!("Executing step {}/{}", step, sub_steps);
-            self.time_step(ddt);
-            if self.waiting_on_key.is_some() {
-                debug!("Cancel remaining execution steps while waiting for key");
-                return;
+        self.pc += ins.size();
+        self.screen_changed = false;
+        self.watch_hit = None;
+        for observer in self.observers.iter_mut() {
+            observer.on_instruction(pc, &ins);
+        }
+        let idle_loop = match self.exec(&ins) {
+            Ok(idle) => idle,
+            Err(err) => {
+                error!("{}", err);
+                self.pc = pc;
+                return Err(err);
             }
+        };
 
-            let raw = {
-                let codes = &self.ram[self.pc..self.pc+2];
-                ((codes[0] as u16) << 8) | codes[1] as u16
-            };
-            let raw_ins = RawInstruction::new(raw);
-            self.pc += 2;
-            self.exec(&Instruction::from_raw(&raw_ins));
+        if let Some(ref mut tracer) = self.tracer {
+            tracer.record(&TraceRecord {
+                pc,
+                raw: raw_ins,
+                instruction: ins,
+                registers: self.reg,
+                i: self.i,
+                delay_timer: self.timer,
+                sound_timer: self.sound_timer,
+                sp: self.sp,
+            });
         }
+
+        Ok(StepOutcome {
+            instruction: ins,
+            pc_before: pc,
+            pc_after: self.pc,
+            screen_changed: self.screen_changed,
+            waiting_on_key: self.keypad.waiting().is_some(),
+            waiting_on_vblank: self.waiting_on_vblank,
+            idle_loop,
+            stop: self.watch_hit.take(),
+        })
+    }
+
+    /// Returns `true` if the program stopped itself with `Exit`
+    pub fn exited(&self) -> bool {
+        self.exited
+    }
+
+    /// Returns `true` if the screen is in high resolution mode
+    pub fn hires(&self) -> bool {
+        self.hires
+    }
+
+    /// Width of the screen in pixels for the current resolution
+    pub fn screen_width(&self) -> usize {
+        if self.hires { HIRES_SCREEN_WIDTH } else { SCREEN_WIDTH }
+    }
+
+    /// Height of the screen in pixels for the current resolution
+    pub fn screen_height(&self) -> usize {
+        if self.hires { HIRES_SCREEN_HEIGHT } else { SCREEN_HEIGHT }
+    }
+
+    /// Returns the contents of the XO-CHIP audio pattern buffer,
+    /// one bit per sample
+    pub fn audio_pattern(&self) -> &[u8] {
+        &self.audio_pattern
+    }
+
+    /// Returns `true` once the program loaded the audio pattern buffer
+    ///
+    /// Until then the sound timer plays the plain CHIP-8 tone.
+    pub fn audio_pattern_loaded(&self) -> bool {
+        self.audio_pattern_loaded
+    }
+
+    /// Returns the playback rate of the audio pattern buffer in bits per second
+    pub fn audio_pattern_rate(&self) -> f32 {
+        4000.0 * 2f32.powf((self.pitch as f32 - DEFAULT_PITCH as f32) / 48.0)
     }
 
-    /// Returns the pixel rows of the screen
-    pub fn screen_rows<'a>(&'a self) -> Chunks<'a, u8> {
-        self.screen.chunks(SCREEN_WIDTH)
+    /// Returns the pixel rows of the screen in the current resolution
+    ///
+    /// Each pixel is a bitmask of the bitplanes it is lit in, i.e. `0` is unlit
+    /// and any other value is lit. Programs that do not use XO-CHIP bitplanes
+    /// only ever light pixels with `1`.
+    pub fn screen_rows(&self) -> Chunks<'_, u8> {
+        let width = self.screen_width();
+        self.screen[..width * self.screen_height()].chunks(width)
     }
 
     /// Prints the current screen pixels to `stdout`
     #[allow(dead_code)]
     pub fn print_screen(&self) {
-        for row in self.screen.chunks(SCREEN_WIDTH) {
-            println!("");
+        for row in self.screen_rows() {
+            println!();
             for byte in row.iter() {
                 match *byte {
                     0x0 => print!("░"),
@@ -423,36 +1237,70 @@ impl Vm {
         }
     }
 
-    // This needs experimental slice patterns and is unused anyways
-    /*
-    /// Prints a disassembly of the entire RAM to `stdout`
+    /// Prints a disassembly of the program in RAM to `stdout`
     #[allow(dead_code)]
     pub fn print_disassembly(&self) {
-        for i in self.ram.chunks(2) {
-            match i {
-                [0, 0] => continue,
-                [h, l] => {
-                    let raw_ins = RawInstruction::new(((h as u16) << 8) | l as u16);
-                    println!("raw bits: 0x{:X}", raw_ins.bits());
-                    println!("instruction: {:?}", Instruction::from_raw(&raw_ins));
-                    println!("addr: 0x{:X}", raw_ins.addr().bits);
-                    println!("x: 0x{:X}", raw_ins.x() as u8);
-                    println!("y: 0x{:X}", raw_ins.y() as u8);
-                    println!("n_high: 0x{:X}", raw_ins.n_high().bits);
-                    println!("n_low: 0x{:X}", raw_ins.n_low().bits);
-                    println!("k: 0x{:X}\n", raw_ins.k());
-                },
-                _ => continue
-            }
+        let lines = disasm::disassemble_vm(self);
+        let stdout = io::stdout();
+        disasm::write_listing(&mut stdout.lock(), &lines, disasm::Syntax::Cowgod).unwrap();
+    }
+}
+
+impl Clone for Vm {
+    fn clone(&self) -> Vm {
+        Vm {
+            reg: self.reg,
+            i: self.i,
+            pc: self.pc,
+            sp: self.sp,
+            stack: self.stack,
+            ram: self.ram.clone(),
+
+            timer: self.timer,
+            sound_timer: self.sound_timer,
+            clock_hz: self.clock_hz,
+            cycle_costs: self.cycle_costs,
+            busy_cycles: self.busy_cycles,
+            frame_ticks: self.frame_ticks,
+            cycle_remainder: self.cycle_remainder,
+
+            screen: self.screen,
+            hires: self.hires,
+            planes: self.planes,
+            screen_changed: self.screen_changed,
+            keypad: self.keypad,
+            flags: self.flags,
+            exited: self.exited,
+            audio_pattern: self.audio_pattern,
+            audio_pattern_loaded: self.audio_pattern_loaded,
+            pitch: self.pitch,
+
+            quirks: self.quirks,
+            rng: self.rng.box_clone(),
+            waiting_on_vblank: self.waiting_on_vblank,
+
+            breakpoints: self.breakpoints.clone(),
+            resume_pc: self.resume_pc,
+            watch_hit: self.watch_hit,
+            tracer: None,
+            observers: Vec::new(),
+            audio: self.audio.as_ref().map(|audio| Audio::new(audio.config())),
         }
     }
-    */
+}
+
+impl Default for Vm {
+    fn default() -> Vm {
+        Vm::new()
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use instructions::*;
+    use quirks::Quirks;
+    use rng::ScriptedRng;
     use instructions::Register::*;
 
     macro_rules! reg_test {
@@ -472,7 +1320,7 @@ mod tests {
                 $(
                     vm.reg[$reg_before as usize] = $reg_before_val;
                 )+
-                vm.exec(&$ins);
+                vm.exec(&$ins).unwrap();
                 $(
                     assert!(vm.reg[$reg_after as usize] == $reg_after_val);
                 )+
@@ -498,6 +1346,14 @@ mod tests {
         ins: Instruction::Add(V2, V3)
     });
 
+    reg_test!(
+        add_into_vf {
+        before: { VF => 0x2, V1 => 0x1 },
+        after:  { V1 => 0x1 },
+        overflow: 0, // The flag overwrites the result
+        ins: Instruction::Add(VF, V1)
+    });
+
     // AddK
     reg_test!(
         add_k {
@@ -532,6 +1388,22 @@ mod tests {
         ins: Instruction::Sub(V0, V1)
     });
 
+    reg_test!(
+        sub_equal {
+        before: { V0 => 0x3, V1 => 0x3 },
+        after:  { V0 => 0x0, V1 => 0x3 },
+        overflow: 1, // Defined as not-borrowed
+        ins: Instruction::Sub(V0, V1)
+    });
+
+    reg_test!(
+        sub_into_vf {
+        before: { VF => 0x5, V1 => 0x2 },
+        after:  { V1 => 0x2 },
+        overflow: 1, // The flag overwrites the result
+        ins: Instruction::Sub(VF, V1)
+    });
+
     // SubInv
     reg_test!(
         sub_inv {
@@ -549,6 +1421,22 @@ mod tests {
         ins: Instruction::SubInv(V0, V1)
     });
 
+    reg_test!(
+        sub_inv_equal {
+        before: { V0 => 0x3, V1 => 0x3 },
+        after:  { V0 => 0x0, V1 => 0x3 },
+        overflow: 1, // Defined as not-borrowed
+        ins: Instruction::SubInv(V0, V1)
+    });
+
+    reg_test!(
+        sub_inv_into_vf {
+        before: { VF => 0x2, V1 => 0x3 },
+        after:  { V1 => 0x3 },
+        overflow: 1, // The flag overwrites the result
+        ins: Instruction::SubInv(VF, V1)
+    });
+
     // ShiftLeft
     reg_test!(
         shiftl_vx_vy {
@@ -574,6 +1462,14 @@ mod tests {
         ins: Instruction::ShiftLeft(V2, V2)
     });
 
+    reg_test!(
+        shiftl_into_vf {
+        before: { V1 => 0b1000_0001 },
+        after:  { V1 => 0b1000_0001 },
+        overflow: 1, // The flag overwrites the result
+        ins: Instruction::ShiftLeft(VF, V1)
+    });
+
     // ShiftRight
     reg_test!(
         shiftr_vx_vy {
@@ -599,6 +1495,477 @@ mod tests {
         ins: Instruction::ShiftRight(V2, V2)
     });
 
+    reg_test!(
+        shiftr_into_vf {
+        before: { V1 => 0b0000_0101 },
+        after:  { V1 => 0b0000_0101 },
+        overflow: 1, // The flag overwrites the result
+        ins: Instruction::ShiftRight(VF, V1)
+    });
+
+    #[test]
+    fn quirk_shift_in_place() {
+        let mut vm = Vm::with_quirks(Quirks::chip48());
+        vm.reg[V2 as usize] = 0b0000_0011;
+        vm.reg[V3 as usize] = 0b1000_0000;
+        vm.exec(&Instruction::ShiftRight(V2, V3)).unwrap();
+        assert_eq!(vm.reg[V2 as usize], 0b0000_0001);
+        assert_eq!(vm.reg[VF as usize], 1);
+    }
+
+    #[test]
+    fn quirk_vf_reset() {
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.reg[VF as usize] = 1;
+        vm.exec(&Instruction::Or(V0, V1)).unwrap();
+        assert_eq!(vm.reg[VF as usize], 0);
+
+        let mut vm = Vm::with_quirks(Quirks::super_chip());
+        vm.reg[VF as usize] = 1;
+        vm.exec(&Instruction::Or(V0, V1)).unwrap();
+        assert_eq!(vm.reg[VF as usize], 1);
+    }
+
+    #[test]
+    fn quirk_load_store_increment() {
+        let presets = [
+            (Quirks::cosmac_vip(), 0x304),
+            (Quirks::chip48(), 0x303),
+            (Quirks::super_chip(), 0x300),
+        ];
+        for &(quirks, i) in presets.iter() {
+            let mut vm = Vm::with_quirks(quirks);
+            vm.i = 0x300;
+            vm.exec(&Instruction::StoreRegisters(V3)).unwrap();
+            assert_eq!(vm.i, i);
+        }
+    }
+
+    #[test]
+    fn quirk_jump_vx() {
+        let mut vm = Vm::with_quirks(Quirks::super_chip());
+        vm.reg[V0 as usize] = 0x10;
+        vm.reg[V3 as usize] = 0x02;
+        vm.exec(&Instruction::LongJump(Addr::new(0x300))).unwrap();
+        assert_eq!(vm.pc, 0x302);
+
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.reg[V0 as usize] = 0x10;
+        vm.exec(&Instruction::LongJump(Addr::new(0x300))).unwrap();
+        assert_eq!(vm.pc, 0x310);
+    }
+
+    #[test]
+    fn quirk_clip_sprites() {
+        for &(quirks, wrapped) in [(Quirks::cosmac_vip(), 0), (Quirks::xo_chip(), 1)].iter() {
+            let mut vm = Vm::with_quirks(quirks);
+            vm.reg[V0 as usize] = 62;
+            vm.i = FONT_ADDR; // "0" glyph, top row is 0xF0
+            vm.exec(&Instruction::Draw(V0, V1, Nibble::new(1))).unwrap();
+            assert_eq!(vm.screen[63], 1);
+            assert_eq!(vm.screen[1], wrapped);
+        }
+    }
+
+    #[test]
+    fn quirk_display_wait() {
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        // DRW V0, V0, 1 ; LD V1, 1 ; JP 0x204
+        vm.load_rom(&mut &[0xD0, 0x01, 0x61, 0x01, 0x12, 0x04][..]).unwrap();
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.pc, 0x202);
+        assert!(vm.waiting_on_vblank());
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.pc, 0x202, "Draw should wait for the next frame");
+        vm.run_frame().unwrap();
+        assert!(!vm.waiting_on_vblank());
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.pc, 0x204);
+
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        vm.load_rom(&mut &[0xD0, 0x01, 0x61, 0x01, 0x12, 0x04][..]).unwrap();
+        assert!(vm.step_instruction().unwrap().waiting_on_vblank);
+        // Only the scheduler waits, callers that step on their own have to
+        // honour `waiting_on_vblank` themselves
+        let outcome = vm.step_instruction().unwrap();
+        assert_eq!(outcome.pc_after, 0x204);
+        assert!(outcome.waiting_on_vblank);
+    }
+
+    #[test]
+    fn display_wait_rate() {
+        // loop: ADD V1, 1 ; DRW V0, V0, 5 ; JP loop
+        let rom = [0x71, 0x01, 0xD0, 0x05, 0x12, 0x00];
+        let draws = |clock_hz, costs| {
+            let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+            vm.load_rom(&mut &rom[..]).unwrap();
+            vm.set_clock_hz(clock_hz);
+            vm.set_cycle_costs(costs);
+            for _ in 0..60 {
+                vm.run_frame().unwrap();
+            }
+            vm.reg[V1 as usize]
+        };
+        // One sprite per frame, no matter how fast the clock is
+        assert_eq!(draws(CLOCK_HZ, CycleCosts::Uniform), 60);
+        assert_eq!(draws(10 * CLOCK_HZ, CycleCosts::Uniform), 60);
+        assert_eq!(draws(::timing::COSMAC_VIP_CLOCK_HZ, CycleCosts::CosmacVip), 60);
+
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &rom[..]).unwrap();
+        vm.run_frame().unwrap();
+        assert_eq!(vm.reg[V1 as usize] as u32, CLOCK_HZ / FRAME_HZ / 3 + 1);
+    }
+
+    #[test]
+    fn hires_large_sprite() {
+        let mut vm = Vm::new();
+        vm.exec(&Instruction::HighRes).unwrap();
+        assert_eq!(vm.screen_rows().count(), 64);
+        assert!(vm.screen_rows().all(|row| row.len() == 128));
+
+        vm.i = 0x300;
+        for b in vm.ram[0x300..0x320].iter_mut() {
+            *b = 0xFF;
+        }
+        vm.reg[V0 as usize] = 120;
+        vm.reg[V1 as usize] = 60;
+        vm.exec(&Instruction::Draw(V0, V1, Nibble::new(0))).unwrap();
+        let lit = vm.screen_rows().flat_map(|row| row.iter()).filter(|px| **px == 1).count();
+        assert_eq!(lit, 16 * 16);
+        assert_eq!(vm.screen[60 * 128 + 127], 1);
+        assert_eq!(vm.screen[0], 1, "sprite should wrap to the top left");
+
+        vm.exec(&Instruction::LowRes).unwrap();
+        assert_eq!(vm.screen_rows().count(), 32);
+        assert!(vm.screen.iter().all(|px| *px == 0));
+    }
+
+    #[test]
+    fn scroll() {
+        let mut vm = Vm::new();
+        vm.screen[0] = 1;
+        vm.exec(&Instruction::ScrollDown(Nibble::new(3))).unwrap();
+        assert_eq!(vm.screen[3 * 64], 1);
+        vm.exec(&Instruction::ScrollRight).unwrap();
+        assert_eq!(vm.screen[3 * 64 + 4], 1);
+        vm.exec(&Instruction::ScrollLeft).unwrap();
+        vm.exec(&Instruction::ScrollLeft).unwrap();
+        assert!(vm.screen.iter().all(|px| *px == 0));
+    }
+
+    #[test]
+    fn rpl_flags() {
+        let mut vm = Vm::new();
+        vm.reg[V0 as usize] = 0x12;
+        vm.reg[V1 as usize] = 0x34;
+        vm.exec(&Instruction::StoreFlags(V1)).unwrap();
+        vm.reg[V0 as usize] = 0;
+        vm.reg[V1 as usize] = 0;
+        vm.exec(&Instruction::LoadFlags(V1)).unwrap();
+        assert_eq!(vm.reg[V0 as usize], 0x12);
+        assert_eq!(vm.reg[V1 as usize], 0x34);
+    }
+
+    #[test]
+    fn exit() {
+        let mut vm = Vm::new();
+        // EXIT ; LD V0, 1
+        vm.load_rom(&mut &[0x00, 0xFD, 0x60, 0x01][..]).unwrap();
+        vm.step(1.0 / 60.0).unwrap();
+        assert!(vm.exited());
+        assert_eq!(vm.pc, 0x202);
+        assert_eq!(vm.reg[V0 as usize], 0);
+    }
+
+    #[test]
+    fn timers() {
+        let mut vm = Vm::new();
+        // LD V0, 3 ; LD DT, V0 ; LD ST, V0 ; JP 0x206
+        vm.load_rom(&mut &[0x60, 0x03, 0xF0, 0x15, 0xF0, 0x18, 0x12, 0x06][..]).unwrap();
+        vm.run_cycles(3).unwrap();
+        assert_eq!((vm.timer, vm.sound_timer), (3, 3));
+
+        // The timers count down at the end of each frame, no matter when they were set
+        vm.run_frame().unwrap();
+        assert_eq!((vm.timer, vm.sound_timer), (2, 2));
+        vm.run_cycles(CLOCK_HZ / FRAME_HZ - 1).unwrap();
+        assert_eq!(vm.timer, 2);
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.timer, 1);
+        vm.run_frame().unwrap();
+        vm.run_frame().unwrap();
+        assert_eq!((vm.timer, vm.sound_timer), (0, 0));
+    }
+
+    #[test]
+    fn step_carries_cycles() {
+        let mut vm = Vm::new();
+        // ADD V0, 1 ; JP 0x200
+        vm.load_rom(&mut &[0x70, 0x01, 0x12, 0x00][..]).unwrap();
+        // A third of a cycle per call executes one instruction every third call
+        for _ in 0..30 {
+            vm.step(1.0 / (3 * CLOCK_HZ) as f32).unwrap();
+        }
+        assert_eq!(vm.reg[V0 as usize], 5);
+
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &[0x70, 0x01, 0x12, 0x00][..]).unwrap();
+        vm.step(1.0).unwrap();
+        assert_eq!(vm.reg[V0 as usize] as u32, CLOCK_HZ / 2 % 256);
+
+        // Durations that fall short of whole cycles only by the `f32` rounding
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &[0x70, 0x01, 0x12, 0x00][..]).unwrap();
+        assert!(((3.0f32 / 600.0) as f64 * 600.0) < 3.0);
+        vm.step(3.0 / 600.0).unwrap();
+        assert_eq!(vm.pc, 0x202);
+        assert_eq!(vm.reg[V0 as usize], 2);
+    }
+
+    #[test]
+    fn clock_speed() {
+        let mut vm = Vm::new();
+        // ADD V0, 1 ; JP 0x200
+        vm.load_rom(&mut &[0x70, 0x01, 0x12, 0x00][..]).unwrap();
+        vm.set_clock_hz(1200);
+        vm.run_frame().unwrap();
+        assert_eq!(vm.reg[V0 as usize], 10);
+
+        // At 90 Hz frames end after the 2nd, 3rd, 5th, 6th, ... cycle
+        vm.set_clock_hz(90);
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.reg[V0 as usize], 11);
+        vm.run_frame().unwrap();
+        assert_eq!(vm.pc, 0x200);
+        vm.run_frame().unwrap();
+        assert_eq!(vm.pc, 0x202);
+        vm.run_frame().unwrap();
+        assert_eq!(vm.pc, 0x202);
+        assert_eq!(vm.reg[V0 as usize], 13);
+    }
+
+    #[test]
+    fn cycle_costs() {
+        let mut vm = Vm::new();
+        // LD V0, 1 ; DRW V0, V0, 15 ; ADD V1, 1 ; JP 0x204
+        vm.load_rom(&mut &[0x60, 0x01, 0xD0, 0x0F, 0x71, 0x01, 0x12, 0x04][..]).unwrap();
+        vm.set_cycle_costs(CycleCosts::CosmacVip);
+        let costs = CycleCosts::CosmacVip;
+        let ld = costs.cost(&Instruction::SetK(V0, 1));
+        let draw = costs.cost(&Instruction::Draw(V0, V0, Nibble { bits: 15 }));
+        vm.run_cycles(ld + draw).unwrap();
+        assert_eq!(vm.pc, 0x204, "ADD should wait for DRW to complete");
+        vm.run_cycles(1).unwrap();
+        assert_eq!(vm.pc, 0x206);
+        assert_eq!(vm.reg[V1 as usize], 1);
+    }
+
+    #[test]
+    fn xo_chip_long_i() {
+        let mut vm = Vm::with_ram_size(Quirks::xo_chip(), XO_CHIP_RAM_SIZE);
+        // LD I, long 0xBEEF ; SE V0, 0 ; LD I, long 0x1234 ; LD V1, 1
+        let rom = [0xF0, 0x00, 0xBE, 0xEF, 0x30, 0x00, 0xF0, 0x00, 0x12, 0x34, 0x61, 0x01];
+        vm.load_rom(&mut &rom[..]).unwrap();
+        vm.run_cycles(2).unwrap();
+        assert_eq!(vm.i, 0xBEEF);
+        assert_eq!(vm.pc, 0x20A, "skip should jump over the whole 4 byte instruction");
+    }
+
+    #[test]
+    fn xo_chip_ram_size() {
+        let mut vm = Vm::with_ram_size(Quirks::xo_chip(), XO_CHIP_RAM_SIZE);
+        let rom = vec![0; XO_CHIP_RAM_SIZE - 0x200];
+        assert!(vm.load_rom(&mut &rom[..]).is_ok());
+
+        // `I` wraps at 16 bits, so the state can always be saved
+        let mut vm = Vm::with_ram_size(Quirks::xo_chip(), XO_CHIP_RAM_SIZE);
+        // LD I, LONG 0xFFFF ; LD V0, 0x10 ; ADD I, V0
+        vm.load_rom(&mut &[0xF0, 0x00, 0xFF, 0xFF, 0x60, 0x10, 0xF0, 0x1E][..]).unwrap();
+        vm.run_cycles(3).unwrap();
+        assert_eq!(vm.i, 0xF);
+        assert!(vm.save_state(&mut Vec::new()).is_ok());
+    }
+
+    #[test]
+    fn xo_chip_register_range() {
+        let mut vm = Vm::new();
+        vm.i = 0x300;
+        vm.reg[V1 as usize] = 1;
+        vm.reg[V2 as usize] = 2;
+        vm.reg[V3 as usize] = 3;
+        vm.exec(&Instruction::SaveRange(V3, V1)).unwrap();
+        assert_eq!(&vm.ram[0x300..0x303], &[3, 2, 1]);
+        assert_eq!(vm.i, 0x300);
+        vm.exec(&Instruction::LoadRange(V4, V6)).unwrap();
+        assert_eq!(&vm.reg[4..7], &[3, 2, 1]);
+    }
+
+    #[test]
+    fn xo_chip_planes() {
+        let mut vm = Vm::new();
+        vm.i = 0x300;
+        vm.ram[0x300] = 0x80;
+        vm.ram[0x301] = 0xC0;
+
+        vm.exec(&Instruction::SelectPlanes(Nibble::new(0b11))).unwrap();
+        vm.exec(&Instruction::Draw(V0, V0, Nibble::new(1))).unwrap();
+        assert_eq!(&vm.screen[..3], &[0b11, 0b10, 0]);
+
+        vm.exec(&Instruction::SelectPlanes(Nibble::new(0b10))).unwrap();
+        vm.exec(&Instruction::ScrollUp(Nibble::new(0))).unwrap();
+        vm.exec(&Instruction::ScrollRight).unwrap();
+        assert_eq!(&vm.screen[..6], &[0b01, 0, 0, 0, 0b10, 0b10]);
+        vm.exec(&Instruction::Clear).unwrap();
+        assert_eq!(&vm.screen[..6], &[0b01, 0, 0, 0, 0, 0]);
+
+        // Switching the resolution clears the planes that are not selected too
+        vm.exec(&Instruction::HighRes).unwrap();
+        assert!(vm.screen.iter().all(|&pixel| pixel == 0));
+    }
+
+    #[test]
+    fn xo_chip_audio() {
+        let mut vm = Vm::new();
+        vm.i = 0x300;
+        assert!(!vm.audio_pattern_loaded());
+        // A silent pattern is loaded too
+        vm.exec(&Instruction::LoadAudio).unwrap();
+        assert!(vm.audio_pattern_loaded());
+        vm.ram[0x300] = 0xAA;
+        vm.exec(&Instruction::LoadAudio).unwrap();
+        assert_eq!(vm.audio_pattern()[0], 0xAA);
+        assert_eq!(vm.audio_pattern_rate(), 4000.0);
+        vm.reg[V0 as usize] = 112;
+        vm.exec(&Instruction::SetPitch(V0)).unwrap();
+        assert_eq!(vm.audio_pattern_rate(), 8000.0);
+    }
+
+    #[test]
+    fn stack_underflow() {
+        let mut vm = Vm::new();
+        // RET
+        vm.load_rom(&mut &[0x00, 0xEE][..]).unwrap();
+        match vm.run_cycles(1) {
+            Err(Chip8Error::StackUnderflow { pc: 0x200 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(vm.pc, 0x200);
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let mut vm = Vm::new();
+        // CALL 0x200
+        vm.load_rom(&mut &[0x22, 0x00][..]).unwrap();
+        match vm.step(1.0) {
+            Err(Chip8Error::StackOverflow { pc: 0x200 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn memory_out_of_bounds() {
+        let mut vm = Vm::new();
+        vm.i = RAM_SIZE - 2;
+        match vm.exec(&Instruction::StoreBCD(V0)) {
+            Err(Chip8Error::MemoryOutOfBounds { addr, pc: 0x1FE }) => assert_eq!(addr, RAM_SIZE),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let mut vm = Vm::new();
+        vm.pc = RAM_SIZE - 1;
+        match vm.run_cycles(1) {
+            Err(Chip8Error::MemoryOutOfBounds { .. }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_opcode() {
+        let mut vm = Vm::new();
+        vm.load_rom(&mut &[0xE0, 0x00][..]).unwrap();
+        match vm.run_cycles(1) {
+            Err(Chip8Error::UnknownOpcode { raw: 0xE000, pc: 0x200 }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn invalid_key() {
+        let mut vm = Vm::new();
+        assert!(vm.set_key(0xF).is_ok());
+        match vm.set_key(0x10) {
+            Err(Chip8Error::InvalidKey(0x10)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert!(vm.unset_key(0x10).is_err());
+
+        vm.reg[V0 as usize] = 0x42;
+        assert!(vm.exec(&Instruction::SkipPressed(V0)).is_err());
+        assert_eq!(vm.keys(), 0x8000);
+    }
+
+    #[test]
+    fn wait_key_release() {
+        let mut vm = Vm::with_quirks(Quirks::cosmac_vip());
+        // LD V3, K ; LD V4, 1
+        vm.load_rom(&mut &[0xF3, 0x0A, 0x64, 0x01][..]).unwrap();
+        vm.run_frame().unwrap();
+        vm.set_key(0xA).unwrap();
+        vm.run_frame().unwrap();
+        assert!(vm.keypad().waiting().is_some(), "WaitKey should wait for the release");
+        assert_eq!(vm.reg[V4 as usize], 0);
+
+        vm.unset_key(0xA).unwrap();
+        vm.run_frame().unwrap();
+        assert_eq!(vm.reg[V3 as usize], 0xA);
+        assert_eq!(vm.reg[V4 as usize], 1);
+    }
+
+    #[test]
+    fn step_instruction() {
+        let mut vm = Vm::new();
+        // LD V0, 5 ; DRW V0, V0, 1 ; LD V1, K ; JP 0x206
+        vm.load_rom(&mut &[0x60, 0x05, 0xD0, 0x01, 0xF1, 0x0A, 0x12, 0x06][..]).unwrap();
+
+        let outcome = vm.step_instruction().unwrap();
+        assert!(matches!(outcome.instruction, Instruction::SetK(V0, 5)));
+        assert_eq!((outcome.pc_before, outcome.pc_after), (0x200, 0x202));
+        assert!(!outcome.screen_changed);
+
+        let outcome = vm.step_instruction().unwrap();
+        assert!(outcome.screen_changed);
+
+        let outcome = vm.step_instruction().unwrap();
+        assert!(outcome.waiting_on_key);
+        let outcome = vm.step_instruction().unwrap();
+        assert!(matches!(outcome.instruction, Instruction::WaitKey(V1)));
+        assert_eq!((outcome.pc_before, outcome.pc_after), (0x206, 0x206));
+
+        vm.set_key(0x3).unwrap();
+        assert_eq!(vm.reg[V1 as usize], 0x3);
+        let outcome = vm.step_instruction().unwrap();
+        assert!(outcome.idle_loop);
+        assert!(!outcome.waiting_on_key);
+    }
+
+    #[test]
+    fn seeded_rand() {
+        // RND V0, 0xFF ; RND V1, 0xFF ; RND V2, 0x0F
+        let rom = [0xC0, 0xFF, 0xC1, 0xFF, 0xC2, 0x0F];
+        let run = |mut vm: Vm| {
+            vm.load_rom(&mut &rom[..]).unwrap();
+            vm.run_cycles(3).unwrap();
+            [vm.reg[0], vm.reg[1], vm.reg[2]]
+        };
+        assert_eq!(run(Vm::with_seed(7)), run(Vm::with_seed(7)));
+
+        let mut vm = Vm::new();
+        vm.set_rng(Box::new(ScriptedRng::new(vec![0x12, 0x34, 0xFF])));
+        assert_eq!(run(vm), [0x12, 0x34, 0x0F]);
+    }
+
     #[test]
     fn oversized_rom() {
         use std::io::Cursor;
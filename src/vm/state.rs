@@ -0,0 +1,430 @@
+//! Binary save state format of the `Vm`
+//!
+//! All numbers are stored big-endian, the layout is:
+//!
+//! * magic `CH8S` and the format `STATE_VERSION` (`u16`)
+//! * quirks (`u8` flags, `u8` load/store increment)
+//! * data registers, `I` (`u16`), program counter (`u16`), stack pointer (`u16`)
+//!   and all stack entries (`u16` each)
+//! * RAM size (`u32`) and RAM contents
+//! * clock speed (`u32`), cycle costs (`u8`) and the cycles the current
+//!   instruction takes beyond its first one (`u32`)
+//! * delay and sound timer (`u8`), the progress of the current frame (`u32`),
+//!   the fraction of a cycle left over from `step` (`f64`) and whether `Draw`
+//!   waits for the vertical blank (`u8`)
+//! * resolution (`u8`), selected planes (`u8`) and all screen pixels
+//! * pressed keys (`u16` bitmask), keys pressed while `WaitKey` waits (`u16`
+//!   bitmask), the register `WaitKey` stores into (`u8`, `0xFF` if none),
+//!   RPL user flags and whether the program exited (`u8`)
+//! * audio pattern buffer, whether it was loaded (`u8`) and pitch (`u8`)
+//! * RNG state size (`u32`) and the state
+
+use std::io::{self, Read, Write};
+use std::mem;
+
+use error::Chip8Error;
+use instructions::Register;
+use keypad::{Keypad, WaitKeyMode};
+use quirks::{Quirks, LoadStoreIncrement};
+
+use super::*;
+
+/// Leading bytes of every save state
+const STATE_MAGIC: &[u8; 4] = b"CH8S";
+/// Version of the save state format written by `save_state`
+pub const STATE_VERSION: u16 = 1;
+
+/// Marker for "not waiting on any key"
+const NO_KEY: u8 = 0xFF;
+
+impl Vm {
+    /// Writes the entire state of the `Vm` to `writer`
+    ///
+    /// The state can be restored with `load_state`.
+    /// Fails with `InvalidState` if the program counter or a stack entry does
+    /// not fit into 16 bits, e.g. after `set_pc` to such an address.
+    pub fn save_state(&self, writer: &mut dyn Write) -> Result<(), Chip8Error> {
+        writer.write_all(STATE_MAGIC)?;
+        write_u16(writer, STATE_VERSION)?;
+
+        write_quirks(writer, &self.quirks)?;
+
+        writer.write_all(&self.reg)?;
+        write_u16(writer, self.i as u16)?;
+        write_u16(writer, address(self.pc, "Program counter does not fit into a save state")?)?;
+        write_u16(writer, self.sp as u16)?;
+        for addr in self.stack.iter() {
+            write_u16(writer, address(*addr, "Stack entry does not fit into a save state")?)?;
+        }
+        write_u32(writer, self.ram.len() as u32)?;
+        writer.write_all(&self.ram)?;
+
+        write_u32(writer, self.clock_hz)?;
+        writer.write_all(&[match self.cycle_costs {
+            CycleCosts::Uniform => 0,
+            CycleCosts::CosmacVip => 1,
+        }])?;
+        write_u32(writer, self.busy_cycles)?;
+
+        writer.write_all(&[self.timer, self.sound_timer])?;
+        write_u32(writer, self.frame_ticks)?;
+        write_f64(writer, self.cycle_remainder)?;
+        writer.write_all(&[self.waiting_on_vblank as u8])?;
+
+        writer.write_all(&[self.hires as u8, self.planes])?;
+        writer.write_all(&self.screen)?;
+
+        write_u16(writer, self.keypad.bits())?;
+        write_u16(writer, self.keypad.pressed_while_waiting())?;
+        writer.write_all(&[self.keypad.waiting().map(|vx| vx as u8).unwrap_or(NO_KEY)])?;
+        writer.write_all(&self.flags)?;
+        writer.write_all(&[self.exited as u8])?;
+
+        writer.write_all(&self.audio_pattern)?;
+        writer.write_all(&[self.audio_pattern_loaded as u8, self.pitch])?;
+
+        let rng = self.rng.state();
+        write_u32(writer, rng.len() as u32)?;
+        writer.write_all(&rng)?;
+        Ok(())
+    }
+
+    /// Restores the entire state of the `Vm` from `reader`, as written by `save_state`
+    ///
+    /// The state is validated before anything is changed, on errors the `Vm`
+    /// is left as it was.
+    /// The random number generator must be of the same kind as the one the
+    /// state was saved with. Observers added with `add_observer`, the tracer
+    /// set with `set_tracer` and the audio enabled with `set_audio`, including
+    /// the samples not taken yet, stay attached.
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> Result<(), Chip8Error> {
+        let mut magic = [0; 4];
+        read_exact(reader, &mut magic)?;
+        if &magic != STATE_MAGIC {
+            return Err(Chip8Error::InvalidState("Not a save state"));
+        }
+        let version = read_u16(reader)?;
+        if version != STATE_VERSION {
+            return Err(Chip8Error::StateVersion { found: version, expected: STATE_VERSION });
+        }
+
+        let mut vm = self.clone();
+
+        vm.quirks = read_quirks(reader)?;
+
+        read_exact(reader, &mut vm.reg)?;
+        vm.i = read_u16(reader)? as usize;
+        vm.pc = read_u16(reader)? as usize;
+        vm.sp = read_u16(reader)? as usize;
+        if vm.sp >= STACK_SIZE {
+            return Err(Chip8Error::InvalidState("Stack pointer is out of range"));
+        }
+        for addr in vm.stack.iter_mut() {
+            *addr = read_u16(reader)? as usize;
+        }
+        let ram_size = read_u32(reader)? as usize;
+        if ram_size <= PROGRAM_START || ram_size > XO_CHIP_RAM_SIZE {
+            return Err(Chip8Error::InvalidState("RAM size is not supported"));
+        }
+        vm.ram = vec![0; ram_size];
+        read_exact(reader, &mut vm.ram)?;
+
+        vm.clock_hz = read_u32(reader)?;
+        if vm.clock_hz == 0 {
+            return Err(Chip8Error::InvalidState("Clock speed is 0"));
+        }
+        vm.cycle_costs = match read_u8(reader)? {
+            0 => CycleCosts::Uniform,
+            1 => CycleCosts::CosmacVip,
+            _ => return Err(Chip8Error::InvalidState("Invalid cycle costs")),
+        };
+        vm.busy_cycles = read_u32(reader)?;
+
+        vm.timer = read_u8(reader)?;
+        vm.sound_timer = read_u8(reader)?;
+        vm.frame_ticks = read_u32(reader)?;
+        if vm.frame_ticks >= vm.clock_hz {
+            return Err(Chip8Error::InvalidState("Frame progress is out of range"));
+        }
+        vm.cycle_remainder = read_f64(reader)?;
+        vm.waiting_on_vblank = read_u8(reader)? != 0;
+
+        vm.hires = read_u8(reader)? != 0;
+        vm.planes = read_u8(reader)?;
+        read_exact(reader, &mut vm.screen)?;
+
+        let keys = read_u16(reader)?;
+        let pressed_while_waiting = read_u16(reader)?;
+        let waiting = match read_u8(reader)? {
+            NO_KEY => None,
+            vx => Some(Register::new(vx).map_err(|_| Chip8Error::InvalidState("Invalid WaitKey register"))?),
+        };
+        vm.keypad = Keypad::restore(keys, pressed_while_waiting, waiting);
+        read_exact(reader, &mut vm.flags)?;
+        vm.exited = read_u8(reader)? != 0;
+
+        read_exact(reader, &mut vm.audio_pattern)?;
+        vm.audio_pattern_loaded = read_u8(reader)? != 0;
+        vm.pitch = read_u8(reader)?;
+
+        let rng_len = read_u32(reader)? as usize;
+        let mut rng = Vec::new();
+        reader.take(rng_len as u64).read_to_end(&mut rng)?;
+        if rng.len() != rng_len {
+            return Err(truncated());
+        }
+        vm.rng.restore(&rng)?;
+
+        vm.screen_changed = false;
+        // Only the emulated state is loaded, cloning leaves out the attachments
+        vm.observers = mem::take(&mut self.observers);
+        vm.tracer = self.tracer.take();
+        vm.audio = self.audio.take();
+        *self = vm;
+        Ok(())
+    }
+}
+
+/// Returns `addr` as `u16`, or `InvalidState` with `desc` if it does not fit
+fn address(addr: usize, desc: &'static str) -> Result<u16, Chip8Error> {
+    if addr > 0xFFFF {
+        return Err(Chip8Error::InvalidState(desc));
+    }
+    Ok(addr as u16)
+}
+
+fn write_quirks(writer: &mut dyn Write, quirks: &Quirks) -> Result<(), Chip8Error> {
+    let flags = quirks.shift_in_place as u8
+        | (quirks.vf_reset as u8) << 1
+        | (quirks.jump_vx as u8) << 2
+        | (quirks.clip_sprites as u8) << 3
+        | (quirks.display_wait as u8) << 4
+        | ((quirks.wait_key == WaitKeyMode::Release) as u8) << 5;
+    let load_store = match quirks.load_store {
+        LoadStoreIncrement::XPlusOne => 0,
+        LoadStoreIncrement::X => 1,
+        LoadStoreIncrement::Unchanged => 2,
+    };
+    writer.write_all(&[flags, load_store])?;
+    Ok(())
+}
+
+fn read_quirks(reader: &mut dyn Read) -> Result<Quirks, Chip8Error> {
+    let flags = read_u8(reader)?;
+    let load_store = match read_u8(reader)? {
+        0 => LoadStoreIncrement::XPlusOne,
+        1 => LoadStoreIncrement::X,
+        2 => LoadStoreIncrement::Unchanged,
+        _ => return Err(Chip8Error::InvalidState("Invalid load/store quirk")),
+    };
+    Ok(Quirks {
+        shift_in_place: flags & 1 != 0,
+        load_store,
+        vf_reset: flags & (1 << 1) != 0,
+        jump_vx: flags & (1 << 2) != 0,
+        clip_sprites: flags & (1 << 3) != 0,
+        display_wait: flags & (1 << 4) != 0,
+        wait_key: if flags & (1 << 5) != 0 { WaitKeyMode::Release } else { WaitKeyMode::Press },
+    })
+}
+
+fn truncated() -> Chip8Error {
+    Chip8Error::InvalidState("Save state is truncated")
+}
+
+fn read_exact(reader: &mut dyn Read, buf: &mut [u8]) -> Result<(), Chip8Error> {
+    reader.read_exact(buf).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => truncated(),
+        _ => Chip8Error::from(err),
+    })
+}
+
+fn read_u8(reader: &mut dyn Read) -> Result<u8, Chip8Error> {
+    let mut buf = [0; 1];
+    read_exact(reader, &mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16(reader: &mut dyn Read) -> Result<u16, Chip8Error> {
+    let mut buf = [0; 2];
+    read_exact(reader, &mut buf)?;
+    Ok(u16::from_be_bytes(buf))
+}
+
+fn read_u32(reader: &mut dyn Read) -> Result<u32, Chip8Error> {
+    let mut buf = [0; 4];
+    read_exact(reader, &mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn read_u64(reader: &mut dyn Read) -> Result<u64, Chip8Error> {
+    let mut buf = [0; 8];
+    read_exact(reader, &mut buf)?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+fn read_f64(reader: &mut dyn Read) -> Result<f64, Chip8Error> {
+    Ok(f64::from_bits(read_u64(reader)?))
+}
+
+fn write_u16(writer: &mut dyn Write, value: u16) -> Result<(), Chip8Error> {
+    writer.write_all(&value.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_u32(writer: &mut dyn Write, value: u32) -> Result<(), Chip8Error> {
+    writer.write_all(&value.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_u64(writer: &mut dyn Write, value: u64) -> Result<(), Chip8Error> {
+    writer.write_all(&value.to_be_bytes())?;
+    Ok(())
+}
+
+fn write_f64(writer: &mut dyn Write, value: f64) -> Result<(), Chip8Error> {
+    write_u64(writer, value.to_bits())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+    use instructions::Register::*;
+    use rng::ScriptedRng;
+
+    fn busy_vm() -> Vm {
+        let mut vm = Vm::with_seed(1234);
+        // CALL 0x204 ; (padding) ; RND V0, 0xFF ; LD ST, V0 ; DRW V0, V0, 5 ; JP 0x20A
+        vm.load_rom(&mut &[0x22, 0x04, 0x00, 0x00, 0xC0, 0xFF, 0xF0, 0x18, 0xD0, 0x05, 0x12, 0x0A][..]).unwrap();
+        vm.step(0.01).unwrap();
+        vm
+    }
+
+    fn state_of(vm: &Vm) -> Vec<u8> {
+        let mut state = Vec::new();
+        vm.save_state(&mut state).unwrap();
+        state
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut vm = busy_vm();
+        let state = state_of(&vm);
+
+        let mut other = Vm::new();
+        other.load_state(&mut &state[..]).unwrap();
+        assert_eq!(state_of(&other), state);
+        assert_eq!(other.sp, 1);
+        assert_eq!(other.reg[V0 as usize], vm.reg[V0 as usize]);
+
+        // Both continue identically, including their random numbers
+        vm.step(0.1).unwrap();
+        other.step(0.1).unwrap();
+        assert_eq!(state_of(&other), state_of(&vm));
+    }
+
+    #[test]
+    fn clone() {
+        let mut vm = busy_vm();
+        let mut other = vm.clone();
+        vm.step(0.1).unwrap();
+        other.step(0.1).unwrap();
+        assert_eq!(state_of(&other), state_of(&vm));
+    }
+
+    #[test]
+    fn address_out_of_range() {
+        let mut vm = Vm::new();
+        vm.set_pc(0x10000);
+        match vm.save_state(&mut Vec::new()) {
+            Err(Chip8Error::InvalidState(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[derive(Default)]
+    struct Counter(usize);
+
+    impl VmObserver for Counter {
+        fn on_instruction(&mut self, _pc: usize, _ins: &Instruction) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn keeps_observers() {
+        let mut vm = busy_vm();
+        let state = state_of(&vm);
+        let counter = Rc::new(RefCell::new(Counter::default()));
+        vm.add_observer(Box::new(counter.clone()));
+        vm.load_state(&mut &state[..]).unwrap();
+        vm.run_cycles(3).unwrap();
+        assert_eq!(counter.borrow().0, 3);
+    }
+
+    #[derive(Default)]
+    struct Records(usize);
+
+    impl Tracer for Records {
+        fn record(&mut self, _record: &TraceRecord) {
+            self.0 += 1;
+        }
+    }
+
+    #[test]
+    fn keeps_tracer() {
+        let mut vm = busy_vm();
+        let state = state_of(&vm);
+        let records = Rc::new(RefCell::new(Records::default()));
+        vm.set_tracer(Some(Box::new(records.clone())));
+        vm.load_state(&mut &state[..]).unwrap();
+        vm.run_cycles(3).unwrap();
+        assert_eq!(records.borrow().0, 3);
+    }
+
+    #[test]
+    fn keeps_audio() {
+        let mut vm = busy_vm();
+        let state = state_of(&vm);
+        vm.set_audio(Some(AudioConfig::default()));
+        // Completes the current frame, then runs a full one
+        vm.run_frame().unwrap();
+        vm.take_samples();
+        vm.run_frame().unwrap();
+        let samples = AudioConfig::default().sample_rate as usize / FRAME_HZ as usize;
+        vm.load_state(&mut &state[..]).unwrap();
+        assert_eq!(vm.take_samples().len(), samples);
+    }
+
+    #[test]
+    fn version_mismatch() {
+        let mut state = state_of(&Vm::new());
+        state[5] = 0xFF;
+        match Vm::new().load_state(&mut &state[..]) {
+            Err(Chip8Error::StateVersion { found: 0xFF, expected: STATE_VERSION }) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn truncated_state() {
+        let state = state_of(&busy_vm());
+        let mut vm = Vm::new();
+        match vm.load_state(&mut &state[..state.len() - 1]) {
+            Err(Chip8Error::InvalidState(_)) => {},
+            other => panic!("unexpected {:?}", other),
+        }
+        assert_eq!(vm.pc, PROGRAM_START, "failed load must not change the Vm");
+    }
+
+    #[test]
+    fn rng_kind_mismatch() {
+        let state = state_of(&busy_vm());
+        let mut vm = Vm::new();
+        vm.set_rng(Box::new(ScriptedRng::new(vec![1])));
+        assert!(vm.load_state(&mut &state[..]).is_err());
+    }
+}